@@ -1,23 +1,28 @@
 use std::{
-    collections::HashSet,
-    io,
-    path::PathBuf,
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    io::{self, BufRead, Read},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use crossterm::{
-    cursor::SetCursorStyle,
-    event::{self, Event, KeyCode, KeyEventKind},
+    cursor::{SetCursorStyle, Show},
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Style},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Padding, Paragraph},
+    widgets::{Block, Borders, Clear, Padding, Paragraph},
     Frame, Terminal,
 };
+use similar::{Algorithm, DiffOp};
 // FIX: Import crates for Unicode handling
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
@@ -26,9 +31,51 @@ use unicode_width::UnicodeWidthStr;
 enum Mode {
     Normal,
     Insert,
+    Replace,
+    VisualBlock,
+    VisualLine,
     Command,
 }
 
+/// How `~`, `gu`, `gU` and `g~` change the case of text.
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum CaseChange {
+    Lower,
+    Upper,
+    Toggle,
+}
+
+/// An operator waiting for a motion, e.g. the `gU` in `gUw`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Operator {
+    Case(CaseChange),
+    Comment,
+    /// Pipes whole lines through an external command, as `!` does.
+    Filter,
+    /// Creates a closed fold over whole lines (`zf`).
+    Fold,
+    /// Deletes text into the registers (`d`).
+    Delete,
+    /// Deletes text and enters Insert mode (`c`).
+    Change,
+}
+
+impl Operator {
+    /// The keys that start the operator.
+    fn keys(self) -> &'static str {
+        match self {
+            Operator::Case(CaseChange::Lower) => "gu",
+            Operator::Case(CaseChange::Upper) => "gU",
+            Operator::Case(CaseChange::Toggle) => "g~",
+            Operator::Comment => "gc",
+            Operator::Filter => "!",
+            Operator::Fold => "zf",
+            Operator::Delete => "d",
+            Operator::Change => "c",
+        }
+    }
+}
+
 struct Buffer {
     filename: Option<PathBuf>,
     lines: Vec<String>,
@@ -37,611 +84,7026 @@ struct Buffer {
     col: usize,
     top_row: usize,
     modified: bool,
+    /// Sign-column markers by line index.
+    signs: HashMap<usize, Sign>,
+    /// The lines as last read from or saved to the file, which `change_marks`
+    /// are computed against. `None` until the buffer has been on disk.
+    baseline: Option<Vec<String>>,
+    /// `+`, `~` or `-` by line index for lines added, changed or removed since
+    /// the file was read or saved, shown in the sign column.
+    change_marks: HashMap<usize, char>,
+    /// The file started with a UTF-8 byte order mark, which is written back on save.
+    bom: bool,
+    /// Language of the file, detected from its name (`rust`, `python`, `make`...)
+    /// or set with `:set filetype=`. Decides the indentation defaults, the `gc`
+    /// comment prefix and whether spell checking applies.
+    filetype: Option<String>,
+    /// Indentation options, defaulted from the file type and then from what
+    /// the file itself uses.
+    indent: Indent,
+    /// Indentation found in the file when it was read.
+    indent_style: IndentStyle,
+    /// Folded line ranges. A closed fold is drawn as one summary row.
+    folds: Vec<Fold>,
+    /// The file has NUL bytes and is shown as a read-only hex dump.
+    binary: bool,
+    /// Set while a worker thread is still reading the file into `lines`.
+    loading: Option<Loading>,
+    /// Display column a run of `j` / `k` keeps returning to through shorter lines;
+    /// `usize::MAX` after `$` for the end of every line. Any other motion or edit
+    /// clears it.
+    desired_col: Option<usize>,
+    /// The file was removed from disk after it was opened. The text is kept and
+    /// counts as unsaved, so `:w` writes it back and `:q` asks first.
+    deleted_on_disk: bool,
+}
+
+/// How many closed buffers `:bun` can bring back.
+const CLOSED_BUFFERS_MAX: usize = 20;
+
+/// Files at least this big are read on a worker thread, so the editor keeps
+/// drawing and taking keys while they load.
+const ASYNC_LOAD_MIN_BYTES: u64 = 1 << 20;
+/// Lines sent from a loading thread at a time; the main loop takes one batch per frame.
+const LOAD_CHUNK_LINES: usize = 50_000;
+
+/// Progress of a buffer whose file is being read on a worker thread.
+struct Loading {
+    /// Number of the load, which its batches carry; the buffer's file name may
+    /// change while it runs.
+    id: usize,
+    /// Lines received so far. The first batch replaces the empty placeholder line.
+    received: usize,
+    /// Where the cursor goes once every line is in.
+    cursor: Option<ViewState>,
+}
+
+/// A batch of lines, or the end, from the thread doing load number `load`.
+struct FileChunk {
+    load: usize,
+    part: LoadPart,
+}
+
+enum LoadPart {
+    Lines(Vec<String>),
+    Done { bom: bool, binary: bool },
+    Failed(io::Error),
+}
+
+/// How many bytes at the start of a file are checked for NULs to tell binary files apart.
+const BINARY_CHECK_LEN: usize = 8192;
+
+/// A file read into buffer lines (at least one).
+struct FileText {
+    lines: Vec<String>,
+    /// The file started with a UTF-8 byte order mark, which is not part of the first line.
+    bom: bool,
+    /// The file has NUL bytes, so `lines` is a hex dump of it.
+    binary: bool,
+}
+
+/// Reads a file for a buffer. A file with a NUL byte near the start is read as a
+/// hex dump unless `as_text` is set, in which case bytes that aren't UTF-8 become
+/// replacement characters instead of an `InvalidData` error.
+fn read_lines(path: &Path, as_text: bool) -> io::Result<FileText> {
+    let bytes = std::fs::read(path)?;
+    if !as_text && bytes.iter().take(BINARY_CHECK_LEN).any(|&b| b == 0) {
+        return Ok(FileText { lines: hex_dump(&bytes), bom: false, binary: true });
+    }
+    let content = match as_text {
+        true => String::from_utf8_lossy(&bytes).into_owned(),
+        false => String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    };
+    let (content, bom) = match content.strip_prefix('\u{feff}') {
+        Some(rest) => (rest, true),
+        None => (content.as_str(), false),
+    };
+    let mut lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    Ok(FileText { lines, bom, binary: false })
+}
+
+/// Reads `path` like `read_lines`, but a batch of `LOAD_CHUNK_LINES` lines at a
+/// time, handing each to `send` as soon as it is read. Returns whether the file
+/// had a byte order mark and whether it is binary, or `None` if `send` returned
+/// `false` to stop.
+fn read_lines_in_batches(path: &Path, send: impl Fn(Vec<String>) -> bool) -> io::Result<Option<(bool, bool)>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut head = Vec::new();
+    (&mut file).take(BINARY_CHECK_LEN as u64).read_to_end(&mut head)?;
+    if head.contains(&0) {
+        // The hex dump is made from the whole file at once.
+        let text = read_lines(path, false)?;
+        let sent = text.lines.chunks(LOAD_CHUNK_LINES).all(|batch| send(batch.to_vec()));
+        return Ok(sent.then_some((false, true)));
+    }
+    let mut bom = false;
+    let mut batch = Vec::new();
+    for (i, line) in io::BufReader::new(io::Cursor::new(head).chain(file)).lines().enumerate() {
+        let mut line = line?;
+        if i == 0 && line.starts_with('\u{feff}') {
+            line.drain(..'\u{feff}'.len_utf8());
+            bom = true;
+        }
+        batch.push(line);
+        if batch.len() == LOAD_CHUNK_LINES && !send(std::mem::take(&mut batch)) {
+            return Ok(None);
+        }
+    }
+    let sent = batch.is_empty() || send(batch);
+    Ok(sent.then_some((bom, false)))
+}
+
+/// `xxd`-style lines of 16 bytes: the offset, the bytes in hex and the printable
+/// ASCII ones, with `.` for the rest.
+fn hex_dump(bytes: &[u8]) -> Vec<String> {
+    let mut lines: Vec<String> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut line = format!("{:08x}:", i * 16);
+            for (j, byte) in chunk.iter().enumerate() {
+                if j % 2 == 0 {
+                    line.push(' ');
+                }
+                line.push_str(&format!("{:02x}", byte));
+            }
+            // Full lines have 8 groups of 4 hex digits after the offset.
+            line.push_str(&" ".repeat(9 + 8 * 5 - line.len() + 2));
+            line.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+            line
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+/// Lines `start..=end` that can be collapsed into a single row.
+#[derive(Clone, Copy)]
+struct Fold {
+    start: usize,
+    end: usize,
+    open: bool,
+}
+
+/// A single-character marker shown in the sign column, e.g. `E` for an error.
+#[derive(Clone, Copy)]
+struct Sign {
+    symbol: char,
+    style: Style,
 }
 
 impl Buffer {
     fn new(filename: Option<PathBuf>) -> Buffer {
+        let filetype = detect_filetype(filename.as_deref());
         Buffer {
+            indent: Indent::for_filetype(filetype.as_deref()),
+            indent_style: IndentStyle::Unknown,
+            filetype,
             filename,
             lines: vec![String::new()],
             row: 0,
             col: 0,
             top_row: 0,
             modified: false,
+            signs: HashMap::new(),
+            baseline: None,
+            change_marks: HashMap::new(),
+            bom: false,
+            folds: Vec::new(),
+            binary: false,
+            loading: None,
+            desired_col: None,
+            deleted_on_disk: false,
         }
     }
-}
-
-struct TreeItem {
-    path: PathBuf,
-    prefix: String,
-    is_dir: bool,
-}
-
-struct Editor {
-    buffers: Vec<Buffer>,
-    active_buffer_index: usize,
-    mode: Mode,
-    command_input: String,
-    command_message: String,
-    scroll_offset_col: usize,
-    should_exit: bool,
-    pending_command_prefix: Option<char>,
-
-    // Directory Tree Properties
-    tree_visible: bool,
-    tree_view_active: bool,
-    tree_width: u16,
-    current_path: PathBuf,
-    tree_scroll_pos: usize,
-    selected_item_index: usize,
-    expanded_dirs: HashSet<PathBuf>,
-    tree_items: Vec<TreeItem>,
-}
 
-impl Editor {
-    fn new() -> Editor {
-        let mut editor = Editor {
-            buffers: Vec::new(),
-            active_buffer_index: 0,
-            mode: Mode::Normal,
-            command_input: String::new(),
-            command_message: String::new(),
-            scroll_offset_col: 0,
-            should_exit: false,
-            pending_command_prefix: None,
+    /// Moves the cursor and view to `state`, kept within the file; a buffer that
+    /// is still loading goes there once it has loaded.
+    fn restore_view(&mut self, state: ViewState) {
+        match &mut self.loading {
+            Some(loading) => loading.cursor = Some(state),
+            None => {
+                self.row = state.row.min(self.lines.len() - 1);
+                self.col = state.col;
+                self.top_row = state.top_row.min(self.row);
+            }
+        }
+    }
 
-            // Directory Tree Properties
-            tree_visible: true,
-            tree_view_active: true,
-            tree_width: 30,
-            current_path: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
-            tree_scroll_pos: 0,
-            selected_item_index: 0,
-            expanded_dirs: HashSet::new(),
-            tree_items: Vec::new(),
-        };
-        editor.expanded_dirs.insert(editor.current_path.clone());
-        editor.open_file_in_new_buffer(None);
-        editor.command_message.clear(); // Clear initial open message
-        editor
+    /// Makes `filetype` the buffer's file type (none if empty) and resets the
+    /// indentation options to its defaults.
+    fn set_filetype(&mut self, filetype: &str) {
+        self.filetype = Some(filetype.to_ascii_lowercase()).filter(|ft| !ft.is_empty());
+        self.indent = Indent::for_filetype(self.filetype.as_deref()).with_style(self.indent_style);
     }
 
-    fn active_buffer(&mut self) -> Option<&mut Buffer> {
-        self.buffers.get_mut(self.active_buffer_index)
+    /// Looks at how the text just read is indented and sets the indentation
+    /// options to match, over the file type's defaults.
+    fn detect_indent(&mut self) {
+        self.indent_style = detect_indent(&self.lines);
+        self.indent = Indent::for_filetype(self.filetype.as_deref()).with_style(self.indent_style);
     }
 
-    /// The main application loop.
-    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> io::Result<()> {
-        loop {
-            if self.should_exit {
-                return Ok(());
-            }
+    /// Buffer-local boolean options, consulted by `:set` before the global ones.
+    fn bool_option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "bomb" => Some(&mut self.bom),
+            _ => None,
+        }
+    }
 
-            // Update data models before drawing
-            if self.tree_visible {
-                self.update_tree_items();
-            }
-            self.clamp_cursor_position();
-            self.update_scroll_offsets(terminal.size()?);
+    /// Width of the sign column: shown when any line has a sign or change mark,
+    /// or `signcolumn` forces it.
+    fn sign_column_width(&self, always: bool) -> usize {
+        usize::from(always || !self.signs.is_empty() || !self.change_marks.is_empty())
+    }
 
-            // Draw UI
-            terminal.draw(|f| self.ui(f))?;
+    /// Recomputes `change_marks` against `baseline`. An unmodified buffer matches
+    /// its file, so only modified ones are diffed.
+    fn update_change_marks(&mut self, enabled: bool) {
+        match (&self.baseline, self.modified && enabled) {
+            (Some(baseline), true) => self.change_marks = change_marks(baseline, &self.lines),
+            _ => self.change_marks.clear(),
+        }
+    }
 
-            // Set cursor style based on the current mode
-            match self.mode {
-                Mode::Insert => {
-                    execute!(terminal.backend_mut(), SetCursorStyle::BlinkingBar)?;
-                }
-                _ => { // Normal, Command
-                    execute!(terminal.backend_mut(), SetCursorStyle::BlinkingBlock)?;
-                }
-            }
+    /// Columns left of the text: the right-aligned line number with a space on
+    /// each side (at least `numberwidth` wide), then the sign column.
+    fn gutter_width(&self, settings: &Settings) -> usize {
+        (self.lines.len().to_string().len() + 2).max(settings.numberwidth) + self.sign_column_width(settings.signcolumn)
+    }
 
-            // Handle input events
-            if event::poll(Duration::from_millis(100))? {
-                if let Event::Key(key) = event::read()? {
-                    if key.kind == KeyEventKind::Press {
-                        if self.tree_view_active && self.tree_visible {
-                            self.handle_tree_view_key(key.code);
-                        } else {
-                            let new_mode = match self.mode {
-                                Mode::Normal => self.handle_normal_mode_key(key.code),
-                                Mode::Insert => self.handle_insert_mode_key(key.code),
-                                Mode::Command => self.handle_command_mode_key(key.code),
-                            };
-                            self.mode = new_mode;
-                        }
-                    }
-                }
-            }
+    /// `w`: moves to the next word start, continuing on the following line.
+    fn move_word_forward(&mut self) {
+        let graphemes: Vec<&str> = self.lines[self.row].graphemes(true).collect();
+        let target = next_word_start(&graphemes, self.col);
+        if target < graphemes.len() || self.row + 1 >= self.lines.len() {
+            self.col = target;
+        } else {
+            self.row += 1;
+            self.col = first_non_blank(&self.lines[self.row]);
         }
     }
 
-    /// Ensures the cursor is within valid bounds of the buffer.
-    fn clamp_cursor_position(&mut self) {
-        if let Some(buffer) = self.active_buffer() {
-            buffer.row = buffer.row.min(buffer.lines.len().saturating_sub(1));
-            // FIX: Clamp column based on grapheme count, not byte length.
-            let grapheme_count = buffer.lines[buffer.row].graphemes(true).count();
-            buffer.col = buffer.col.min(grapheme_count);
+    /// `b`: moves to the previous word start, continuing on the preceding line.
+    fn move_word_backward(&mut self) {
+        let graphemes: Vec<&str> = self.lines[self.row].graphemes(true).collect();
+        let target = prev_word_start(&graphemes, self.col);
+        let at_word = graphemes.get(target).is_some_and(|g| grapheme_class(g) != 0);
+        if (target < self.col && at_word) || self.row == 0 {
+            self.col = target;
+        } else {
+            self.row -= 1;
+            let graphemes: Vec<&str> = self.lines[self.row].graphemes(true).collect();
+            self.col = prev_word_start(&graphemes, graphemes.len());
         }
     }
 
-    /// Updates vertical and horizontal scroll offsets based on cursor position.
-    fn update_scroll_offsets(&mut self, term_size: Rect) {
-        let editor_area = if self.tree_visible {
-            let chunks = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([
-                    Constraint::Length(self.tree_width), // Tree
-                    Constraint::Length(1),               // Separator
-                    Constraint::Min(0),                  // Editor
-                ])
-                .split(term_size);
-            chunks[2]
-        } else {
-            let chunks = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Min(0)])
-                .split(term_size);
-            chunks[0]
-        };
+    /// `e`: moves to the end of the next word, continuing on following lines.
+    fn move_word_end(&mut self) {
+        let mut from = self.col + 1;
+        for row in self.row..self.lines.len() {
+            let graphemes: Vec<&str> = self.lines[row].graphemes(true).collect();
+            if let Some(end) = word_end(&graphemes, from) {
+                self.row = row;
+                self.col = end;
+                return;
+            }
+            from = 0;
+        }
+    }
 
-        let text_area = {
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Min(1), Constraint::Length(2)].as_ref())
-                .split(editor_area);
-            chunks[0]
-        };
+    /// The outermost closed fold containing `row`, as `(start, end)`.
+    fn closed_fold_at(&self, row: usize) -> Option<(usize, usize)> {
+        self.folds
+            .iter()
+            .filter(|fold| !fold.open && fold.start <= row && row <= fold.end)
+            .map(|fold| (fold.start, fold.end))
+            .min_by_key(|&(start, end)| (start, usize::MAX - end))
+    }
 
-        // First, calculate the new horizontal scroll offset using an immutable borrow
-        let new_scroll_offset_col = if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
-            let line_num_width = buffer.lines.len().to_string().len() + 2;
-            let content_width = text_area.width.saturating_sub(line_num_width as u16);
-            
-            // FIX: Calculate scroll based on visual width, not column index.
-            let pre_cursor_text: String = buffer.lines[buffer.row].graphemes(true).take(buffer.col).collect();
-            let pre_cursor_width = UnicodeWidthStr::width(pre_cursor_text.as_str());
+    /// First row of the screen row showing `row`: the start of the closed fold
+    /// hiding it, or `row` itself.
+    fn screen_row_start(&self, row: usize) -> usize {
+        self.closed_fold_at(row).map_or(row, |(start, _)| start)
+    }
 
-            let mut new_offset = self.scroll_offset_col;
-            if pre_cursor_width < new_offset {
-                new_offset = pre_cursor_width;
-            }
-            if pre_cursor_width >= new_offset + content_width as usize {
-                new_offset = pre_cursor_width - content_width as usize + 1;
+    /// Moves `count` screen rows down (or up) from `row`, treating each closed
+    /// fold as one row and stopping at the first or last line.
+    fn step_rows(&self, row: usize, count: usize, up: bool) -> usize {
+        let mut row = self.screen_row_start(row);
+        for _ in 0..count {
+            let next = if up {
+                row.checked_sub(1).map(|r| self.screen_row_start(r))
+            } else {
+                let end = self.closed_fold_at(row).map_or(row, |(_, end)| end);
+                (end + 1 < self.lines.len()).then_some(end + 1)
+            };
+            match next {
+                Some(next) => row = next,
+                None => break,
             }
-            Some(new_offset)
-        } else {
-            None
-        };
+        }
+        row
+    }
 
-        // Now, get a mutable borrow to update the vertical scroll
-        if let Some(buffer) = self.active_buffer() {
-            let editor_height = text_area.height;
-            if buffer.row < buffer.top_row {
-                buffer.top_row = buffer.row;
-            }
-            if buffer.row >= buffer.top_row + editor_height as usize {
-                buffer.top_row = buffer.row - editor_height as usize + 1;
+    /// Buffer rows at the top of each of the `height` screen rows starting at `top`.
+    fn screen_rows(&self, top: usize, height: usize) -> Vec<usize> {
+        let mut rows = Vec::new();
+        let mut row = self.screen_row_start(top);
+        while rows.len() < height && row < self.lines.len() {
+            rows.push(row);
+            let next = self.step_rows(row, 1, false);
+            if next == row {
+                break;
             }
+            row = next;
         }
+        rows
+    }
 
-        // Finally, apply the new horizontal offset
-        if let Some(new_offset) = new_scroll_offset_col {
-            self.scroll_offset_col = new_offset;
+    /// Opens every closed fold that hides `row`, so the cursor line is visible.
+    fn open_folds_at(&mut self, row: usize) {
+        for fold in &mut self.folds {
+            if fold.start < row && row <= fold.end {
+                fold.open = true;
+            }
         }
     }
 
-    /// Handles key presses in normal mode.
-    fn handle_normal_mode_key(&mut self, key_code: KeyCode) -> Mode {
-        let pending_prefix = self.pending_command_prefix.take();
+    /// Innermost fold containing `row`.
+    fn fold_at(&mut self, row: usize) -> Option<&mut Fold> {
+        self.folds
+            .iter_mut()
+            .filter(|fold| fold.start <= row && row <= fold.end)
+            .min_by_key(|fold| fold.end - fold.start)
+    }
 
-        if let Some(prefix) = pending_prefix {
-            if prefix == 'd' && key_code == KeyCode::Char('d') {
-                if let Some(buffer) = self.active_buffer() {
-                    if buffer.lines.len() > 1 {
-                        buffer.lines.remove(buffer.row);
-                        if buffer.row >= buffer.lines.len() {
-                            buffer.row = buffer.lines.len() - 1;
-                        }
-                    } else {
-                        buffer.lines = vec![String::new()];
-                        buffer.row = 0;
-                    }
-                    buffer.modified = true;
+    /// Moves folds after lines were inserted (`delta > 0`) or removed at `at`.
+    /// Folds whose lines were all removed are dropped.
+    fn shift_folds(&mut self, at: usize, delta: isize) {
+        let count = delta.unsigned_abs();
+        self.folds.retain_mut(|fold| {
+            if delta > 0 {
+                if fold.start >= at { fold.start += count; }
+                if fold.end >= at { fold.end += count; }
+                return true;
+            }
+            // Rows `at..at + count` are gone: later rows move up, and a fold
+            // ending among them now ends on the row before.
+            let end = match fold.end {
+                end if end < at => Some(end),
+                end if end >= at + count => Some(end - count),
+                _ => at.checked_sub(1),
+            };
+            match end {
+                Some(end) if fold.start.min(at) <= end => {
+                    fold.start = if fold.start >= at + count { fold.start - count } else { fold.start.min(at) };
+                    fold.end = end;
+                    true
                 }
+                _ => false,
             }
-            return Mode::Normal;
+        });
+        let len = self.lines.len();
+        self.folds.retain(|fold| fold.end < len);
+    }
+
+    /// Replaces rows `rows` with `lines` and returns the rows taken out. Rows
+    /// replaced one for one keep their folds; folds after them move with the text.
+    fn splice_lines(&mut self, rows: Range<usize>, lines: Vec<String>) -> Vec<String> {
+        let (removed, added) = (rows.len(), lines.len());
+        let at = rows.start + removed.min(added);
+        let old = self.lines.splice(rows, lines).collect();
+        if removed != added {
+            self.shift_folds(at, added as isize - removed as isize);
         }
+        old
+    }
 
-        match key_code {
-            KeyCode::Char('i') => return Mode::Insert,
-            KeyCode::Char(':') => {
-                self.command_input.clear();
-                self.command_message.clear();
-                return Mode::Command;
-            }
-            KeyCode::Char('h') | KeyCode::Left => {
-                if let Some(b) = self.active_buffer() { b.col = b.col.saturating_sub(1); }
-            }
-            KeyCode::Char('l') | KeyCode::Right => {
-                if let Some(b) = self.active_buffer() { b.col += 1; }
-            }
-            KeyCode::Char('j') | KeyCode::Down => {
-                if let Some(b) = self.active_buffer() { b.row += 1; }
-            }
-            KeyCode::Char('k') | KeyCode::Up => {
-                if let Some(b) = self.active_buffer() { b.row = b.row.saturating_sub(1); }
-            }
-            KeyCode::Char('x') => {
-                if let Some(buffer) = self.active_buffer() {
-                    // FIX: Delete by grapheme.
-                    let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
-                    if buffer.col < graphemes.len() {
-                        graphemes.remove(buffer.col);
-                        buffer.lines[buffer.row] = graphemes.join("");
-                        buffer.modified = true;
+    /// Replaces the folds with closed ones following the indentation: each run of
+    /// lines indented at least one `shiftwidth` deeper than the line before becomes
+    /// a fold, nested by level. Blank lines take the level of the lines around them.
+    fn fold_by_indent(&mut self) {
+        let shiftwidth = self.indent.shiftwidth();
+        let levels: Vec<Option<usize>> = self
+            .lines
+            .iter()
+            .map(|line| (!line.trim().is_empty()).then(|| indent_width(line, self.indent.tabstop) / shiftwidth))
+            .collect();
+        let levels: Vec<usize> = (0..levels.len())
+            .map(|i| levels[i].unwrap_or_else(|| {
+                let before = levels[..i].iter().rev().find_map(|l| *l).unwrap_or(0);
+                let after = levels[i + 1..].iter().find_map(|l| *l).unwrap_or(0);
+                before.min(after)
+            }))
+            .collect();
+        self.folds.clear();
+        for level in 1..=levels.iter().copied().max().unwrap_or(0) {
+            let mut start = None;
+            for (row, &l) in levels.iter().chain([&0]).enumerate() {
+                match (start, l >= level) {
+                    (None, true) => start = Some(row),
+                    (Some(first), false) => {
+                        self.folds.push(Fold { start: first, end: row - 1, open: false });
+                        start = None;
                     }
+                    _ => {}
                 }
             }
-            KeyCode::Char('d') => self.pending_command_prefix = Some('d'),
-            KeyCode::Char('o') => {
-                if let Some(b) = self.active_buffer() {
-                    b.row += 1;
-                    b.lines.insert(b.row, String::new());
-                    b.col = 0;
-                    b.modified = true;
-                }
-                return Mode::Insert;
-            }
-            KeyCode::Char('O') => {
-                if let Some(b) = self.active_buffer() {
-                    b.lines.insert(b.row, String::new());
-                    b.col = 0;
-                    b.modified = true;
-                }
-                return Mode::Insert;
-            }
-            KeyCode::Tab => {
-                if self.tree_visible { self.tree_view_active = true; }
-            }
-            _ => {}
         }
-        Mode::Normal
     }
 
-    /// Handles key presses in insert mode.
-    fn handle_insert_mode_key(&mut self, key_code: KeyCode) -> Mode {
-        if let Some(buffer) = self.active_buffer() {
-            buffer.modified = true;
-            match key_code {
-                KeyCode::Esc => return Mode::Normal,
-                KeyCode::Enter => {
-                    // FIX: Split line at the correct byte index for the grapheme.
-                    let line = &mut buffer.lines[buffer.row];
-                    let byte_idx = line.grapheme_indices(true).nth(buffer.col).map_or(line.len(), |(i, _)| i);
-                    let new_line = line.split_off(byte_idx);
-                    buffer.lines.insert(buffer.row + 1, new_line);
-                    buffer.row += 1;
-                    buffer.col = 0;
-                }
-                KeyCode::Backspace => {
-                    if buffer.col > 0 {
-                        // FIX: Remove previous grapheme.
-                        let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
-                        buffer.col -= 1;
-                        graphemes.remove(buffer.col);
-                        buffer.lines[buffer.row] = graphemes.join("");
-                    } else if buffer.row > 0 {
-                        let prev_line = buffer.lines.remove(buffer.row);
-                        buffer.row -= 1;
-                        buffer.col = buffer.lines[buffer.row].graphemes(true).count();
-                        buffer.lines[buffer.row].push_str(&prev_line);
-                    }
-                }
-                KeyCode::Left => buffer.col = buffer.col.saturating_sub(1),
-                KeyCode::Right => buffer.col += 1,
-                KeyCode::Up => buffer.row = buffer.row.saturating_sub(1),
-                KeyCode::Down => buffer.row += 1,
-                KeyCode::Char(c) => {
-                    // FIX: Insert by grapheme.
-                    let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
-                    let char_str = c.to_string();
-                    graphemes.insert(buffer.col, &char_str);
-                    // This is a bit inefficient, but safe.
-                    buffer.lines[buffer.row] = graphemes.join("");
-                    buffer.col += 1;
-                }
-                _ => buffer.modified = false, // No change for other keys
-            }
+    /// `Home`: moves to the first non-blank character, or to column 0 if already
+    /// there. The target follows from the cursor position alone, so the toggle
+    /// stays right after any other motion.
+    fn smart_home(&mut self) {
+        let first = first_non_blank(&self.lines[self.row]);
+        self.col = if self.col == first { 0 } else { first };
+    }
+}
+
+/// Indentation options of a buffer. They start from the file type's defaults
+/// and `:set` changes them for the active buffer only.
+#[derive(Clone, Copy)]
+struct Indent {
+    /// Number of columns a tab character advances to.
+    tabstop: usize,
+    /// Columns per indentation level; 0 means the same as `tabstop`.
+    shiftwidth: usize,
+    /// Tab inserts spaces up to the next indentation level instead of a tab character.
+    expandtab: bool,
+}
+
+impl Default for Indent {
+    fn default() -> Indent {
+        Indent { tabstop: 8, shiftwidth: 0, expandtab: false }
+    }
+}
+
+impl Indent {
+    /// Defaults for a file type. Unknown types use 8-column tabs.
+    fn for_filetype(filetype: Option<&str>) -> Indent {
+        let spaces = |width| Indent { tabstop: width, shiftwidth: width, expandtab: true };
+        match filetype {
+            Some("python" | "rust" | "c" | "cpp" | "csharp" | "java" | "kotlin" | "swift" | "markdown") => spaces(4),
+            Some("javascript" | "typescript" | "json" | "yaml" | "html" | "css" | "ruby" | "lua" | "dart") => spaces(2),
+            Some("go") => Indent { tabstop: 4, shiftwidth: 4, expandtab: false },
+            _ => Indent::default(),
         }
-        Mode::Insert
     }
 
-    /// Handles key presses in command mode.
-    fn handle_command_mode_key(&mut self, key_code: KeyCode) -> Mode {
-        match key_code {
-            KeyCode::Esc => {
-                self.command_input.clear();
-                self.command_message.clear();
-                return Mode::Normal;
-            }
-            KeyCode::Enter => {
-                let command = self.command_input.trim().to_string();
-                self.execute_command(&command);
-                self.command_input.clear();
-                return Mode::Normal;
-            }
-            KeyCode::Backspace => {
-                self.command_input.pop();
-            }
-            KeyCode::Char(c) => {
-                self.command_input.push(c);
-            }
-            _ => {}
+    /// These options changed to indent the way `style` does.
+    fn with_style(self, style: IndentStyle) -> Indent {
+        match style {
+            IndentStyle::Tabs => Indent { shiftwidth: 0, expandtab: false, ..self },
+            IndentStyle::Spaces(width) => Indent { shiftwidth: width, expandtab: true, ..self },
+            IndentStyle::Unknown => self,
         }
-        Mode::Command
     }
 
-    /// Handles key presses in the tree view.
-    fn handle_tree_view_key(&mut self, key_code: KeyCode) {
-        match key_code {
-            KeyCode::Char('j') | KeyCode::Down => {
-                self.selected_item_index = (self.selected_item_index + 1).min(self.tree_items.len().saturating_sub(1));
-            }
+    fn shiftwidth(&self) -> usize {
+        if self.shiftwidth == 0 { self.tabstop } else { self.shiftwidth }
+    }
+
+    fn bool_option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "expandtab" | "et" => Some(&mut self.expandtab),
+            _ => None,
+        }
+    }
+
+    fn number_option(&mut self, name: &str) -> Option<&mut usize> {
+        match name {
+            "tabstop" | "ts" => Some(&mut self.tabstop),
+            "shiftwidth" | "sw" => Some(&mut self.shiftwidth),
+            _ => None,
+        }
+    }
+
+    /// Applies a `:set` argument if it names an indentation option. Returns
+    /// `Ok(false)` for other options.
+    fn apply(&mut self, arg: &str) -> Result<bool, EditorError> {
+        if let Some((name, value)) = arg.split_once('=') {
+            let Some(option) = self.number_option(name) else { return Ok(false) };
+            match value.parse::<usize>() {
+                Ok(0) if matches!(name, "tabstop" | "ts") => return Err(EditorError::invalid_value(name, value)),
+                Ok(n) => *option = n,
+                Err(_) => return Err(EditorError::invalid_value(name, value)),
+            }
+            return Ok(true);
+        }
+        Ok(apply_bool_option(self, arg, Indent::bool_option))
+    }
+}
+
+/// How a file indents its lines, as found by `detect_indent`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum IndentStyle {
+    Tabs,
+    /// Spaces, this many per level.
+    Spaces(usize),
+    /// Nothing is indented, or tabs and spaces are too mixed to tell.
+    Unknown,
+}
+
+/// The `:set indent` text: the indentation options and where they came from.
+fn indent_summary(indent: &Indent, style: IndentStyle) -> String {
+    let source = match style {
+        IndentStyle::Tabs => "tabs, detected from the file",
+        IndentStyle::Spaces(_) => "spaces, detected from the file",
+        IndentStyle::Unknown => "file type default",
+    };
+    format!(
+        "{}expandtab shiftwidth={} tabstop={} ({})",
+        if indent.expandtab { "" } else { "no" },
+        indent.shiftwidth(),
+        indent.tabstop,
+        source
+    )
+}
+
+/// Lines looked at by `detect_indent`; the start of a file is enough to tell.
+const DETECT_INDENT_LINES: usize = 1000;
+
+/// Guesses whether `lines` are indented with tabs or spaces, and for spaces how
+/// many per level: the most common step up from one line's indent to the next.
+/// One kind has to indent at least twice as many lines as the other to count.
+/// Lines starting with `*` after the indent (block comment bodies) are skipped.
+fn detect_indent(lines: &[String]) -> IndentStyle {
+    let (mut tabs, mut spaces) = (0, 0);
+    // How often each step of 1 to 8 spaces appears.
+    let mut steps = [0usize; 9];
+    let mut previous = 0;
+    for line in lines.iter().take(DETECT_INDENT_LINES) {
+        let body = line.trim_start_matches([' ', '\t']);
+        if body.is_empty() || body.starts_with('*') {
+            continue;
+        }
+        let indent = &line[..line.len() - body.len()];
+        if indent.starts_with('\t') {
+            tabs += 1;
+            continue;
+        }
+        if indent.contains('\t') {
+            continue;
+        }
+        if !indent.is_empty() {
+            spaces += 1;
+        }
+        if let Some(step) = indent.len().checked_sub(previous).filter(|step| (1..=8).contains(step)) {
+            steps[step] += 1;
+        }
+        previous = indent.len();
+    }
+    match (tabs, spaces) {
+        (0, 0) => IndentStyle::Unknown,
+        (tabs, spaces) if tabs >= 2 * spaces => IndentStyle::Tabs,
+        (tabs, spaces) if spaces >= 2 * tabs => {
+            // Ties go to the smaller step, which the larger one is often a multiple of.
+            let width = (1..=8).rev().max_by_key(|&step| steps[step]).filter(|&step| steps[step] > 0);
+            width.map_or(IndentStyle::Unknown, IndentStyle::Spaces)
+        }
+        _ => IndentStyle::Unknown,
+    }
+}
+
+/// A failure shown to the user on the command line.
+#[derive(Debug, thiserror::Error)]
+enum EditorError {
+    #[error("Error loading {}: {source}", path.display())]
+    Load { path: PathBuf, source: io::Error },
+    /// The file is not valid UTF-8.
+    #[error("Error loading {}: {source}", path.display())]
+    Encoding { path: PathBuf, source: io::Error },
+    #[error("{} is still loading", .0.display())]
+    StillLoading(PathBuf),
+    #[error("Error saving {}: {source}", path.display())]
+    Save { path: PathBuf, source: io::Error },
+    #[error("Cannot read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Cannot read word list {}: {source} (use :set spellfile=<path>)", path.display())]
+    WordList { path: PathBuf, source: io::Error },
+    #[error("Failed to run {command}: {source}")]
+    Shell { command: String, source: io::Error },
+    #[error("Cannot change directory to {}: {source}", path.display())]
+    ChangeDir { path: PathBuf, source: io::Error },
+    #[error("Not a directory: {}", .0.display())]
+    NotADirectory(PathBuf),
+    #[error("Error in {} line {line}: {source}", path.display())]
+    Config { path: PathBuf, line: usize, source: Box<EditorError> },
+    #[error("Broken link: {} -> {}", .0.display(), .1.display())]
+    BrokenLink(PathBuf, PathBuf),
+    #[error("{} links back to {}", .0.display(), .1.display())]
+    LinkCycle(PathBuf, PathBuf),
+    #[error("Cannot rename {}: {source}", path.display())]
+    Rename { path: PathBuf, source: io::Error },
+    #[error("{} is binary and read-only (:set binary edits it as text)", .0.display())]
+    Binary(PathBuf),
+    #[error("Trailing characters: {0}")]
+    TrailingCharacters(String),
+    #[error("Address required: {0}")]
+    AddressRequired(String),
+    #[error("Cannot move a range of lines into itself")]
+    MoveIntoItself,
+    #[error("Invalid pattern {pattern}: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+    #[error("Invalid range: {0}")]
+    InvalidRange(String),
+    #[error("Mark not set: {0}")]
+    MarkNotSet(String),
+    #[error("No range allowed for :{0}")]
+    NoRangeAllowed(String),
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("Recursive alias: {0}")]
+    RecursiveAlias(String),
+    #[error("No such alias: {0}")]
+    NoSuchAlias(String),
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+    #[error("No write since last change for {0} (add ! to override)")]
+    Unsaved(String),
+    #[error("Invalid value for {name}: {value}")]
+    InvalidValue { name: String, value: String },
+}
+
+impl EditorError {
+    /// A failure to read `path` into a buffer, telling undecodable files apart.
+    fn load(path: &Path, source: io::Error) -> EditorError {
+        let path = path.to_path_buf();
+        match source.kind() {
+            io::ErrorKind::InvalidData => EditorError::Encoding { path, source },
+            _ => EditorError::Load { path, source },
+        }
+    }
+
+    fn invalid_value(name: &str, value: &str) -> EditorError {
+        EditorError::InvalidValue { name: name.to_string(), value: value.to_string() }
+    }
+}
+
+/// What the command line shows while nothing is typed on it.
+#[derive(Debug)]
+enum Message {
+    Info(String),
+    /// Drawn in the error style until another message replaces it.
+    Error(EditorError),
+}
+
+impl Message {
+    fn clear(&mut self) {
+        *self = Message::default();
+    }
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Message::Info(String::new())
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Message::Info(text) => f.write_str(text),
+            Message::Error(error) => error.fmt(f),
+        }
+    }
+}
+
+/// Editor options changed with `:set`.
+struct Settings {
+    /// Strip trailing spaces/tabs from every line when saving a modified buffer.
+    trim_trailing: bool,
+    /// Make sure the saved file ends with exactly one newline.
+    fix_eol: bool,
+    /// Auto-insert closing brackets and quotes in insert mode.
+    autopairs: bool,
+    /// Show a vertical scrollbar right of the text.
+    scrollbar: bool,
+    /// Show a horizontal scroll indicator below the text.
+    hscrollbar: bool,
+    /// Always show the sign column, even when no line has a sign.
+    signcolumn: bool,
+    /// Minimum number of lines kept visible above and below the cursor.
+    scrolloff: usize,
+    /// Minimum width of the line-number column, so it doesn't grow as lines are added.
+    numberwidth: usize,
+    /// Fold by indentation (`:set foldmethod=indent`) instead of only with `zf`.
+    fold_by_indent: bool,
+    /// How many cells characters are assumed to take on screen.
+    width: WidthConfig,
+    /// Underline unknown words in prose files.
+    spell: bool,
+    /// Word list used by `spell`, one word per line.
+    spellfile: PathBuf,
+    /// Send RGB colors as they are; otherwise they are drawn with the nearest of
+    /// the 256 standard colors. Defaults to whether `COLORTERM` announces truecolor.
+    termguicolors: bool,
+    /// Save the expanded tree directories on exit, for the next session started
+    /// in the same directory.
+    tree_session: bool,
+    /// Let the cursor go past the end of the line (`:set virtualedit=all`); text
+    /// typed there is padded with spaces.
+    virtual_edit: bool,
+    /// Searches and `:s` / `:g` patterns match regardless of case.
+    ignore_case: bool,
+    /// With `ignore_case`, a pattern containing an uppercase letter still
+    /// matches case exactly.
+    smart_case: bool,
+    /// Patterns are regular expressions; `:set nomagic` makes them literal text.
+    magic: bool,
+    /// Milliseconds a pending count, operator or prefix waits for the next key
+    /// before it is dropped; 0 waits forever.
+    timeoutlen: usize,
+    /// Show `line,col` in the status bar with the screen column when it differs,
+    /// and how far through the file the cursor is, in place of `line:col`.
+    ruler: bool,
+    /// Mark lines changed since the file was read or saved in the sign column.
+    change_marks: bool,
+    /// Show the tree when the editor starts.
+    start_tree: bool,
+    /// Give the tree the focus when the editor starts without files to open.
+    tree_focus: bool,
+    /// Start in Insert mode, in the buffer.
+    start_insert: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            trim_trailing: false,
+            fix_eol: false,
+            autopairs: false,
+            scrollbar: false,
+            hscrollbar: false,
+            signcolumn: false,
+            scrolloff: 0,
+            numberwidth: 3,
+            fold_by_indent: false,
+            width: WidthConfig::default(),
+            spell: false,
+            termguicolors: truecolor_supported(),
+            tree_session: true,
+            virtual_edit: false,
+            ignore_case: false,
+            smart_case: false,
+            magic: true,
+            ruler: false,
+            change_marks: true,
+            start_tree: true,
+            tree_focus: true,
+            start_insert: false,
+            timeoutlen: 1000,
+            spellfile: PathBuf::from("/usr/share/dict/words"),
+        }
+    }
+}
+
+impl Settings {
+    fn bool_option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "trimtrailing" => Some(&mut self.trim_trailing),
+            "fixeol" | "fixendofline" => Some(&mut self.fix_eol),
+            "autopairs" => Some(&mut self.autopairs),
+            "scrollbar" => Some(&mut self.scrollbar),
+            "hscrollbar" => Some(&mut self.hscrollbar),
+            "signcolumn" | "scl" => Some(&mut self.signcolumn),
+            "changemarks" => Some(&mut self.change_marks),
+            "starttree" => Some(&mut self.start_tree),
+            "treefocus" => Some(&mut self.tree_focus),
+            "startinsert" => Some(&mut self.start_insert),
+            "spell" => Some(&mut self.spell),
+            "termguicolors" | "tgc" => Some(&mut self.termguicolors),
+            "treesession" => Some(&mut self.tree_session),
+            "ignorecase" | "ic" => Some(&mut self.ignore_case),
+            "smartcase" | "scs" => Some(&mut self.smart_case),
+            "magic" => Some(&mut self.magic),
+            "ruler" | "ru" => Some(&mut self.ruler),
+            _ => None,
+        }
+    }
+
+    /// Compiles a typed pattern. With `smartcase`, an uppercase letter in it
+    /// (other than in an escape like `\W`) makes it match case exactly.
+    fn regex(&self, pattern: &str) -> Result<Regex, EditorError> {
+        let mut escaped = false;
+        let has_upper = pattern.chars().any(|c| {
+            let upper = c.is_uppercase() && !(escaped && self.magic);
+            escaped = c == '\\' && !escaped;
+            upper
+        });
+        let ignore_case = self.ignore_case && !(self.smart_case && has_upper);
+        Regex::new(pattern, self.magic, ignore_case).map_err(|reason| EditorError::InvalidPattern { pattern: pattern.to_string(), reason })
+    }
+
+    /// Like `regex`, except that the word searched for by `*` and `#` is not
+    /// typed, so `smartcase` leaves it alone.
+    fn search_regex(&self, search: &Search) -> Result<Regex, EditorError> {
+        match search.whole_word {
+            true => Regex::new(&search.pattern, false, self.ignore_case)
+                .map_err(|reason| EditorError::InvalidPattern { pattern: search.pattern.clone(), reason }),
+            false => self.regex(&search.pattern),
+        }
+    }
+
+    fn number_option(&mut self, name: &str) -> Option<&mut usize> {
+        match name {
+            "scrolloff" | "so" => Some(&mut self.scrolloff),
+            "numberwidth" | "nuw" => Some(&mut self.numberwidth),
+            "timeoutlen" | "tm" => Some(&mut self.timeoutlen),
+            _ => None,
+        }
+    }
+
+    /// Applies a single `:set` argument: `name`, `noname`, `name!` or `name=value`.
+    fn apply(&mut self, arg: &str) -> Result<(), EditorError> {
+        if let Some((name, value)) = arg.split_once('=') {
+            if matches!(name, "ambiwidth" | "ambw") {
+                self.width.ambiguous_double = match value {
+                    "single" => false,
+                    "double" => true,
+                    _ => return Err(EditorError::invalid_value(name, value)),
+                };
+                return Ok(());
+            }
+            if matches!(name, "foldmethod" | "fdm") {
+                self.fold_by_indent = match value {
+                    "manual" => false,
+                    "indent" => true,
+                    _ => return Err(EditorError::invalid_value(name, value)),
+                };
+                return Ok(());
+            }
+            if matches!(name, "virtualedit" | "ve") {
+                self.virtual_edit = match value {
+                    "" | "none" => false,
+                    "all" => true,
+                    _ => return Err(EditorError::invalid_value(name, value)),
+                };
+                return Ok(());
+            }
+            if matches!(name, "spellfile" | "spf") {
+                self.spellfile = PathBuf::from(value);
+                return Ok(());
+            }
+            let option = self.number_option(name).ok_or_else(|| EditorError::UnknownOption(name.to_string()))?;
+            *option = value.parse().map_err(|_| EditorError::invalid_value(name, value))?;
+            return Ok(());
+        }
+        if apply_bool_option(self, arg, Settings::bool_option) {
+            return Ok(());
+        }
+        Err(EditorError::UnknownOption(arg.to_string()))
+    }
+}
+
+/// Sets (`name`), clears (`noname`) or toggles (`name!`) the boolean option that
+/// `lookup` finds on `target`. Returns false if there is no such option.
+fn apply_bool_option<T>(target: &mut T, arg: &str, lookup: for<'a> fn(&'a mut T, &str) -> Option<&'a mut bool>) -> bool {
+    if let Some(name) = arg.strip_suffix('!') {
+        if let Some(option) = lookup(target, name) {
+            *option = !*option;
+            return true;
+        }
+    } else if let Some(option) = lookup(target, arg) {
+        *option = true;
+        return true;
+    } else if let Some(option) = arg.strip_prefix("no").and_then(|name| lookup(target, name)) {
+        *option = false;
+        return true;
+    }
+    false
+}
+
+/// Whether spell checking applies to a file type: plain text and Markdown.
+fn is_prose(filetype: Option<&str>) -> bool {
+    matches!(filetype, Some("text" | "markdown"))
+}
+
+/// Reads a word list with one word per line.
+fn load_spell_words(path: &Path) -> io::Result<HashSet<String>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content.lines().map(str::trim).filter(|w| !w.is_empty()).map(|w| w.to_string()).collect())
+}
+
+/// Byte ranges of the words in `line` missing from `words`. A word is also known
+/// if its lowercase form is listed, so capitalized words pass. Words containing
+/// digits are never flagged.
+fn misspelled_words(line: &str, words: &HashSet<String>) -> Vec<Range<usize>> {
+    line.split_word_bound_indices()
+        .filter(|(_, w)| w.starts_with(char::is_alphabetic) && !w.contains(|c: char| c.is_ascii_digit()))
+        .filter(|(_, w)| !words.contains(*w) && !words.contains(&w.to_lowercase()))
+        .map(|(i, w)| i..i + w.len())
+        .collect()
+}
+
+/// Edit distance between `a` and `b` in characters, counting a swap of two
+/// neighbouring characters (`teh` -> `the`) as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before_prev: Vec<usize> = Vec::new();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 0..a.len() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for j in 0..b.len() {
+            let substitution = prev[j] + usize::from(a[i] != b[j]);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                cur[j + 1] = cur[j + 1].min(before_prev[j - 1] + 1);
+            }
+        }
+        before_prev = std::mem::replace(&mut prev, cur);
+    }
+    prev[b.len()]
+}
+
+/// Up to nine listed words at most two edits away from `word`, closest first.
+/// A capitalized word gets capitalized suggestions.
+fn spell_suggestions(word: &str, words: &HashSet<String>) -> Vec<String> {
+    let lower = word.to_lowercase();
+    let len = lower.chars().count();
+    let mut candidates: Vec<(usize, String)> = words
+        .iter()
+        .filter(|w| w.chars().count().abs_diff(len) <= 2)
+        .map(|w| (edit_distance(&lower, &w.to_lowercase()), w.clone()))
+        .filter(|(distance, _)| *distance <= 2)
+        .collect();
+    candidates.sort();
+    let capitalize = word.starts_with(char::is_uppercase);
+    let mut suggestions: Vec<String> = Vec::new();
+    for (_, w) in candidates {
+        let w = match w.chars().next() {
+            Some(first) if capitalize => first.to_uppercase().chain(w.chars().skip(1)).collect(),
+            _ => w,
+        };
+        if !suggestions.contains(&w) {
+            suggestions.push(w);
+        }
+        if suggestions.len() == 9 {
+            break;
+        }
+    }
+    suggestions
+}
+
+/// Distinct words in `lines` that start with `prefix` (and are longer), in the
+/// order they appear from `row` on, wrapping around to the lines above.
+fn completion_candidates(lines: &[String], row: usize, prefix: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for i in 0..lines.len() {
+        let line = &lines[(row + i) % lines.len()];
+        for word in line.split_word_bounds() {
+            if word.len() > prefix.len() && word.starts_with(prefix) && word.graphemes(true).all(|g| grapheme_class(g) == 1) && seen.insert(word) {
+                candidates.push(word.to_string());
+            }
+        }
+    }
+    candidates
+}
+
+/// An Insert-mode `Ctrl-n`/`Ctrl-p` completion in progress on `row`.
+struct Completion {
+    row: usize,
+    /// Grapheme column where the completed word starts.
+    start_col: usize,
+    /// What was typed before completion started; shown again after the last candidate.
+    prefix: String,
+    candidates: Vec<String>,
+    /// Candidate currently in the text, `None` for the typed prefix.
+    index: Option<usize>,
+}
+
+/// Replacements offered by `z=` for the word at `range` (bytes) of `row`.
+struct SpellPopup {
+    row: usize,
+    range: Range<usize>,
+    suggestions: Vec<String>,
+}
+
+/// Character width assumptions shared by cursor placement, scrolling and selections.
+#[derive(Clone, Copy, Default)]
+struct WidthConfig {
+    /// East Asian ambiguous-width characters (`±`, `→`, `○`, ...) take two cells,
+    /// as in terminals set up for CJK text. `:set ambiwidth=double`.
+    ambiguous_double: bool,
+}
+
+/// Number of terminal cells `s` takes up.
+fn display_width(s: &str, cfg: &WidthConfig) -> usize {
+    if cfg.ambiguous_double {
+        UnicodeWidthStr::width_cjk(s)
+    } else {
+        UnicodeWidthStr::width(s)
+    }
+}
+
+/// Display width of `text` when it starts at column 0, with tabs advancing to the next tab stop.
+fn text_width(text: &str, tabstop: usize, cfg: &WidthConfig) -> usize {
+    text.graphemes(true).fold(0, |width, g| if g == "\t" { width + tabstop - width % tabstop } else { width + display_width(g, cfg) })
+}
+
+/// Display width of a line's leading whitespace, with tabs advancing to the next tab stop.
+fn indent_width(line: &str, tabstop: usize) -> usize {
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += tabstop - width % tabstop,
+            _ => break,
+        }
+    }
+    width
+}
+
+/// Character class for word motions: 0 for blanks, 1 for word characters, 2 for punctuation.
+/// A grapheme goes by its first character, so combining marks don't split a word.
+fn grapheme_class(g: &str) -> u8 {
+    if g.trim().is_empty() {
+        0
+    } else if g.chars().next().is_some_and(is_word_char) {
+        1
+    } else {
+        2
+    }
+}
+
+/// Index of the next word start after `col`, or `graphemes.len()` if the line has none.
+fn next_word_start(graphemes: &[&str], col: usize) -> usize {
+    let n = graphemes.len();
+    let mut i = col.min(n);
+    if i < n {
+        let class = grapheme_class(graphemes[i]);
+        if class != 0 {
+            while i < n && grapheme_class(graphemes[i]) == class { i += 1; }
+        }
+    }
+    while i < n && grapheme_class(graphemes[i]) == 0 { i += 1; }
+    i
+}
+
+/// Index of the start of the word before `col` (0 if there is none).
+fn prev_word_start(graphemes: &[&str], col: usize) -> usize {
+    let mut i = col.min(graphemes.len());
+    while i > 0 && grapheme_class(graphemes[i - 1]) == 0 { i -= 1; }
+    if i > 0 {
+        let class = grapheme_class(graphemes[i - 1]);
+        while i > 0 && grapheme_class(graphemes[i - 1]) == class { i -= 1; }
+    }
+    i
+}
+
+/// Index of the last grapheme of the first word found at or after `from`.
+fn word_end(graphemes: &[&str], from: usize) -> Option<usize> {
+    let n = graphemes.len();
+    let mut i = from;
+    while i < n && grapheme_class(graphemes[i]) == 0 { i += 1; }
+    if i >= n {
+        return None;
+    }
+    let class = grapheme_class(graphemes[i]);
+    while i + 1 < n && grapheme_class(graphemes[i + 1]) == class { i += 1; }
+    Some(i)
+}
+
+/// Row `{` (backward) or `}` (forward) moves to from `row`: the next blank line
+/// after the current paragraph, or the first/last line.
+fn paragraph_row(lines: &[String], row: usize, forward: bool) -> usize {
+    let is_blank = |i: usize| lines[i].trim().is_empty();
+    let last = lines.len() - 1;
+    let mut row = row;
+    if forward {
+        while row < last && is_blank(row) { row += 1; }
+        while row < last && !is_blank(row) { row += 1; }
+    } else {
+        while row > 0 && is_blank(row) { row -= 1; }
+        while row > 0 && !is_blank(row) { row -= 1; }
+    }
+    row
+}
+
+/// Text an operator acts on: from `start` up to, but not including, `end`, both
+/// `(row, grapheme col)`. A `linewise` range covers rows `start.0..=end.0` whole.
+#[derive(Clone, Copy)]
+struct TextRange {
+    start: (usize, usize),
+    end: (usize, usize),
+    linewise: bool,
+}
+
+impl TextRange {
+    fn chars(row: usize, start: usize, end: usize) -> TextRange {
+        TextRange { start: (row, start), end: (row, end), linewise: false }
+    }
+
+    fn rows(first: usize, last: usize) -> TextRange {
+        TextRange { start: (first, 0), end: (last, 0), linewise: true }
+    }
+}
+
+/// Resolves a text object typed after an operator (`iw`, `a(`, `i"`, `ip`, ...)
+/// around the cursor at `(row, col)`. `inner` is `i`, otherwise `a`. A count
+/// selects the `count`th enclosing pair of brackets.
+fn text_object_range(lines: &[String], (row, col): (usize, usize), object: char, inner: bool, count: usize) -> Option<TextRange> {
+    match object {
+        'w' => word_object(&lines[row], row, col, inner),
+        '(' | ')' | 'b' => bracket_object(lines, (row, col), ('(', ')'), inner, count),
+        '[' | ']' => bracket_object(lines, (row, col), ('[', ']'), inner, count),
+        '{' | '}' | 'B' => bracket_object(lines, (row, col), ('{', '}'), inner, count),
+        '<' | '>' => bracket_object(lines, (row, col), ('<', '>'), inner, count),
+        '"' | '\'' | '`' => quote_object(&lines[row], row, col, object, inner),
+        'p' => Some(paragraph_object(lines, row, inner)),
+        _ => None,
+    }
+}
+
+/// `iw`: the run of word, punctuation or blank graphemes under the cursor.
+/// `aw` adds the blanks after it, or before it if there are none after.
+fn word_object(line: &str, row: usize, col: usize, inner: bool) -> Option<TextRange> {
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    if graphemes.is_empty() {
+        return None;
+    }
+    let col = col.min(graphemes.len() - 1);
+    let class = grapheme_class(graphemes[col]);
+    let same = |i: usize| grapheme_class(graphemes[i]) == class;
+    let mut start = col;
+    while start > 0 && same(start - 1) { start -= 1; }
+    let mut end = col + 1;
+    while end < graphemes.len() && same(end) { end += 1; }
+    if !inner && class != 0 {
+        let blank = |i: usize| grapheme_class(graphemes[i]) == 0;
+        let trailing = (end..graphemes.len()).take_while(|&i| blank(i)).count();
+        if trailing > 0 {
+            end += trailing;
+        } else {
+            start -= (0..start).rev().take_while(|&i| blank(i)).count();
+        }
+    }
+    Some(TextRange::chars(row, start, end))
+}
+
+/// `i(` / `a(` and friends: the text inside the `count`th pair of `open`/`close`
+/// around the cursor, which may span lines. The cursor may be on either bracket.
+fn bracket_object(lines: &[String], cursor: (usize, usize), (open, close): (char, char), inner: bool, count: usize) -> Option<TextRange> {
+    let graphemes: Vec<Vec<&str>> = lines.iter().map(|line| line.graphemes(true).collect()).collect();
+    let char_at = |(r, c): (usize, usize)| graphemes[r].get(c).and_then(|g| g.chars().next());
+    let prev = |(r, c): (usize, usize)| match c {
+        0 => r.checked_sub(1).map(|r| (r, graphemes[r].len())),
+        c => Some((r, c - 1)),
+    };
+    let next = |(r, c): (usize, usize)| if c < graphemes[r].len() { Some((r, c + 1)) } else if r + 1 < graphemes.len() { Some((r + 1, 0)) } else { None };
+    // Walk back to the unmatched opening bracket, starting on it if the cursor is there.
+    let mut start = cursor;
+    for i in 0..count {
+        let mut depth = 0;
+        let mut pos = if i == 0 && char_at(cursor) == Some(open) { Some(cursor) } else { prev(start) };
+        loop {
+            let p = pos?;
+            match char_at(p) {
+                Some(c) if c == open && depth == 0 => break,
+                Some(c) if c == open => depth -= 1,
+                Some(c) if c == close => depth += 1,
+                _ => {}
+            }
+            pos = prev(p);
+        }
+        start = pos?;
+    }
+    let mut depth = 0;
+    let mut end = next(start);
+    loop {
+        let p = end?;
+        match char_at(p) {
+            Some(c) if c == close && depth == 0 => break,
+            Some(c) if c == close => depth -= 1,
+            Some(c) if c == open => depth += 1,
+            _ => {}
+        }
+        end = next(p);
+    }
+    let end = end?;
+    if inner {
+        // `next` stops at the end of a line, which stands for its line break.
+        let after_open = next(start)?;
+        let after_open = if after_open.1 >= graphemes[after_open.0].len() && after_open < end { next(after_open)? } else { after_open };
+        return Some(TextRange { start: after_open, end, linewise: false });
+    }
+    Some(TextRange { start, end: (end.0, end.1 + 1), linewise: false })
+}
+
+/// `i"` / `a"`: the quoted text on the cursor line. Quotes pair up from the start
+/// of the line, skipping escaped ones; a cursor before the first pair uses it.
+/// `a"` includes the quotes and any blanks after the closing one.
+fn quote_object(line: &str, row: usize, col: usize, quote: char, inner: bool) -> Option<TextRange> {
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    let quote = quote.to_string();
+    let positions: Vec<usize> = (0..graphemes.len())
+        .filter(|&i| graphemes[i] == quote && (i == 0 || graphemes[i - 1] != "\\"))
+        .collect();
+    let (open, close) = positions
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| col <= close)?;
+    if inner {
+        return Some(TextRange::chars(row, open + 1, close));
+    }
+    let trailing = graphemes[close + 1..].iter().take_while(|g| grapheme_class(g) == 0).count();
+    Some(TextRange::chars(row, open, close + 1 + trailing))
+}
+
+/// `ip`: the lines around `row` that are all blank or all non-blank. `ap` adds
+/// the blank lines after a paragraph (or before it, at the end of the buffer).
+fn paragraph_object(lines: &[String], row: usize, inner: bool) -> TextRange {
+    let blank = |i: usize| lines[i].trim().is_empty();
+    let kind = blank(row);
+    let mut first = row;
+    while first > 0 && blank(first - 1) == kind { first -= 1; }
+    let mut last = row;
+    while last + 1 < lines.len() && blank(last + 1) == kind { last += 1; }
+    if !inner {
+        let end = last;
+        while last + 1 < lines.len() && blank(last + 1) != kind { last += 1; }
+        if last == end && !kind {
+            while first > 0 && blank(first - 1) { first -= 1; }
+        }
+    }
+    TextRange::rows(first, last)
+}
+
+/// Changes the case of every character of `text`. Rust's Unicode case
+/// mapping is locale independent and can change the length (`ß` uppercases to
+/// `SS`, `İ` lowercases to `i̇`), so callers must re-count graphemes afterwards.
+fn convert_case(text: &str, change: CaseChange) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match change {
+            CaseChange::Lower => result.extend(c.to_lowercase()),
+            CaseChange::Upper => result.extend(c.to_uppercase()),
+            CaseChange::Toggle => {
+                if c.is_lowercase() {
+                    result.extend(c.to_uppercase());
+                } else if c.is_uppercase() {
+                    result.extend(c.to_lowercase());
+                } else {
+                    result.push(c);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// File type of a file, from its extension, or from its name for files like
+/// `Makefile` and `Dockerfile` that have none.
+fn detect_filetype(filename: Option<&Path>) -> Option<String> {
+    let path = filename?;
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    let filetype = match (name.as_str(), extension.as_deref()) {
+        ("makefile" | "gnumakefile", _) | (_, Some("mk")) => "make",
+        ("dockerfile" | "containerfile", _) | (_, Some("dockerfile")) => "dockerfile",
+        (_, Some("rs")) => "rust",
+        (_, Some("py")) => "python",
+        (_, Some("c" | "h")) => "c",
+        (_, Some("cc" | "cpp" | "hpp")) => "cpp",
+        (_, Some("cs")) => "csharp",
+        (_, Some("java")) => "java",
+        (_, Some("kt")) => "kotlin",
+        (_, Some("swift")) => "swift",
+        (_, Some("scala")) => "scala",
+        (_, Some("dart")) => "dart",
+        (_, Some("zig")) => "zig",
+        (_, Some("go")) => "go",
+        (_, Some("js" | "jsx")) => "javascript",
+        (_, Some("ts" | "tsx")) => "typescript",
+        (_, Some("json")) => "json",
+        (_, Some("yaml" | "yml")) => "yaml",
+        (_, Some("html")) => "html",
+        (_, Some("css")) => "css",
+        (_, Some("rb")) => "ruby",
+        (_, Some("lua")) => "lua",
+        (_, Some("sh" | "bash" | "zsh")) => "sh",
+        (_, Some("pl")) => "perl",
+        (_, Some("toml")) => "toml",
+        (_, Some("conf")) => "conf",
+        (_, Some("r")) => "r",
+        (_, Some("sql")) => "sql",
+        (_, Some("hs")) => "haskell",
+        (_, Some("vim")) => "vim",
+        (_, Some("tex")) => "tex",
+        (_, Some("lisp" | "el")) => "lisp",
+        (_, Some("clj")) => "clojure",
+        (_, Some("md" | "markdown")) => "markdown",
+        (_, Some("txt")) => "text",
+        _ => return None,
+    };
+    Some(filetype.to_string())
+}
+
+/// Line-comment prefix for a file type.
+fn comment_prefix(filetype: Option<&str>) -> Option<&'static str> {
+    match filetype? {
+        "rust" | "c" | "cpp" | "csharp" | "java" | "javascript" | "typescript" | "go" | "swift" | "kotlin"
+        | "scala" | "dart" | "zig" => Some("//"),
+        "sh" | "python" | "ruby" | "perl" | "toml" | "yaml" | "conf" | "r" | "make" | "dockerfile" => Some("#"),
+        "sql" | "lua" | "haskell" => Some("--"),
+        "vim" => Some("\""),
+        "tex" => Some("%"),
+        "lisp" | "clojure" => Some(";"),
+        _ => None,
+    }
+}
+
+/// Radix and digit count of a character code typed after `Ctrl-v`, chosen by
+/// its first key: a decimal digit (`065`), `x` (`x41`), `o` (`o101`), `u`
+/// (`u00e9`) or `U` (`U0001f600`).
+fn literal_code_format(first: char) -> Option<(u32, usize)> {
+    match first {
+        '0'..='9' => Some((10, 3)),
+        'x' | 'X' => Some((16, 2)),
+        'o' | 'O' => Some((8, 3)),
+        'u' => Some((16, 4)),
+        'U' => Some((16, 8)),
+        _ => None,
+    }
+}
+
+/// Character of a `Ctrl-v` code, complete or cut short by another key. `None`
+/// without digits, for codes above 255 in decimal or octal, and for values
+/// that are not a character the buffer can hold.
+fn literal_code_char(code: &str) -> Option<char> {
+    let first = code.chars().next()?;
+    let (radix, _) = literal_code_format(first)?;
+    let digits = if first.is_ascii_digit() { code } else { &code[1..] };
+    let value = u32::from_str_radix(digits, radix).ok()?;
+    if matches!(radix, 8 | 10) && value > 255 {
+        return None;
+    }
+    char::from_u32(value).filter(|&c| c != '\n')
+}
+
+/// Closing character auto-inserted after `c` when `autopairs` is set.
+fn pair_closer(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        _ => None,
+    }
+}
+
+/// Grapheme index of the first non-blank character of a line.
+fn first_non_blank(line: &str) -> usize {
+    line.graphemes(true).take_while(|g| g.trim().is_empty()).count()
+}
+
+/// Cursor and scroll position remembered for a file between sessions.
+#[derive(Clone, Copy)]
+struct ViewState {
+    row: usize,
+    col: usize,
+    top_row: usize,
+}
+
+/// Message shown once `path` is open, warning that binary files are read-only.
+fn opened_message(path: &Path, binary: bool) -> String {
+    match binary {
+        true => format!("Opened {} [binary, read-only; :set binary to edit as text]", path.display()),
+        false => format!("Opened {}", path.display()),
+    }
+}
+
+/// Directory for persistent editor state: `$XDG_STATE_HOME/moti` or `~/.local/state/moti`.
+fn state_dir() -> Option<PathBuf> {
+    // Tests must neither see nor overwrite the state of whoever runs them.
+    if cfg!(test) {
+        return Some(std::env::temp_dir().join(format!("moti-test-state-{}", std::process::id())));
+    }
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+    };
+    Some(base.join("moti"))
+}
+
+/// The file of commands run at startup: `$XDG_CONFIG_HOME/moti/config` or
+/// `~/.config/moti/config`.
+fn config_file() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("moti").join("config"))
+}
+
+fn view_state_file() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join("viewstate"))
+}
+
+/// Reads the view-state file: one `row<TAB>col<TAB>top_row<TAB>path` entry per line.
+fn load_view_states() -> HashMap<PathBuf, ViewState> {
+    let mut states = HashMap::new();
+    let Some(content) = view_state_file().and_then(|f| std::fs::read_to_string(f).ok()) else { return states };
+    for line in content.lines() {
+        let mut fields = line.splitn(4, '\t');
+        let mut number = || fields.next().and_then(|f| f.parse::<usize>().ok());
+        if let (Some(row), Some(col), Some(top_row)) = (number(), number(), number()) {
+            if let Some(path) = fields.next() {
+                states.insert(PathBuf::from(path), ViewState { row, col, top_row });
+            }
+        }
+    }
+    states
+}
+
+/// Writes `content` to `path` so that a crash never leaves a half-written file:
+/// the text goes to a temporary file next to it, which is then renamed over it
+/// with the old file's permissions (and owner, where allowed). A failed write
+/// leaves the old file untouched. Files with several hard links are written in
+/// place to keep the links, and so are files whose directory doesn't allow
+/// creating the temporary file or renaming it.
+fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    // Replace the file a symlink points to, not the link.
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let metadata = std::fs::metadata(&path).ok();
+    #[cfg(unix)]
+    if metadata.as_ref().is_some_and(|m| std::os::unix::fs::MetadataExt::nlink(m) > 1) {
+        return std::fs::write(&path, content);
+    }
+    let in_place = |e: &io::Error| matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::CrossesDevices);
+    let name = path.file_name().map_or("file".into(), |n| n.to_string_lossy());
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let mut file = match std::fs::File::create(&temp) {
+        Ok(file) => file,
+        Err(e) if in_place(&e) => return std::fs::write(&path, content),
+        Err(e) => return Err(e),
+    };
+    let written = (|| {
+        io::Write::write_all(&mut file, content.as_bytes())?;
+        file.sync_all()?;
+        if let Some(metadata) = &metadata {
+            std::fs::set_permissions(&temp, metadata.permissions())?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                // Only root can give a file away; others keep their own ownership.
+                let _ = std::os::unix::fs::chown(&temp, Some(metadata.uid()), Some(metadata.gid()));
+            }
+        }
+        std::fs::rename(&temp, &path)
+    })();
+    match written {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            if in_place(&e) { std::fs::write(&path, content) } else { Err(e) }
+        }
+    }
+}
+
+fn write_view_states(states: &HashMap<PathBuf, ViewState>) -> io::Result<()> {
+    let Some(file) = view_state_file() else { return Ok(()) };
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut content = String::new();
+    for (path, state) in states {
+        content.push_str(&format!("{}\t{}\t{}\t{}\n", state.row, state.col, state.top_row, path.display()));
+    }
+    std::fs::write(file, content)
+}
+
+/// Shape of the directory tree remembered for a working directory.
+struct TreeState {
+    root: PathBuf,
+    expanded_dirs: Vec<PathBuf>,
+}
+
+fn tree_state_file() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join("treestate"))
+}
+
+/// Reads the tree-state file: one `working dir<TAB>tree root<TAB>expanded dir...`
+/// entry per line.
+fn load_tree_states() -> HashMap<PathBuf, TreeState> {
+    let mut states = HashMap::new();
+    let Some(content) = tree_state_file().and_then(|f| std::fs::read_to_string(f).ok()) else { return states };
+    for line in content.lines() {
+        let mut fields = line.split('\t').map(PathBuf::from);
+        if let (Some(dir), Some(root)) = (fields.next(), fields.next()) {
+            states.insert(dir, TreeState { root, expanded_dirs: fields.collect() });
+        }
+    }
+    states
+}
+
+fn write_tree_states(states: &HashMap<PathBuf, TreeState>) -> io::Result<()> {
+    let Some(file) = tree_state_file() else { return Ok(()) };
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut content = String::new();
+    for (dir, state) in states {
+        content.push_str(&format!("{}\t{}", dir.display(), state.root.display()));
+        for expanded in &state.expanded_dirs {
+            content.push_str(&format!("\t{}", expanded.display()));
+        }
+        content.push('\n');
+    }
+    std::fs::write(file, content)
+}
+
+/// Open files and tree written by `:mksession`, one `kind<TAB>fields` entry per
+/// line: `cwd<TAB>dir`, `root<TAB>dir`, `expanded<TAB>dir`,
+/// `buffer<TAB>row<TAB>col<TAB>top row<TAB>path` and `active<TAB>buffer index`.
+#[derive(Default)]
+struct Session {
+    cwd: Option<PathBuf>,
+    root: Option<PathBuf>,
+    expanded_dirs: Vec<PathBuf>,
+    buffers: Vec<(PathBuf, ViewState)>,
+    /// Index into `buffers`.
+    active: Option<usize>,
+}
+
+/// File `:mksession` and `:source` use when given none.
+const DEFAULT_SESSION_FILE: &str = "Session.moti";
+
+/// Reads a session file, skipping entries it does not understand.
+fn parse_session(content: &str) -> Session {
+    let mut session = Session::default();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            ["cwd", dir] => session.cwd = Some(PathBuf::from(dir)),
+            ["root", dir] => session.root = Some(PathBuf::from(dir)),
+            ["expanded", dir] => session.expanded_dirs.push(PathBuf::from(dir)),
+            ["buffer", row, col, top_row, path] => {
+                let (Ok(row), Ok(col), Ok(top_row)) = (row.parse(), col.parse(), top_row.parse()) else { continue };
+                session.buffers.push((PathBuf::from(path), ViewState { row, col, top_row }));
+            }
+            ["active", index] => session.active = index.parse().ok(),
+            _ => {}
+        }
+    }
+    session
+}
+
+/// Where a tag points in its file.
+#[derive(Clone)]
+enum TagAddress {
+    Line(usize),
+    /// A search pattern such as `^fn main() {$`, with the delimiters and escapes removed.
+    Pattern(String),
+}
+
+/// One definition listed in a ctags `tags` file.
+#[derive(Clone)]
+struct TagEntry {
+    name: String,
+    file: PathBuf,
+    address: TagAddress,
+}
+
+/// The parsed `tags` file and the modification time it was read at.
+struct TagsCache {
+    modified: Option<SystemTime>,
+    entries: HashMap<String, Vec<TagEntry>>,
+}
+
+/// Parses the address field of a tags line: a line number or a `/pattern/`
+/// (`?pattern?`) search, optionally followed by `;"` and extension fields.
+fn parse_tag_address(field: &str) -> Option<TagAddress> {
+    let mut chars = field.chars();
+    let delimiter = chars.next()?;
+    if delimiter.is_ascii_digit() {
+        let digits: String = field.chars().take_while(char::is_ascii_digit).collect();
+        return digits.parse().ok().map(TagAddress::Line);
+    }
+    if delimiter != '/' && delimiter != '?' {
+        return None;
+    }
+    let mut pattern = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => pattern.extend(chars.next()),
+            c if c == delimiter => return Some(TagAddress::Pattern(pattern)),
+            c => pattern.push(c),
+        }
+    }
+    None
+}
+
+/// Parses the lines of a tags file. File names are relative to `base`, the
+/// directory holding the tags file.
+fn parse_tags(content: &str, base: &Path) -> HashMap<String, Vec<TagEntry>> {
+    let mut entries: HashMap<String, Vec<TagEntry>> = HashMap::new();
+    for line in content.lines().filter(|l| !l.starts_with("!_TAG_")) {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(name), Some(file), Some(address)) = (fields.next(), fields.next(), fields.next()) else { continue };
+        let Some(address) = parse_tag_address(address) else { continue };
+        entries.entry(name.to_string()).or_default().push(TagEntry { name: name.to_string(), file: base.join(file), address });
+    }
+    entries
+}
+
+/// Row of `lines` a tag address points at. Patterns anchored with `^`/`$` must
+/// match the start/end of the line.
+fn find_tag_row(lines: &[String], address: &TagAddress) -> Option<usize> {
+    match address {
+        TagAddress::Line(line) => Some(line.saturating_sub(1).min(lines.len() - 1)),
+        TagAddress::Pattern(pattern) => {
+            let (pattern, at_start) = match pattern.strip_prefix('^') {
+                Some(rest) => (rest, true),
+                None => (pattern.as_str(), false),
+            };
+            let (pattern, at_end) = match pattern.strip_suffix('$') {
+                Some(rest) => (rest, true),
+                None => (pattern, false),
+            };
+            lines.iter().position(|line| match (at_start, at_end) {
+                (true, true) => line == pattern,
+                (true, false) => line.starts_with(pattern),
+                (false, true) => line.ends_with(pattern),
+                (false, false) => line.contains(pattern),
+            })
+        }
+    }
+}
+
+/// Splits a command-line file argument of the form `path[:line[:col]]`. An argument
+/// naming an existing file is taken literally even if it contains colons.
+fn parse_file_position(arg: &str) -> (PathBuf, Option<usize>, Option<usize>) {
+    if Path::new(arg).exists() {
+        return (PathBuf::from(arg), None, None);
+    }
+    let mut numbers = Vec::new();
+    let mut rest = arg;
+    while numbers.len() < 2 {
+        let Some((head, n)) = rest.rsplit_once(':') else { break };
+        match n.parse::<usize>() {
+            Ok(n) if !head.is_empty() => {
+                numbers.insert(0, n);
+                rest = head;
+            }
+            _ => break,
+        }
+    }
+    (PathBuf::from(rest), numbers.first().copied(), numbers.get(1).copied())
+}
+
+/// Colors used when drawing the editor.
+struct UiStyle {
+    /// Matches of the pattern being searched or substituted.
+    search_result_style: Style,
+    /// Text inside a visual selection.
+    selection_style: Style,
+    /// Words not found in the spell-check word list.
+    spell_error_style: Style,
+    /// Summary row standing in for a closed fold.
+    fold_style: Style,
+    /// Error messages on the command line.
+    error_message_style: Style,
+    /// Cursors added with `Ctrl-Down`/`Ctrl-Up`, besides the terminal cursor.
+    extra_cursor_style: Style,
+    /// Diff view: lines only in the right buffer.
+    diff_added_style: Style,
+    /// Diff view: lines only in the left buffer.
+    diff_removed_style: Style,
+    /// Diff view: lines that differ between the buffers.
+    diff_changed_style: Style,
+    /// Diff view: filler rows standing in for lines the other side has.
+    diff_filler_style: Style,
+    /// Sign column: `+` on lines added since the last save.
+    change_added_style: Style,
+    /// Sign column: `~` on lines changed since the last save.
+    change_changed_style: Style,
+    /// Sign column: `-` where lines were removed since the last save.
+    change_removed_style: Style,
+}
+
+impl Default for UiStyle {
+    fn default() -> UiStyle {
+        UiStyle {
+            search_result_style: Style::default().fg(Color::Black).bg(Color::Yellow),
+            selection_style: Style::default().bg(Color::Blue),
+            spell_error_style: Style::default().fg(Color::Red).add_modifier(Modifier::UNDERLINED),
+            fold_style: Style::default().fg(Color::Cyan).bg(Color::DarkGray),
+            error_message_style: Style::default().fg(Color::Red),
+            extra_cursor_style: Style::default().add_modifier(Modifier::REVERSED),
+            diff_added_style: Style::default().bg(Color::Green).fg(Color::Black),
+            diff_removed_style: Style::default().bg(Color::Red).fg(Color::Black),
+            diff_changed_style: Style::default().bg(Color::Blue),
+            diff_filler_style: Style::default().fg(Color::DarkGray),
+            change_added_style: Style::default().fg(Color::Green),
+            change_changed_style: Style::default().fg(Color::Blue),
+            change_removed_style: Style::default().fg(Color::Red),
+        }
+    }
+}
+
+/// Whether the terminal says it can show 24-bit colors, via `COLORTERM`.
+fn truecolor_supported() -> bool {
+    std::env::var("COLORTERM").is_ok_and(|value| matches!(value.as_str(), "truecolor" | "24bit"))
+}
+
+/// Index of the xterm 256-color palette entry closest to an RGB color: the
+/// nearer of the 6x6x6 color cube (16-231) and the gray ramp (232-255).
+fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let cube_index = |c: u8| match c {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => (c - 35) / 40,
+    };
+    let distance = |(r2, g2, b2): (u8, u8, u8)| {
+        [(r, r2), (g, g2), (b, b2)].iter().map(|&(a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+    };
+    let (ri, gi, bi) = (cube_index(r), cube_index(g), cube_index(b));
+    let cube = (LEVELS[ri as usize], LEVELS[gi as usize], LEVELS[bi as usize]);
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray = 8 + 10 * gray_index;
+    if distance((gray, gray, gray)) < distance(cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * ri + 6 * gi + bi
+    }
+}
+
+/// Replaces every RGB color drawn in `buffer` with its nearest 256-color
+/// equivalent, for terminals without truecolor.
+fn downgrade_rgb_colors(buffer: &mut ratatui::buffer::Buffer) {
+    let downgrade = |color: Color| match color {
+        Color::Rgb(r, g, b) => Color::Indexed(rgb_to_ansi256(r, g, b)),
+        color => color,
+    };
+    for cell in &mut buffer.content {
+        cell.fg = downgrade(cell.fg);
+        cell.bg = downgrade(cell.bg);
+        cell.underline_color = downgrade(cell.underline_color);
+    }
+}
+
+/// How a row of the diff view differs between the two buffers.
+#[derive(Clone, Copy, PartialEq)]
+enum DiffKind {
+    Equal,
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One screen row of the diff view: a line index on each side, `None` where
+/// that side shows a filler row.
+struct DiffRow {
+    left: Option<usize>,
+    right: Option<usize>,
+    kind: DiffKind,
+}
+
+/// A read-only side-by-side comparison of two buffers, opened with `:diff`.
+struct DiffView {
+    left: usize,
+    right: usize,
+    rows: Vec<DiffRow>,
+    /// Selected row, and the first row on screen. Both panes scroll together.
+    cursor: usize,
+    top: usize,
+}
+
+/// Lines up `left` and `right` row by row, pairing lines that were replaced
+/// and padding the shorter side with fillers so equal lines stay level.
+fn diff_rows(left: &[String], right: &[String]) -> Vec<DiffRow> {
+    let mut rows = Vec::new();
+    for op in similar::capture_diff_slices(Algorithm::Myers, left, right) {
+        match op {
+            DiffOp::Equal { old_index, new_index, len } => {
+                rows.extend((0..len).map(|i| DiffRow { left: Some(old_index + i), right: Some(new_index + i), kind: DiffKind::Equal }));
+            }
+            DiffOp::Delete { old_index, old_len, .. } => {
+                rows.extend((0..old_len).map(|i| DiffRow { left: Some(old_index + i), right: None, kind: DiffKind::Removed }));
+            }
+            DiffOp::Insert { new_index, new_len, .. } => {
+                rows.extend((0..new_len).map(|i| DiffRow { left: None, right: Some(new_index + i), kind: DiffKind::Added }));
+            }
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                rows.extend((0..old_len.max(new_len)).map(|i| DiffRow {
+                    left: (i < old_len).then_some(old_index + i),
+                    right: (i < new_len).then_some(new_index + i),
+                    kind: DiffKind::Changed,
+                }));
+            }
+        }
+    }
+    rows
+}
+
+/// Gutter marks for how `lines` differ from `baseline`: `+` on added lines, `~`
+/// on changed ones and `-` on the line after removed ones. The diff skips the
+/// unchanged lines at both ends first, so small edits to big files stay cheap.
+fn change_marks(baseline: &[String], lines: &[String]) -> HashMap<usize, char> {
+    let mut marks = HashMap::new();
+    let deadline = Instant::now() + Duration::from_millis(50);
+    for op in similar::capture_diff_slices_deadline(Algorithm::Myers, baseline, lines, Some(deadline)) {
+        match op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Insert { new_index, new_len, .. } => marks.extend((new_index..new_index + new_len).map(|i| (i, '+'))),
+            DiffOp::Replace { old_len, new_index, new_len, .. } => {
+                marks.extend((0..new_len).map(|i| (new_index + i, if i < old_len { '~' } else { '+' })));
+                if old_len > new_len {
+                    marks.entry((new_index + new_len).min(lines.len() - 1)).or_insert('-');
+                }
+            }
+            DiffOp::Delete { new_index, .. } => {
+                marks.entry(new_index.min(lines.len() - 1)).or_insert('-');
+            }
+        }
+    }
+    marks
+}
+
+/// A parsed `:s/pattern/replacement/flags` command.
+#[derive(Clone)]
+struct Substitute {
+    pattern: String,
+    replacement: String,
+    /// The `g` flag replaces every match on a line rather than just the first.
+    global: bool,
+    /// The `c` flag asks before each replacement.
+    confirm: bool,
+}
+
+/// Splits the line range (`%`, `10,20`, `.,$`, `'<,'>`, `.+2`) off the front of
+/// an Ex command, returning it and the rest of the command.
+fn split_range(command: &str) -> (&str, &str) {
+    let mut end = 0;
+    let mut chars = command.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '0'..='9' | '.' | '$' | '%' | ',' | '+' | '-' => end = i + 1,
+            // A mark is a quote and the mark's name.
+            '\'' => match chars.next() {
+                Some((j, mark)) => end = j + mark.len_utf8(),
+                None => end = i + 1,
+            },
+            _ => break,
+        }
+    }
+    command.split_at(end)
+}
+
+/// Resolves a range from `split_range` to 0-based rows `(first, last)`, or
+/// `None` for an empty range. `cursor` is the row of `.`, `last` the row of `$`
+/// and `marks` the rows of `'<` and `'>`. Line numbers are 1-based as typed.
+fn resolve_range(range: &str, cursor: usize, last: usize, marks: Option<(usize, usize)>) -> Result<Option<(usize, usize)>, EditorError> {
+    if range.is_empty() {
+        return Ok(None);
+    }
+    if range == "%" {
+        return Ok(Some((0, last)));
+    }
+    let invalid = || EditorError::InvalidRange(range.to_string());
+    let address = |text: &str| -> Result<usize, EditorError> {
+        let digits = |s: &str| s.chars().take_while(char::is_ascii_digit).count();
+        let (base, mut rest) = match text.chars().next() {
+            Some('0'..='9') => {
+                let n = digits(text);
+                (text[..n].parse::<isize>().map_err(|_| invalid())? - 1, &text[n..])
+            }
+            Some('.') => (cursor as isize, &text[1..]),
+            Some('$') => (last as isize, &text[1..]),
+            Some('\'') => {
+                let marks = marks.ok_or_else(|| EditorError::MarkNotSet(text.chars().take(2).collect()))?;
+                match text.get(..2) {
+                    Some("'<") => (marks.0 as isize, &text[2..]),
+                    Some("'>") => (marks.1 as isize, &text[2..]),
+                    _ => return Err(EditorError::MarkNotSet(text.chars().take(2).collect())),
+                }
+            }
+            _ => (cursor as isize, text),
+        };
+        let mut row = base;
+        // Offsets like `+2`, `-1` or a bare `+` (one line) follow the address.
+        while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+            let n = digits(&rest[1..]);
+            let amount = if n == 0 { 1 } else { rest[1..1 + n].parse::<isize>().map_err(|_| invalid())? };
+            row += if sign == '+' { amount } else { -amount };
+            rest = &rest[1 + n..];
+        }
+        if !rest.is_empty() || row < 0 || row > last as isize {
+            return Err(invalid());
+        }
+        Ok(row as usize)
+    };
+    let (first, second) = match range.split_once(',') {
+        Some((first, second)) => (address(first)?, address(second)?),
+        None => {
+            let row = address(range)?;
+            (row, row)
+        }
+    };
+    Ok(Some((first.min(second), first.max(second))))
+}
+
+/// Parses `s/pattern/replacement/flags`, without the line range. Any punctuation
+/// character after `s` is the delimiter and a backslash escapes it. Missing trailing
+/// parts default to empty, so an in-progress `s/foo` already yields its pattern.
+fn parse_substitute(command: &str) -> Option<Substitute> {
+    let rest = command.strip_prefix('s')?;
+    let delimiter = rest.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+        return None;
+    }
+    let mut parts = vec![String::new()];
+    let mut chars = rest[delimiter.len_utf8()..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => parts.last_mut()?.push(next),
+                Some(next) => {
+                    let part = parts.last_mut()?;
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => parts.last_mut()?.push('\\'),
+            },
+            c if c == delimiter && parts.len() < 3 => parts.push(String::new()),
+            c => parts.last_mut()?.push(c),
+        }
+    }
+    let mut parts = parts.into_iter();
+    let pattern = parts.next().unwrap_or_default();
+    let replacement = parts.next().unwrap_or_default();
+    let flags = parts.next().unwrap_or_default();
+    Some(Substitute { pattern, replacement, global: flags.contains('g'), confirm: flags.contains('c') })
+}
+
+/// `:g/pattern/command`, or `:v` (`:g!`) for the lines that don't match.
+struct Global {
+    invert: bool,
+    pattern: String,
+    command: String,
+}
+
+/// Parses `g/pattern/command`, `g!/pattern/command` or `v/pattern/command`. As
+/// with `:s`, any punctuation character is the delimiter and a backslash escapes it.
+fn parse_global(command: &str) -> Option<Global> {
+    let (invert, rest) = match command.strip_prefix('g') {
+        Some(rest) => match rest.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        },
+        None => (true, command.strip_prefix('v')?),
+    };
+    let delimiter = rest.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+        return None;
+    }
+    let mut pattern = String::new();
+    let mut chars = rest[delimiter.len_utf8()..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => pattern.push(next),
+                Some(next) => {
+                    pattern.push('\\');
+                    pattern.push(next);
+                }
+                None => pattern.push('\\'),
+            },
+            c if c == delimiter => break,
+            c => pattern.push(c),
+        }
+    }
+    Some(Global { invert, pattern, command: chars.as_str().trim().to_string() })
+}
+
+/// What the user is asked for on the command line.
+enum PromptKind {
+    /// `y` answers yes; `n` and Esc answer no.
+    YesNo,
+    /// A line of text, edited in place; Enter accepts it and Esc cancels.
+    Text(String),
+    /// One of these keys answers with itself; Esc cancels and other keys ask again.
+    Choice(&'static str),
+}
+
+/// What to do once a prompt has been answered.
+enum PromptAction {
+    /// Quit even though a buffer has unsaved changes.
+    Quit,
+    /// Rename the file or directory at this path to the answer.
+    Rename(PathBuf),
+    /// Carry on with a `:s///c` as the answer says; cancelling stops it.
+    Substitute(Box<SubstituteConfirm>),
+}
+
+/// A question shown on the command line that takes every key until answered.
+struct Prompt {
+    message: String,
+    kind: PromptKind,
+    action: PromptAction,
+}
+
+/// A `:s///c` waiting for an answer about the match at `range` (bytes) of `row`.
+struct SubstituteConfirm {
+    sub: Substitute,
+    regex: Regex,
+    row: usize,
+    range: Range<usize>,
+    /// Last row the command covers.
+    last_row: usize,
+    replaced: usize,
+    changed_rows: HashSet<usize>,
+}
+
+/// First match of `pattern` at or after byte `from` of `row`, looking on through
+/// `last_row`. Returns the row and the match's byte range in it.
+fn next_match(lines: &[String], regex: &Regex, row: usize, from: usize, last_row: usize) -> Option<(usize, Range<usize>)> {
+    (row..=last_row.min(lines.len().saturating_sub(1))).find_map(|r| {
+        let from = if r == row { from } else { 0 };
+        // Matched against the whole line, so `^` and `\b` see what comes before `from`.
+        let range = find_matches(&lines[r], regex).into_iter().find(|range| range.start >= from)?;
+        Some((r, range))
+    })
+}
+
+/// Applies `sub` to `line`, returning the new line and the number of replacements,
+/// or `None` if the pattern doesn't occur.
+fn substitute_line(line: &str, sub: &Substitute, regex: &Regex) -> Option<(String, usize)> {
+    let mut matches = regex.captures(line);
+    if !sub.global {
+        matches.truncate(1);
+    }
+    if matches.is_empty() {
+        return None;
+    }
+    let mut new_line = String::with_capacity(line.len());
+    let mut pos = 0;
+    for captures in &matches {
+        let Some(range) = &captures[0] else { continue };
+        new_line.push_str(&line[pos..range.start]);
+        new_line.push_str(&expand_replacement(&sub.replacement, line, captures));
+        pos = range.end;
+    }
+    new_line.push_str(&line[pos..]);
+    Some((new_line, matches.len()))
+}
+
+/// A character set in a pattern: `[a-z_]`, `\d`, `\w`, `\s`.
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Digit,
+    Word,
+    Space,
+}
+
+impl ClassItem {
+    fn contains(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(low, high) => (low..=high).contains(&c),
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Word => is_word_char(c),
+            ClassItem::Space => c.is_whitespace(),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// One element of a compiled pattern.
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    /// `.`: any character.
+    Any,
+    Class { items: Vec<ClassItem>, negated: bool },
+    /// `^` and `$`: the start and end of the line.
+    Start,
+    End,
+    /// `\b` (or `\B` when `false`): between a word character and something else.
+    WordBoundary(bool),
+    /// `\<` and `\>`: the start and end of a word.
+    WordStart,
+    WordEnd,
+    /// `(...)` or `(?:...)`: alternatives separated by `|`, and the capture group
+    /// number if it has one. The whole pattern is group 0.
+    Group(Vec<Vec<Node>>, Option<usize>),
+    /// `*`, `+`, `?`, `{n}`, `{n,}`, `{n,m}`; lazy when followed by `?`.
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+/// A pattern for `/`, `:s` and `:g`, matched within a single line. The syntax is
+/// the usual one (`.`, `*`, `+`, `?`, `{n,m}`, `[...]`, `(...)`, `|`, `^`, `$`,
+/// `\d`, `\w`, `\s`, `\b` and their uppercase negations, `\<`, `\>`); with
+/// `:set nomagic` every character stands for itself.
+#[derive(Clone, Debug)]
+struct Regex {
+    program: Vec<Inst>,
+    /// The number of `Inst::Split`s in `program`.
+    splits: usize,
+    groups: usize,
+    ignore_case: bool,
+}
+
+/// Byte ranges of the groups of one match; index 0 is the whole match.
+type Captures = Vec<Option<Range<usize>>>;
+
+impl Regex {
+    fn new(pattern: &str, magic: bool, ignore_case: bool) -> Result<Regex, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let (root, groups) = match magic {
+            true => {
+                let mut parser = RegexParser { chars: &chars, pos: 0, groups: 0, depth: 0 };
+                let alternatives = parser.alternatives()?;
+                if parser.pos < chars.len() {
+                    return Err("unmatched )".to_string());
+                }
+                (Node::Group(alternatives, Some(0)), parser.groups)
+            }
+            false => (Node::Group(vec![chars.into_iter().map(Node::Char).collect()], Some(0)), 0),
+        };
+        let mut compiler = RegexCompiler { program: Vec::new(), splits: 0 };
+        compiler.node(&root)?;
+        compiler.push(Inst::Match)?;
+        Ok(Regex { program: compiler.program, splits: compiler.splits, groups, ignore_case })
+    }
+
+    /// The leftmost, non-overlapping matches in `line`. An empty match right
+    /// where the previous one ended is skipped, as in Vim.
+    fn captures(&self, line: &str) -> Vec<Captures> {
+        let (offsets, chars): (Vec<usize>, Vec<char>) = line.char_indices().unzip();
+        let byte = |i: usize| offsets.get(i).copied().unwrap_or(line.len());
+        let visited = vec![0; (self.splits * (chars.len() + 1)).div_ceil(64)];
+        let mut matcher = RegexMatcher { program: &self.program, chars: &chars, ignore_case: self.ignore_case, visited };
+        let mut all = Vec::new();
+        let mut start = 0;
+        let mut last_end = None;
+        while start <= chars.len() {
+            let mut slots = vec![None; 2 * (self.groups + 1)];
+            let found = matcher.run(start, &mut slots);
+            // Failing from a split means failing from any start, but the splits
+            // on the way to a match have to be tried again for the next one.
+            if found {
+                matcher.visited.fill(0);
+            }
+            match slots[0].zip(slots[1]) {
+                Some((s, e)) if found && !(s == e && last_end == Some(s)) => {
+                    all.push((0..=self.groups).map(|g| slots[2 * g].zip(slots[2 * g + 1]).map(|(s, e)| byte(s)..byte(e))).collect());
+                    last_end = Some(e);
+                    start = if e > s { e } else { s + 1 };
+                }
+                _ => start += 1,
+            }
+        }
+        all
+    }
+}
+
+/// Deepest nesting of groups a pattern may have. Parsing and compiling recurse
+/// into each group, so `((((...` would otherwise overflow the stack.
+const REGEX_MAX_DEPTH: usize = 200;
+
+struct RegexParser<'a> {
+    chars: &'a [char],
+    pos: usize,
+    groups: usize,
+    /// Groups open around the current position.
+    depth: usize,
+}
+
+impl RegexParser<'_> {
+    /// Parses up to an unmatched `)` or the end of the pattern.
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![Vec::new()];
+        while let Some(&c) = self.chars.get(self.pos) {
+            self.pos += 1;
+            let sequence = alternatives.last_mut().expect("starts with one alternative");
+            let node = match c {
+                '|' => {
+                    alternatives.push(Vec::new());
+                    continue;
+                }
+                ')' => {
+                    self.pos -= 1;
+                    break;
+                }
+                '(' => {
+                    if self.depth >= REGEX_MAX_DEPTH {
+                        return Err("pattern too deeply nested".to_string());
+                    }
+                    let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                        self.pos += 2;
+                        None
+                    } else {
+                        self.groups += 1;
+                        Some(self.groups)
+                    };
+                    self.depth += 1;
+                    let inner = self.alternatives()?;
+                    self.depth -= 1;
+                    if self.chars.get(self.pos) != Some(&')') {
+                        return Err("unmatched (".to_string());
+                    }
+                    self.pos += 1;
+                    Node::Group(inner, index)
+                }
+                '.' => Node::Any,
+                '^' => Node::Start,
+                '$' => Node::End,
+                '[' => self.class()?,
+                '\\' => self.escape()?,
+                '*' | '+' | '?' | '{' => {
+                    let Some((min, max)) = self.quantifier(c) else {
+                        sequence.push(Node::Char(c));
+                        continue;
+                    };
+                    let node = match sequence.pop() {
+                        Some(node @ (Node::Char(_) | Node::Any | Node::Class { .. } | Node::Group(..))) => node,
+                        _ => return Err(format!("nothing to repeat before {}", c)),
+                    };
+                    let greedy = self.chars.get(self.pos) != Some(&'?');
+                    if !greedy {
+                        self.pos += 1;
+                    }
+                    Node::Repeat { node: Box::new(node), min, max, greedy }
+                }
+                c => Node::Char(c),
+            };
+            sequence.push(node);
+        }
+        Ok(alternatives)
+    }
+
+    /// The bounds of the quantifier `c` just read, or `None` for a `{` that
+    /// doesn't start one and so stands for itself.
+    fn quantifier(&mut self, c: char) -> Option<(usize, Option<usize>)> {
+        match c {
+            '*' => Some((0, None)),
+            '+' => Some((1, None)),
+            '?' => Some((0, Some(1))),
+            _ => {
+                let close = self.chars[self.pos..].iter().position(|&c| c == '}')?;
+                let body: String = self.chars[self.pos..self.pos + close].iter().collect();
+                let bounds = match body.split_once(',') {
+                    None => body.parse().ok().map(|n| (n, Some(n))),
+                    Some((min, "")) => min.parse().ok().map(|min| (min, None)),
+                    Some((min, max)) => min.parse().ok().zip(max.parse().ok()).map(|(min, max)| (min, Some(max))),
+                }?;
+                self.pos += close + 1;
+                Some(bounds)
+            }
+        }
+    }
+
+    /// A backslash sequence outside brackets.
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = *self.chars.get(self.pos).ok_or("trailing \\")?;
+        self.pos += 1;
+        let class = |item, negated| Node::Class { items: vec![item], negated };
+        Ok(match c {
+            'd' | 'D' => class(ClassItem::Digit, c == 'D'),
+            'w' | 'W' => class(ClassItem::Word, c == 'W'),
+            's' | 'S' => class(ClassItem::Space, c == 'S'),
+            'b' | 'B' => Node::WordBoundary(c == 'b'),
+            '<' => Node::WordStart,
+            '>' => Node::WordEnd,
+            't' => Node::Char('\t'),
+            c => Node::Char(c),
+        })
+    }
+
+    /// `[...]` after the `[`. A `]` right after `[` or `[^` is a literal.
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.chars.get(self.pos) == Some(&'^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        loop {
+            let c = *self.chars.get(self.pos).ok_or("unmatched [")?;
+            self.pos += 1;
+            let low = match c {
+                ']' if !items.is_empty() => break,
+                '\\' => {
+                    let escaped = *self.chars.get(self.pos).ok_or("unmatched [")?;
+                    self.pos += 1;
+                    match escaped {
+                        'd' => items.push(ClassItem::Digit),
+                        'w' => items.push(ClassItem::Word),
+                        's' => items.push(ClassItem::Space),
+                        't' => items.push(ClassItem::Range('\t', '\t')),
+                        c => items.push(ClassItem::Range(c, c)),
+                    }
+                    continue;
+                }
+                c => c,
+            };
+            match self.chars.get(self.pos..self.pos + 2) {
+                Some(&['-', high]) if high != ']' => {
+                    if high < low {
+                        return Err(format!("invalid range {}-{}", low, high));
+                    }
+                    self.pos += 2;
+                    items.push(ClassItem::Range(low, high));
+                }
+                _ => items.push(ClassItem::Range(low, low)),
+            }
+        }
+        Ok(Node::Class { items, negated })
+    }
+}
+
+/// Longest program a pattern may compile to. Counted repeats are written out
+/// in full, so `(x{100}){100}` would otherwise grow without bound.
+const REGEX_MAX_PROGRAM: usize = 10_000;
+
+/// One instruction of a compiled pattern.
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class { items: Vec<ClassItem>, negated: bool },
+    Start,
+    End,
+    WordBoundary(bool),
+    WordStart,
+    WordEnd,
+    /// Records the current position in a group bound: `2n` is where group `n`
+    /// starts, `2n + 1` where it ends.
+    Save(usize),
+    /// Goes on at `prefer`, and at `other` if that fails. `memo` numbers the
+    /// split for `RegexMatcher::visited`.
+    Split { prefer: usize, other: usize, memo: usize },
+    Jump(usize),
+    Match,
+}
+
+struct RegexCompiler {
+    program: Vec<Inst>,
+    splits: usize,
+}
+
+impl RegexCompiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() >= REGEX_MAX_PROGRAM {
+            return Err("pattern too large".to_string());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    /// A split whose targets are filled in by `patch` once they are known.
+    fn split(&mut self) -> Result<usize, String> {
+        self.splits += 1;
+        self.push(Inst::Split { prefer: 0, other: 0, memo: self.splits - 1 })
+    }
+
+    /// Points the split at `at` to `more` (the body of a repeat or the next
+    /// alternative) and `done`; a greedy split tries `more` first.
+    fn patch(&mut self, at: usize, more: usize, done: usize, greedy: bool) {
+        if let Inst::Split { prefer, other, .. } = &mut self.program[at] {
+            (*prefer, *other) = if greedy { (more, done) } else { (done, more) };
+        }
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), String> {
+        let inst = match node {
+            Node::Char(c) => Inst::Char(*c),
+            Node::Any => Inst::Any,
+            Node::Class { items, negated } => Inst::Class { items: items.clone(), negated: *negated },
+            Node::Start => Inst::Start,
+            Node::End => Inst::End,
+            Node::WordBoundary(at) => Inst::WordBoundary(*at),
+            Node::WordStart => Inst::WordStart,
+            Node::WordEnd => Inst::WordEnd,
+            Node::Group(alternatives, index) => {
+                if let Some(index) = index {
+                    self.push(Inst::Save(2 * index))?;
+                }
+                let mut jumps = Vec::new();
+                for (n, alternative) in alternatives.iter().enumerate() {
+                    let split = match n + 1 < alternatives.len() {
+                        true => Some(self.split()?),
+                        false => None,
+                    };
+                    for node in alternative {
+                        self.node(node)?;
+                    }
+                    if let Some(split) = split {
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        self.patch(split, split + 1, self.program.len(), true);
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+                match index {
+                    Some(index) => Inst::Save(2 * index + 1),
+                    None => return Ok(()),
+                }
+            }
+            Node::Repeat { node, min, max, greedy } => {
+                if max.is_some_and(|max| max < *min) {
+                    return Err(format!("invalid repeat {{{},{}}}", min, max.unwrap_or_default()));
+                }
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                let splits = match max {
+                    // A loop back to the split; an empty pass through the body
+                    // comes back to a split already visited and fails there.
+                    None => {
+                        let split = self.split()?;
+                        self.node(node)?;
+                        self.push(Inst::Jump(split))?;
+                        vec![split]
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.split()?);
+                            self.node(node)?;
+                        }
+                        splits
+                    }
+                };
+                let end = self.program.len();
+                for split in splits {
+                    self.patch(split, split + 1, end, *greedy);
+                }
+                return Ok(());
+            }
+        };
+        self.push(inst)?;
+        Ok(())
+    }
+}
+
+/// What is left to do when a way of matching fails.
+enum Backtrack {
+    /// Try the program from this instruction at this position.
+    Try(usize, usize),
+    /// Put back a group bound set on the way that failed.
+    Restore(usize, Option<usize>),
+}
+
+/// Backtracking matcher over the characters of one line, with its own stack
+/// instead of recursion. Whether the program matches from a split on depends
+/// only on the position, so every split is tried at most once per position:
+/// matching takes time linear in the line, however the pattern nests.
+struct RegexMatcher<'a> {
+    program: &'a [Inst],
+    chars: &'a [char],
+    ignore_case: bool,
+    /// One bit per split and position, set once the split has been tried there.
+    visited: Vec<u64>,
+}
+
+impl RegexMatcher<'_> {
+    fn char_matches(&self, pattern: char, c: char) -> bool {
+        pattern == c || (self.ignore_case && pattern.to_lowercase().eq(c.to_lowercase()))
+    }
+
+    fn class_matches(&self, items: &[ClassItem], c: char) -> bool {
+        let contains = |c: char| items.iter().any(|item| item.contains(c));
+        contains(c) || (self.ignore_case && (c.to_lowercase().any(contains) || c.to_uppercase().any(contains)))
+    }
+
+    /// Marks the split `memo` as tried at `i`, returning whether it already was.
+    fn visit(&mut self, memo: usize, i: usize) -> bool {
+        let bit = memo * (self.chars.len() + 1) + i;
+        let (word, mask) = (bit / 64, 1 << (bit % 64));
+        let seen = self.visited[word] & mask != 0;
+        self.visited[word] |= mask;
+        seen
+    }
+
+    /// Whether the program matches from character `start`, filling in `slots`
+    /// with the group bounds (see `Inst::Save`) if so.
+    fn run(&mut self, start: usize, slots: &mut [Option<usize>]) -> bool {
+        let mut stack = vec![Backtrack::Try(0, start)];
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut i) = match job {
+                Backtrack::Try(pc, i) => (pc, i),
+                Backtrack::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            loop {
+                let c = self.chars.get(i).copied();
+                let before = i > 0 && is_word_char(self.chars[i - 1]);
+                let after = c.is_some_and(is_word_char);
+                let ok = match &self.program[pc] {
+                    Inst::Match => return true,
+                    Inst::Jump(to) => {
+                        pc = *to;
+                        continue;
+                    }
+                    &Inst::Split { prefer, other, memo } => {
+                        if self.visit(memo, i) {
+                            break;
+                        }
+                        stack.push(Backtrack::Try(other, i));
+                        pc = prefer;
+                        continue;
+                    }
+                    &Inst::Save(slot) => {
+                        stack.push(Backtrack::Restore(slot, slots[slot].replace(i)));
+                        true
+                    }
+                    Inst::Char(p) => c.is_some_and(|c| self.char_matches(*p, c)),
+                    Inst::Any => c.is_some(),
+                    Inst::Class { items, negated } => c.is_some_and(|c| self.class_matches(items, c) != *negated),
+                    Inst::Start => i == 0,
+                    Inst::End => c.is_none(),
+                    Inst::WordBoundary(at) => (before != after) == *at,
+                    Inst::WordStart => !before && after,
+                    Inst::WordEnd => before && !after,
+                };
+                if !ok {
+                    break;
+                }
+                if matches!(self.program[pc], Inst::Char(_) | Inst::Any | Inst::Class { .. }) {
+                    i += 1;
+                }
+                pc += 1;
+            }
+        }
+        false
+    }
+}
+
+/// The replacement of `:s` for one match: `\1`-`\9` or `$1`-`$9` insert a group
+/// (`\0`, `$0` the whole match) and `\\` a backslash. A group the pattern doesn't
+/// have stays as typed.
+fn expand_replacement(replacement: &str, line: &str, captures: &Captures) -> String {
+    let mut text = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        let group = chars.peek().and_then(|d| d.to_digit(10)).map(|d| d as usize).filter(|&d| d < captures.len());
+        match (c, group) {
+            ('\\' | '$', Some(group)) => {
+                chars.next();
+                if let Some(range) = &captures[group] {
+                    text.push_str(&line[range.clone()]);
+                }
+            }
+            ('\\', None) if chars.peek() == Some(&'\\') => {
+                chars.next();
+                text.push('\\');
+            }
+            (c, _) => text.push(c),
+        }
+    }
+    text
+}
+
+/// Byte ranges of every match of `regex` in `line`. This is the one place that
+/// decides what a match is, so searching, substitution and highlighting agree.
+fn find_matches(line: &str, regex: &Regex) -> Vec<Range<usize>> {
+    regex.captures(line).into_iter().filter_map(|mut groups| groups.swap_remove(0)).collect()
+}
+
+/// The pattern last searched for with `/`, `?`, `*` or `#`, repeated by `n`/`N`.
+#[derive(Clone)]
+struct Search {
+    pattern: String,
+    /// Only match where the pattern is a whole word (`*`, `#`).
+    whole_word: bool,
+    /// `/` and `*` search forward, `?` and `#` backward.
+    forward: bool,
+}
+
+/// Byte ranges of the matches of `search` in `line`: those of `find_matches`,
+/// minus the ones inside a longer word when `whole_word` is set.
+fn search_matches(line: &str, search: &Search, regex: &Regex) -> Vec<Range<usize>> {
+    let mut matches = find_matches(line, regex);
+    if search.whole_word {
+        let is_word = |g: Option<&str>| g.is_some_and(|g| grapheme_class(g) == 1);
+        matches.retain(|range| {
+            !is_word(line[..range.start].graphemes(true).next_back()) && !is_word(line[range.end..].graphemes(true).next())
+        });
+    }
+    matches
+}
+
+/// Finds the next (or previous) range after (before) byte `cursor_byte` of `row`,
+/// wrapping around the end of the buffer. `ranges` lists the candidates in a line.
+/// Returns the row, the grapheme column of the range start and whether it wrapped.
+fn find_wrapping(
+    lines: &[String],
+    row: usize,
+    cursor_byte: usize,
+    forward: bool,
+    ranges: impl Fn(&str) -> Vec<Range<usize>>,
+) -> Option<(usize, usize, bool)> {
+    let line_count = lines.len();
+    // The cursor row is visited twice: once for the part past the cursor and
+    // once, after wrapping, for the part before it.
+    for step in 0..=line_count {
+        let r = if forward { (row + step) % line_count } else { (row + line_count * 2 - step) % line_count };
+        let line = &lines[r];
+        let ranges = ranges(line);
+        let hit = match (step, forward) {
+            (0, true) => ranges.into_iter().find(|range| range.start > cursor_byte),
+            (0, false) => ranges.into_iter().rev().find(|range| range.start < cursor_byte),
+            (_, true) => ranges.into_iter().next(),
+            (_, false) => ranges.into_iter().next_back(),
+        };
+        if let Some(range) = hit {
+            let wrapped = step > 0 && if forward { r <= row } else { r >= row };
+            return Some((r, line[..range.start].graphemes(true).count(), wrapped));
+        }
+    }
+    None
+}
+
+/// Splits a line into spans at every edge of the given byte ranges, which may
+/// overlap. Each span gets the styles of all ranges covering it patched
+/// together in order, so a later range wins where two set the same attribute.
+fn highlight_spans<'a>(line: &'a str, highlights: &[(Range<usize>, Style)]) -> Vec<Span<'a>> {
+    let mut edges: Vec<usize> = vec![0, line.len()];
+    for (range, _) in highlights {
+        edges.extend([range.start.min(line.len()), range.end.min(line.len())]);
+    }
+    edges.sort_unstable();
+    edges.dedup();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut span_start = 0;
+    for pair in edges.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let style = highlights
+            .iter()
+            .filter(|(range, _)| range.start <= start && end <= range.end)
+            .fold(Style::default(), |style, (_, layer)| style.patch(*layer));
+        // Neighbours that end up styled alike stay one span.
+        match spans.last_mut() {
+            Some(last) if last.style == style => last.content = line[span_start..end].into(),
+            _ => {
+                span_start = start;
+                spans.push(Span::styled(&line[start..end], style));
+            }
+        }
+    }
+    if spans.is_empty() {
+        spans.push(Span::raw(line));
+    }
+    spans
+}
+
+/// A visual-block selection: rows `top..=bottom` and display columns `left..=right`.
+#[derive(Clone, Copy)]
+struct VisualBlock {
+    top: usize,
+    bottom: usize,
+    left: usize,
+    right: usize,
+}
+
+/// A pending visual-block insert, replayed on rows below `top` when Insert mode ends.
+struct BlockInsert {
+    top: usize,
+    bottom: usize,
+    /// Display column the text is inserted at.
+    display_col: usize,
+    /// Pad rows shorter than `display_col` with spaces (`A`) instead of skipping them (`I`).
+    pad: bool,
+    /// Grapheme column on the top row where typing started.
+    start_col: usize,
+}
+
+/// Display column at which grapheme `col` of `line` starts.
+fn display_col_of(line: &str, col: usize, cfg: &WidthConfig) -> usize {
+    let (count, width) = line.graphemes(true).take(col).fold((0, 0), |(n, w), g| (n + 1, w + display_width(g, cfg)));
+    // Past the end of the line (`virtualedit`) every column is one cell.
+    width + col - count
+}
+
+/// Screen cells, counted from the start of `line`, taken by grapheme `col`: two
+/// for a wide character, one past the end of the line so the cursor has a cell.
+fn cursor_cells(line: &str, col: usize, cfg: &WidthConfig) -> Range<usize> {
+    let start = display_col_of(line, col, cfg);
+    let width = line.graphemes(true).nth(col).map_or(1, |g| display_width(g, cfg).max(1));
+    start..start + width
+}
+
+/// First display column at or after `cells` where a grapheme of `line` starts,
+/// so a horizontal scroll doesn't cut a wide character in half.
+fn grapheme_boundary_after(line: &str, cells: usize, cfg: &WidthConfig) -> usize {
+    let mut width = 0;
+    for g in line.graphemes(true) {
+        if width >= cells {
+            return width;
+        }
+        width += display_width(g, cfg);
+    }
+    width.max(cells)
+}
+
+/// Drops the first `offset` display columns of `spans` for horizontal scrolling.
+/// A wide character cut by the left edge leaves blanks, so everything after it
+/// stays in the columns the cursor math expects.
+fn scroll_spans<'a>(spans: Vec<Span<'a>>, mut offset: usize, cfg: &WidthConfig) -> Vec<Span<'a>> {
+    let mut scrolled = Vec::new();
+    for span in spans {
+        if offset == 0 {
+            scrolled.push(span);
+            continue;
+        }
+        let mut start = span.content.len();
+        for (i, g) in span.content.grapheme_indices(true) {
+            if offset == 0 {
+                start = i;
+                break;
+            }
+            let width = display_width(g, cfg);
+            if width > offset {
+                scrolled.push(Span::styled(" ".repeat(width - offset), span.style));
+                offset = 0;
+                start = i + g.len();
+                break;
+            }
+            offset -= width;
+        }
+        if start < span.content.len() {
+            scrolled.push(Span::styled(span.content[start..].to_string(), span.style));
+        }
+    }
+    scrolled
+}
+
+/// The `:set ruler` text: `line,col`, then `-screen col` when tabs or wide
+/// characters before the cursor make it differ, then the cursor line as a
+/// percentage of the file (`Top` and `Bot` at the ends, `All` for one line).
+fn ruler(buffer: &Buffer, cfg: &WidthConfig) -> String {
+    let screen_col = display_col_of(&buffer.lines[buffer.row], buffer.col, cfg);
+    let col = match screen_col == buffer.col {
+        true => format!("{}", buffer.col + 1),
+        false => format!("{}-{}", buffer.col + 1, screen_col + 1),
+    };
+    let last = buffer.lines.len() - 1;
+    let position = match buffer.row {
+        _ if last == 0 => "All".to_string(),
+        0 => "Top".to_string(),
+        row if row == last => "Bot".to_string(),
+        row => format!("{}%", row * 100 / last),
+    };
+    format!("{},{}  {:>3}", buffer.row + 1, col, position)
+}
+
+/// Grapheme indices of `line` that overlap display columns `left..=right`. Empty
+/// (at the line end) when the line is too short to reach the block.
+fn block_grapheme_range(line: &str, left: usize, right: usize, cfg: &WidthConfig) -> Range<usize> {
+    let mut x = 0;
+    let mut start = None;
+    let mut end = 0;
+    for (i, g) in line.graphemes(true).enumerate() {
+        let width = display_width(g, cfg).max(1);
+        if x > right {
+            break;
+        }
+        if x + width > left {
+            start.get_or_insert(i);
+            end = i + 1;
+        }
+        x += width;
+    }
+    match start {
+        Some(start) => start..end,
+        None => {
+            let len = line.graphemes(true).count();
+            len..len
+        }
+    }
+}
+
+/// Converts a range of grapheme indices in `line` to the matching byte range.
+fn grapheme_byte_range(line: &str, range: Range<usize>) -> Range<usize> {
+    let byte_at = |col: usize| line.grapheme_indices(true).nth(col).map_or(line.len(), |(i, _)| i);
+    byte_at(range.start)..byte_at(range.end)
+}
+
+/// Grapheme index at which text inserted at display column `display_col` goes.
+/// A line that ends before that column is padded with spaces if `pad` is set,
+/// otherwise it is skipped (`None`).
+fn insert_col_for_display(line: &mut String, display_col: usize, pad: bool, cfg: &WidthConfig) -> Option<usize> {
+    let width = display_width(line, cfg);
+    if width < display_col {
+        if !pad {
+            return None;
+        }
+        line.push_str(&" ".repeat(display_col - width));
+    }
+    Some(block_grapheme_range(line, display_col, display_col, cfg).start)
+}
+
+/// The first integer in `line`, with its sign, for `:sort n`.
+fn first_number(line: &str) -> Option<i64> {
+    let start = line.find(|c: char| c.is_ascii_digit())?;
+    let digits = line[start..].find(|c: char| !c.is_ascii_digit()).map_or(&line[start..], |end| &line[start..start + end]);
+    let number = digits.parse::<i64>().unwrap_or(i64::MAX);
+    Some(if line[..start].ends_with('-') { -number } else { number })
+}
+
+/// Where the output of a finished shell command goes.
+enum ShellTarget {
+    /// `:r !cmd` inserts stdout below `row` of buffer `buffer`. `removed` is how
+    /// long `Editor::removed_buffers` was when it started.
+    InsertBelow { buffer: usize, row: usize, removed: usize },
+    /// `:!cmd` opens a new unnamed buffer holding stdout and stderr.
+    Scratch,
+}
+
+/// A shell command that finished on a worker thread.
+struct ShellResult {
+    command: String,
+    target: ShellTarget,
+    output: io::Result<Output>,
+}
+
+/// Builds a command that runs `command` through the user's shell.
+fn shell_command(command: &str) -> Command {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    let mut cmd = Command::new(shell);
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Splits command output into buffer lines.
+fn output_lines(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes).lines().map(|s| s.to_string()).collect()
+}
+
+/// How register text goes back in when it is put.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum RegisterKind {
+    /// Text within or across lines (`x`, `dw`), put inside the cursor line.
+    Charwise,
+    /// Whole lines (`dd`, `Vy`), put as new lines below or above the cursor line.
+    Linewise,
+    /// A rectangle (`Ctrl-v` `d`), one piece per line, put in a column from the cursor down.
+    Blockwise,
+}
+
+/// Text held by a register.
+#[derive(Clone)]
+struct Register {
+    lines: Vec<String>,
+    kind: RegisterKind,
+}
+
+/// Text kept from deletes and inserts, listed by `:registers`.
+#[derive(Default)]
+struct Registers {
+    /// `""`: the most recent delete or yank.
+    unnamed: Option<Register>,
+    /// `"0` holds the last yank; `"1`-`"9` the last nine deletes of whole or
+    /// several lines, newest first.
+    numbered: [Option<Register>; 10],
+    /// `"-`: the last delete within a single line.
+    small_delete: Option<Register>,
+    /// `".`: the text typed in the last Insert mode.
+    last_insert: Option<Register>,
+}
+
+impl Registers {
+    /// Records deleted text: line-wise and multi-line deletes shift `"1`-`"8`
+    /// down to `"2`-`"9` and go into `"1`, smaller ones go into `"-`.
+    fn delete(&mut self, register: Register) {
+        if register.kind != RegisterKind::Charwise || register.lines.len() > 1 {
+            self.numbered[1..].rotate_right(1);
+            self.numbered[1] = Some(register.clone());
+        } else {
+            self.small_delete = Some(register.clone());
+        }
+        self.unnamed = Some(register);
+    }
+
+    /// Records yanked text in `"0` and `""`.
+    fn yank(&mut self, register: Register) {
+        self.numbered[0] = Some(register.clone());
+        self.unnamed = Some(register);
+    }
+
+    /// The register called `name`, if it holds anything. `"%` is not stored
+    /// here since it always reflects the current buffer.
+    fn get(&self, name: char) -> Option<&Register> {
+        match name {
+            '"' => self.unnamed.as_ref(),
+            '0'..='9' => self.numbered[name as usize - '0' as usize].as_ref(),
+            '-' => self.small_delete.as_ref(),
+            '.' => self.last_insert.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// One `:registers` row: the name and the contents on a single line, with line
+/// breaks shown as `^J` and long text cut off at `max_width` characters.
+fn register_summary(name: char, register: &Register, max_width: usize) -> String {
+    let mut text = register.lines.join("^J");
+    if register.kind == RegisterKind::Linewise {
+        text.push_str("^J");
+    }
+    let text = text.replace('\t', "^I");
+    let shown: String = text.chars().take(max_width).collect();
+    let ellipsis = if shown.len() < text.len() { "…" } else { "" };
+    format!("\"{}  {}{}", name, shown, ellipsis)
+}
+
+/// Screen areas of the main view.
+struct EditorLayout {
+    tree: Option<Rect>,
+    separator: Option<Rect>,
+    text: Rect,
+    /// Column right of the text showing the vertical position, with `:set scrollbar`.
+    vscrollbar: Option<Rect>,
+    /// Row below the text showing the horizontal position, with `:set hscrollbar`.
+    hscrollbar: Option<Rect>,
+    /// Status bar and command line.
+    status: Rect,
+}
+
+/// Frame of the directory tree, titled with the tree root; its inner area holds the items.
+fn tree_block(title: String) -> Block<'static> {
+    Block::default()
+        .title(title)
+        .padding(Padding::horizontal(1))
+}
+
+/// The tree root as its title shows it: the home directory as `~`, and cut
+/// from the left with `…` to fit `width` cells, so the root's own name stays.
+fn tree_breadcrumb(root: &Path, width: usize) -> String {
+    let home = std::env::var_os("HOME").map(PathBuf::from).filter(|home| home.parent().is_some());
+    let text = match home.as_deref().and_then(|home| root.strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => root.display().to_string(),
+    };
+    if text.width() <= width {
+        return text;
+    }
+    let mut kept = Vec::new();
+    let mut used = 1;
+    for grapheme in text.graphemes(true).rev() {
+        used += grapheme.width();
+        if used > width {
+            break;
+        }
+        kept.push(grapheme);
+    }
+    kept.reverse();
+    format!("…{}", kept.concat())
+}
+
+struct TreeItem {
+    path: PathBuf,
+    prefix: String,
+    is_dir: bool,
+    /// A symbolic link, listed as what it points to.
+    symlink: bool,
+    /// A symbolic link whose target doesn't exist.
+    broken: bool,
+    /// A directory link to one of the directories it is listed in, which would
+    /// repeat them forever if expanded.
+    cycle: Option<PathBuf>,
+}
+
+/// Commands typed with Shift still held, and `:x`, with the command each stands
+/// for. An `:alias` of the same name takes precedence.
+const BUILTIN_ALIASES: &[(&str, &str)] = &[
+    ("W", "w"),
+    ("Q", "q"),
+    ("Q!", "q!"),
+    ("Wq", "wq"),
+    ("WQ", "wq"),
+    ("x", "wq"),
+    ("X", "wq"),
+];
+
+/// Maximum number of entries kept in the jump list, as in Vim.
+const JUMPLIST_MAX: usize = 100;
+/// Shortest time between two redraws (about 60 frames a second). Keys that
+/// arrive faster, like a held key's autorepeat, are drawn together.
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+struct Editor {
+    buffers: Vec<Buffer>,
+    active_buffer_index: usize,
+    mode: Mode,
+    command_input: String,
+    command_message: Message,
+    scroll_offset_col: usize,
+    should_exit: bool,
+    pending_command_prefix: Option<char>,
+    pending_operator: Option<Operator>,
+    /// Set after an operator and `i` (true) or `a` (false) while the text object's key is awaited.
+    pending_text_object: Option<bool>,
+    pending_count: Option<usize>,
+    /// Set by `Ctrl-v` in Insert mode: the digits of the character code typed so
+    /// far, empty while the next key is awaited.
+    literal_input: Option<String>,
+    /// When the keys of `pending_keys` were last added to, for `timeoutlen`.
+    pending_since: Option<Instant>,
+    /// Something on screen may have changed since the last frame; `run` only
+    /// draws when this is set.
+    needs_redraw: bool,
+    settings: Settings,
+    /// Positions `(buffer index, row, col)` recorded before jumps, for `Ctrl-o`/`Ctrl-i`.
+    jumplist: Vec<(usize, usize, usize)>,
+    jumplist_index: usize,
+    ui_style: UiStyle,
+    /// Fixed corner `(row, col)` of the visual selection; the cursor is the other corner.
+    visual_anchor: (usize, usize),
+    /// Text typed after `I`/`A`/`c` in visual-block mode is repeated on the other rows on Esc.
+    block_insert: Option<BlockInsert>,
+    /// Pattern of the `:s` command being typed, highlighted on screen as a preview.
+    substitute_preview: Option<String>,
+    /// Graphemes overwritten in Replace mode, restored by Backspace (`None` for appended ones).
+    replaced_graphemes: Vec<Option<String>>,
+    /// First and last row of the last Visual Block or Visual Line selection, the
+    /// `'<` and `'>` marks of command line ranges.
+    visual_marks: Option<(usize, usize)>,
+    /// Word list for `:set spell`, loaded when spell checking is turned on.
+    spell_words: Option<HashSet<String>>,
+    /// Suggestions shown by `z=`; the next key picks one or closes the list.
+    spell_popup: Option<SpellPopup>,
+    /// Keyword completion cycled with `Ctrl-n`/`Ctrl-p`; ends on any other key.
+    completion: Option<Completion>,
+    /// Contents of `tags` in the tree root, read on the first `Ctrl-]`.
+    tags: Option<TagsCache>,
+    /// Positions `(buffer index, row, col)` `Ctrl-]` jumped from, popped by `Ctrl-t`.
+    tag_stack: Vec<(usize, usize, usize)>,
+    /// Definitions to choose from when a tag has several; the next key picks one.
+    tag_choices: Option<Vec<TagEntry>>,
+    /// Last search, repeated by `n` and `N`.
+    search: Option<Search>,
+    /// Whether matches of `search` are highlighted; `:nohlsearch` turns it off
+    /// until the next search.
+    search_highlight: bool,
+    /// Character the command line starts with: `:` for commands, `/` or `?` for a search.
+    command_prompt: char,
+    /// An operator and count waiting for the search being typed, as in `d/foo<CR>`.
+    search_operator: Option<(Operator, usize)>,
+    /// A question on the command line; keys go to it until it is answered.
+    prompt: Option<Prompt>,
+    /// Further cursors `(row, col)` in the active buffer; Insert mode edits at each of them.
+    extra_cursors: Vec<(usize, usize)>,
+    /// Deleted and inserted text, by register name.
+    registers: Registers,
+    /// Command aliases defined with `:alias`, by name.
+    aliases: BTreeMap<String, String>,
+    /// Text typed since entering Insert mode, stored in `".` on Esc.
+    inserted_text: String,
+    /// Rows listed by `:registers`, closed by the next key.
+    registers_popup: Option<Vec<String>>,
+    /// Side-by-side comparison shown instead of the buffer while set.
+    diff: Option<DiffView>,
+    /// Last cursor position per absolute file path, loaded from disk on first use.
+    view_states: Option<HashMap<PathBuf, ViewState>>,
+    /// Shell commands run on worker threads and send their results here.
+    shell_sender: Sender<ShellResult>,
+    shell_receiver: Receiver<ShellResult>,
+    /// Threads loading big files send their lines here.
+    load_sender: Sender<FileChunk>,
+    load_receiver: Receiver<FileChunk>,
+    /// Number of the last file load started, see `Loading::id`.
+    last_load: usize,
+    /// Keys pressed while the active buffer was loading, run once it has loaded.
+    typeahead: Vec<(KeyCode, KeyModifiers)>,
+    /// Files of buffers closed with `:bd`, most recent last, for `:bun` to reopen.
+    closed_buffers: Vec<PathBuf>,
+    /// Indices of every buffer removed so far, in order, so a shell command can
+    /// find its buffer again when it finishes.
+    removed_buffers: Vec<usize>,
+
+    // Directory Tree Properties
+    tree_visible: bool,
+    tree_view_active: bool,
+    tree_width: u16,
+    current_path: PathBuf,
+    tree_scroll_pos: usize,
+    selected_item_index: usize,
+    expanded_dirs: HashSet<PathBuf>,
+    tree_items: Vec<TreeItem>,
+    /// Set when `tree_items` no longer matches the filesystem or `expanded_dirs`.
+    tree_dirty: bool,
+}
+
+/// Whether `key_code` moves the cursor in the Visual modes, which pass it on
+/// to Normal mode.
+fn is_visual_motion(key_code: KeyCode) -> bool {
+    matches!(
+        key_code,
+        KeyCode::Char('h' | 'j' | 'k' | 'l' | 'w' | 'b' | 'e' | '^' | '$' | 'G' | 'g' | '{' | '}' | '0'..='9')
+            | KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down
+    )
+}
+
+impl Editor {
+    fn new() -> Editor {
+        let mut editor = Editor::with_buffer(vec![String::new()]);
+        editor.tree_visible = true;
+        editor.tree_view_active = true;
+        editor.current_path = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        editor.expanded_dirs.insert(editor.current_path.clone());
+        editor.restore_tree_state();
+        editor
+    }
+
+    /// An editor on a single unnamed buffer holding `lines`, with the tree hidden.
+    /// Unlike `new` it reads nothing from disk, so what it draws depends only on
+    /// `lines` and the settings.
+    fn with_buffer(lines: Vec<String>) -> Editor {
+        let (shell_sender, shell_receiver) = mpsc::channel();
+        let (load_sender, load_receiver) = mpsc::channel();
+        let mut buffer = Buffer::new(None);
+        if !lines.is_empty() {
+            buffer.lines = lines;
+        }
+        Editor {
+            buffers: vec![buffer],
+            active_buffer_index: 0,
+            mode: Mode::Normal,
+            command_input: String::new(),
+            command_message: Message::default(),
+            scroll_offset_col: 0,
+            should_exit: false,
+            pending_command_prefix: None,
+            pending_operator: None,
+            pending_text_object: None,
+            pending_count: None,
+            literal_input: None,
+            pending_since: None,
+            needs_redraw: true,
+            settings: Settings::default(),
+            jumplist: Vec::new(),
+            jumplist_index: 0,
+            ui_style: UiStyle::default(),
+            visual_anchor: (0, 0),
+            block_insert: None,
+            substitute_preview: None,
+            replaced_graphemes: Vec::new(),
+            visual_marks: None,
+            spell_words: None,
+            spell_popup: None,
+            completion: None,
+            tags: None,
+            tag_stack: Vec::new(),
+            tag_choices: None,
+            search: None,
+            search_highlight: false,
+            command_prompt: ':',
+            search_operator: None,
+            prompt: None,
+            extra_cursors: Vec::new(),
+            registers: Registers::default(),
+            aliases: BTreeMap::new(),
+            inserted_text: String::new(),
+            registers_popup: None,
+            diff: None,
+            view_states: None,
+            shell_sender,
+            shell_receiver,
+            load_sender,
+            load_receiver,
+            last_load: 0,
+            typeahead: Vec::new(),
+            closed_buffers: Vec::new(),
+            removed_buffers: Vec::new(),
+
+            // Directory Tree Properties
+            tree_visible: false,
+            tree_view_active: false,
+            tree_width: 30,
+            current_path: PathBuf::from("."),
+            tree_scroll_pos: 0,
+            selected_item_index: 0,
+            expanded_dirs: HashSet::new(),
+            tree_items: Vec::new(),
+            tree_dirty: true,
+        }
+    }
+
+    /// Draws one frame into an off-screen buffer of `size`, the way `run` draws
+    /// to the terminal, so the result can be inspected cell by cell.
+    #[cfg(test)]
+    fn render_to(&mut self, size: Rect) -> ratatui::buffer::Buffer {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(size.width, size.height)).expect("drawing to memory cannot fail");
+        if self.tree_visible && self.tree_dirty {
+            self.update_tree_items();
+        }
+        let change_marks = self.settings.change_marks;
+        if let Some(buffer) = self.active_buffer() {
+            buffer.update_change_marks(change_marks);
+        }
+        self.clamp_cursor_position();
+        self.update_scroll_offsets(size);
+        terminal.draw(|f| self.ui(f)).expect("drawing to memory cannot fail");
+        terminal.backend().buffer().clone()
+    }
+
+    /// The buffer being edited. `None` if every buffer is gone or the index is out
+    /// of range; every caller has to cope with that.
+    fn active_buffer(&mut self) -> Option<&mut Buffer> {
+        self.buffers.get_mut(self.active_buffer_index)
+    }
+
+    /// Fails if the active buffer is a binary file, which is read-only. Every
+    /// path that changes the text of the active buffer checks this first.
+    fn check_editable(&self) -> Result<(), EditorError> {
+        match self.buffers.get(self.active_buffer_index) {
+            Some(Buffer { binary: true, filename: Some(path), .. }) => Err(EditorError::Binary(path.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// The active buffer, for changing its text; `None` after reporting the
+    /// error if it is read-only.
+    fn editable_buffer(&mut self) -> Option<&mut Buffer> {
+        if let Err(e) = self.check_editable() {
+            self.report_error(e);
+            return None;
+        }
+        self.active_buffer()
+    }
+
+    /// Shows `error` on the command line, styled as an error until another
+    /// message replaces it.
+    fn report_error(&mut self, error: EditorError) {
+        self.command_message = Message::Error(error);
+    }
+
+    /// The main application loop.
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> io::Result<()> {
+        let mut last_draw: Option<Instant> = None;
+        let mut cursor_mode: Option<Mode> = None;
+        loop {
+            if self.should_exit {
+                for i in 0..self.buffers.len() {
+                    self.remember_view_state(i);
+                }
+                self.persist_view_states();
+                self.save_tree_state();
+                return Ok(());
+            }
+
+            while let Ok(result) = self.shell_receiver.try_recv() {
+                self.finish_shell_command(result);
+                self.needs_redraw = true;
+            }
+            // One batch per frame, so a big file fills in as it arrives.
+            if let Ok(chunk) = self.load_receiver.try_recv() {
+                self.receive_file_chunk(chunk);
+                self.needs_redraw = true;
+            }
+            let timeout = self.pending_timeout();
+            if timeout == Some(Duration::ZERO) {
+                self.cancel_pending();
+                self.needs_redraw = true;
+            }
+
+            // Update data models before drawing; keys handled before the next frame rely on them too.
+            if self.needs_redraw {
+                // Rebuilding re-reads every expanded directory, so only do it when something changed.
+                if self.tree_visible && self.tree_dirty {
+                    self.update_tree_items();
+                }
+                // The marks can widen the gutter, which the scroll offsets depend on.
+                let change_marks = self.settings.change_marks;
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.update_change_marks(change_marks);
+                }
+                self.clamp_cursor_position();
+                self.update_scroll_offsets(terminal.size()?);
+            }
+
+            // Draw only when something changed, and at most once per frame interval.
+            let frame_wait = last_draw.map_or(Duration::ZERO, |t| MIN_FRAME_INTERVAL.saturating_sub(t.elapsed()));
+            if self.needs_redraw && frame_wait.is_zero() {
+                // Draw UI
+                terminal.draw(|f| self.ui(f))?;
+                self.needs_redraw = false;
+                last_draw = Some(Instant::now());
+
+                // Set cursor style based on the current mode, only when it changes
+                // so the terminal doesn't restart the blink on every frame.
+                if cursor_mode.as_ref() != Some(&self.mode) {
+                    match self.mode {
+                        Mode::Insert => {
+                            execute!(terminal.backend_mut(), SetCursorStyle::BlinkingBar)?;
+                        }
+                        Mode::Replace => {
+                            execute!(terminal.backend_mut(), SetCursorStyle::BlinkingUnderScore)?;
+                        }
+                        _ => { // Normal, Command
+                            execute!(terminal.backend_mut(), SetCursorStyle::BlinkingBlock)?;
+                        }
+                    }
+                    cursor_mode = Some(self.mode.clone());
+                }
+            }
+
+            // Handle input events
+            // Wake up in time to drop a pending command that has waited too long,
+            // or to draw a frame that was held back.
+            let mut poll = timeout.filter(|t| !t.is_zero()).map_or(Duration::from_millis(100), |t| t.min(Duration::from_millis(100)));
+            if self.needs_redraw {
+                poll = poll.min(frame_wait);
+            }
+            if event::poll(poll)? {
+                self.needs_redraw = true;
+                match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => self.feed_key(key.code, key.modifiers),
+                    Event::Resize(width, height) => {
+                        // Re-fit the viewport to the new size right away; the next
+                        // iteration then redraws without waiting for a keypress.
+                        terminal.autoresize()?;
+                        self.clamp_cursor_position();
+                        self.update_scroll_offsets(Rect::new(0, 0, width, height));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Handles one key press the way the terminal loop does: an open prompt gets
+    /// it first, then the tree if it has focus, then the current mode.
+    fn feed_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        // Keys for a buffer that is still loading wait until all of it is there;
+        // `Ctrl-c` drops them.
+        let loading = self.buffers.get(self.active_buffer_index).is_some_and(|b| b.loading.is_some());
+        if loading && self.prompt.is_none() && !(self.tree_view_active && self.tree_visible) {
+            match (code, modifiers.contains(KeyModifiers::CONTROL)) {
+                (KeyCode::Char('c'), true) => {
+                    self.typeahead.clear();
+                    self.command_message = Message::Info("Dropped the keys typed while loading".to_string());
+                }
+                _ => self.typeahead.push((code, modifiers)),
+            }
+            return;
+        }
+        self.clamp_cursor_position();
+        if let Some(prompt) = self.prompt.take() {
+            self.handle_prompt_key(prompt, code);
+        } else if self.tree_view_active && self.tree_visible {
+            self.handle_tree_view_key(code);
+        } else {
+            let new_mode = match self.mode {
+                Mode::Normal => self.handle_normal_mode_key(code, modifiers),
+                Mode::Insert => self.handle_insert_mode_key(code, modifiers),
+                Mode::Replace => self.handle_replace_mode_key(code),
+                Mode::VisualBlock => self.handle_visual_block_key(code, modifiers),
+                Mode::VisualLine => self.handle_visual_line_key(code, modifiers),
+                Mode::Command => self.handle_command_mode_key(code),
+            };
+            self.mode = new_mode;
+            self.pending_since = match self.pending_keys().is_empty() {
+                true => None,
+                false => Some(Instant::now()),
+            };
+        }
+    }
+
+    /// Time left before the pending keys time out, zero once they have; `None`
+    /// when nothing is pending or `timeoutlen` is 0.
+    fn pending_timeout(&self) -> Option<Duration> {
+        let since = self.pending_since.filter(|_| self.settings.timeoutlen > 0)?;
+        Some(Duration::from_millis(self.settings.timeoutlen as u64).saturating_sub(since.elapsed()))
+    }
+
+    /// Drops a half-typed command: its count, operator, text object and prefix.
+    fn cancel_pending(&mut self) {
+        self.pending_count = None;
+        self.pending_operator = None;
+        self.pending_text_object = None;
+        self.pending_command_prefix = None;
+        self.pending_since = None;
+    }
+
+    /// Runs `command` as if typed after `:` and confirmed with Enter.
+    #[cfg(test)]
+    fn feed_command(&mut self, command: &str) {
+        self.mode = Mode::Command;
+        self.command_input = command.to_string();
+        self.feed_key(KeyCode::Enter, KeyModifiers::NONE);
+    }
+
+    /// Lines of the active buffer.
+    #[cfg(test)]
+    fn text(&self) -> &[String] {
+        self.buffers.get(self.active_buffer_index).map_or(&[], |b| &b.lines)
+    }
+
+    /// Row and grapheme column of the cursor in the active buffer.
+    #[cfg(test)]
+    fn cursor(&self) -> (usize, usize) {
+        self.buffers.get(self.active_buffer_index).map_or((0, 0), |b| (b.row, b.col))
+    }
+
+    /// Ensures the cursor is within valid bounds of the buffer.
+    fn clamp_cursor_position(&mut self) {
+        let virtual_edit = self.settings.virtual_edit;
+        if let Some(buffer) = self.active_buffer() {
+            buffer.row = buffer.row.min(buffer.lines.len().saturating_sub(1));
+            if virtual_edit {
+                return;
+            }
+            // FIX: Clamp column based on grapheme count, not byte length.
+            let grapheme_count = buffer.lines[buffer.row].graphemes(true).count();
+            buffer.col = buffer.col.min(grapheme_count);
+        }
+    }
+
+    /// Updates vertical and horizontal scroll offsets based on cursor position.
+    fn update_scroll_offsets(&mut self, term_size: Rect) {
+        let layout = self.layout(term_size);
+        let text_area = layout.text;
+        if let Some(diff) = &mut self.diff {
+            let height = (text_area.height as usize).max(1);
+            diff.cursor = diff.cursor.min(diff.rows.len().saturating_sub(1));
+            diff.top = diff.top.min(diff.cursor).max((diff.cursor + 1).saturating_sub(height));
+        }
+
+        // First, calculate the new horizontal scroll offset using an immutable borrow
+        let new_scroll_offset_col = if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+            let line_num_width = buffer.gutter_width(&self.settings);
+            let content_width = text_area.width.saturating_sub(line_num_width as u16);
+            
+            // Both cells of a wide character under the cursor must be on screen, and the
+            // left edge must not cut one in half.
+            let line = &buffer.lines[buffer.row];
+            let cursor = cursor_cells(line, buffer.col, &self.settings.width);
+
+            let mut new_offset = self.scroll_offset_col;
+            if cursor.end > new_offset + content_width as usize {
+                new_offset = grapheme_boundary_after(line, cursor.end - content_width as usize, &self.settings.width);
+            }
+            Some(new_offset.min(cursor.start))
+        } else {
+            None
+        };
+
+        // Now, get a mutable borrow to update the vertical scroll
+        let scrolloff = self.settings.scrolloff;
+        if let Some(buffer) = self.active_buffer() {
+            // A window shrunk to nothing still keeps the cursor line as the top row.
+            let editor_height = text_area.height.max(1) as usize;
+            // A margin of more than half the window would make both edges fight.
+            let margin = scrolloff.min((editor_height - 1) / 2);
+            // Counted in screen rows, where a closed fold is one row.
+            let highest_top = buffer.step_rows(buffer.row, margin, true);
+            buffer.top_row = buffer.screen_row_start(buffer.top_row).min(highest_top);
+            // Last row that must be visible below the cursor, without scrolling past the end.
+            let bottom = buffer.step_rows(buffer.row, margin, false);
+            let lowest_top = buffer.step_rows(bottom, editor_height - 1, true);
+            buffer.top_row = buffer.top_row.max(lowest_top);
+        }
+
+        // Keep the tree selection on screen.
+        if let Some(tree) = layout.tree {
+            // Only the title row matters for the height, not its text.
+            let height = (tree_block(String::new()).inner(tree).height as usize).max(1);
+            self.tree_scroll_pos = self.tree_scroll_pos.min(self.selected_item_index).max((self.selected_item_index + 1).saturating_sub(height));
+        }
+
+        // Finally, apply the new horizontal offset
+        if let Some(new_offset) = new_scroll_offset_col {
+            self.scroll_offset_col = new_offset;
+        }
+    }
+
+    /// Handles key presses in normal mode.
+    fn handle_normal_mode_key(&mut self, key_code: KeyCode, modifiers: KeyModifiers) -> Mode {
+        self.registers_popup = None;
+        if let Some(popup) = self.spell_popup.take() {
+            if let KeyCode::Char(c @ '1'..='9') = key_code {
+                let index = c.to_digit(10).unwrap_or(1) as usize - 1;
+                if let Some(word) = popup.suggestions.get(index) {
+                    self.replace_word(popup.row, popup.range, word);
+                }
+            }
+            return Mode::Normal;
+        }
+        if self.diff.is_some() {
+            return self.handle_diff_key(key_code);
+        }
+        if key_code == KeyCode::Esc && !self.pending_keys().is_empty() {
+            self.cancel_pending();
+            return Mode::Normal;
+        }
+        if let Some(choices) = self.tag_choices.take() {
+            if let KeyCode::Char(c @ '1'..='9') = key_code {
+                let index = c.to_digit(10).unwrap_or(1) as usize - 1;
+                if let Some(entry) = choices.get(index) {
+                    self.go_to_tag(entry);
+                }
+            }
+            return Mode::Normal;
+        }
+        // A leading digit starts a count; `0` only continues one. Ctrl-5 is how
+        // terminals send Ctrl-], so it is not a digit.
+        if let (KeyCode::Char(c @ '0'..='9'), false) = (key_code, modifiers.contains(KeyModifiers::CONTROL)) {
+            if c != '0' || self.pending_count.is_some() {
+                let digit = c.to_digit(10).unwrap_or(0) as usize;
+                self.pending_count = Some(self.pending_count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                return Mode::Normal;
+            }
+        }
+
+        // Only `j` and `k` keep the column they go back to, and `$` sets it anew.
+        // A key starting a command leaves it to the key that completes it.
+        let no_operator = self.pending_operator.is_none();
+        let vertical = no_operator
+            && matches!(self.pending_command_prefix, None | Some('g'))
+            && matches!(key_code, KeyCode::Char('j' | 'k') | KeyCode::Up | KeyCode::Down);
+        let starts_command = no_operator && self.pending_command_prefix.is_none() && matches!(key_code, KeyCode::Char('g' | 'z' | '[' | ']'));
+        if !vertical && !starts_command {
+            self.forget_desired_col();
+        }
+        let mut mode = self.dispatch_normal_mode_key(key_code, modifiers);
+        if matches!(mode, Mode::Insert | Mode::Replace) {
+            if let Err(e) = self.check_editable() {
+                self.report_error(e);
+                mode = Mode::Normal;
+            }
+        }
+        // A motion that lands inside a closed fold opens it; `j`/`k` stop on the
+        // fold's first line and so leave it closed.
+        if let Some(buffer) = self.active_buffer() {
+            let row = buffer.row.min(buffer.lines.len().saturating_sub(1));
+            buffer.open_folds_at(row);
+        }
+        // The count belongs to the command, so keep it while a prefix or operator is still pending.
+        if self.pending_command_prefix.is_none() && self.pending_operator.is_none() {
+            self.pending_text_object = None;
+            self.pending_count = None;
+        }
+        mode
+    }
+
+    fn dispatch_normal_mode_key(&mut self, key_code: KeyCode, modifiers: KeyModifiers) -> Mode {
+        let count = self.pending_count.unwrap_or(1);
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+
+        if let Some(op) = self.pending_operator.take() {
+            return self.apply_operator_motion(op, key_code, count);
+        }
+
+        let pending_prefix = self.pending_command_prefix.take();
+
+        if let Some(prefix) = pending_prefix {
+            match (prefix, key_code) {
+                ('[', KeyCode::Char('s')) => self.jump_to_misspelling(false),
+                (']', KeyCode::Char('s')) => self.jump_to_misspelling(true),
+                ('z', KeyCode::Char('=')) => self.suggest_spelling(),
+                ('[', KeyCode::Char('[')) => {
+                    self.push_jump();
+                    self.jump_to_block(false);
+                }
+                (']', KeyCode::Char(']')) => {
+                    self.push_jump();
+                    self.jump_to_block(true);
+                }
+                ('g', KeyCode::Char('g')) if ctrl => self.report_word_count(),
+                ('g', KeyCode::Char('B')) => self.reopen_closed_buffer(),
+                ('g', KeyCode::Char('g')) => {
+                    self.push_jump();
+                    self.goto_line(self.pending_count.unwrap_or(1));
+                }
+                // Lines are never soft-wrapped, so a display line is a buffer line.
+                ('g', KeyCode::Char(c @ ('*' | '#'))) => self.search_word_under_cursor(c == '*', false, count),
+                ('g', KeyCode::Char('j') | KeyCode::Down) => self.move_vertically(count, false),
+                ('g', KeyCode::Char('k') | KeyCode::Up) => self.move_vertically(count, true),
+                ('z', KeyCode::Char('f')) => self.pending_operator = Some(Operator::Fold),
+                ('z', KeyCode::Char('F')) => {
+                    if let Some(b) = self.active_buffer() {
+                        let end = (b.row + count - 1).min(b.lines.len() - 1);
+                        b.folds.push(Fold { start: b.row, end, open: false });
+                    }
+                }
+                ('z', KeyCode::Char(c @ ('a' | 'o' | 'c' | 'd'))) => {
+                    let Some(b) = self.active_buffer() else { return Mode::Normal };
+                    let row = b.row;
+                    // `zc` and `za` on a closed fold act on the fold around it.
+                    let fold = match c {
+                        'c' => b.folds.iter_mut().filter(|f| f.open && f.start <= row && row <= f.end).min_by_key(|f| f.end - f.start),
+                        _ => b.fold_at(row),
+                    };
+                    match (c, fold) {
+                        (_, None) => self.command_message = Message::Info("No fold found".to_string()),
+                        ('a', Some(fold)) => fold.open = !fold.open,
+                        ('o', Some(fold)) => fold.open = true,
+                        ('c', Some(fold)) => fold.open = false,
+                        (_, Some(fold)) => {
+                            let (start, end) = (fold.start, fold.end);
+                            b.folds.retain(|f| (f.start, f.end) != (start, end));
+                        }
+                    }
+                    if let Some(b) = self.active_buffer() {
+                        b.row = b.screen_row_start(b.row);
+                    }
+                }
+                ('z', KeyCode::Char(c @ ('R' | 'M'))) => {
+                    if let Some(b) = self.active_buffer() {
+                        b.folds.iter_mut().for_each(|f| f.open = c == 'R');
+                        b.row = b.screen_row_start(b.row);
+                    }
+                }
+                ('z', KeyCode::Char('E')) => {
+                    if let Some(b) = self.active_buffer() { b.folds.clear(); }
+                }
+                ('g', KeyCode::Char('u')) => self.pending_operator = Some(Operator::Case(CaseChange::Lower)),
+                ('g', KeyCode::Char('U')) => self.pending_operator = Some(Operator::Case(CaseChange::Upper)),
+                ('g', KeyCode::Char('~')) => self.pending_operator = Some(Operator::Case(CaseChange::Toggle)),
+                ('g', KeyCode::Char('c')) => self.pending_operator = Some(Operator::Comment),
+                _ => {}
+            }
+            return Mode::Normal;
+        }
+
+        match key_code {
+            KeyCode::Char('o') if ctrl => self.jump_back(),
+            KeyCode::Char(']' | '5') if ctrl => self.jump_to_tag(),
+            KeyCode::Char('t') if ctrl => match self.tag_stack.pop() {
+                Some(position) => self.go_to_position(position),
+                None => self.command_message = Message::Info("At bottom of tag stack".to_string()),
+            },
+            KeyCode::Char('v') if ctrl => {
+                if let Some(b) = self.buffers.get(self.active_buffer_index) {
+                    self.visual_anchor = (b.row, b.col);
+                    return Mode::VisualBlock;
+                }
+            }
+            KeyCode::Char('V') => {
+                if let Some(b) = self.buffers.get(self.active_buffer_index) {
+                    self.visual_anchor = (b.row, b.col);
+                    return Mode::VisualLine;
+                }
+            }
+            KeyCode::Char('p') => self.put(true, count),
+            KeyCode::Char('P') => self.put(false, count),
+            // Most terminals send Ctrl-i as Tab, which stays bound to focusing the tree.
+            KeyCode::Char('i') if ctrl => self.jump_forward(),
+            KeyCode::Char('i') => return Mode::Insert,
+            KeyCode::Down if ctrl => self.add_cursor(false, count),
+            KeyCode::Up if ctrl => self.add_cursor(true, count),
+            KeyCode::Esc => self.extra_cursors.clear(),
+            KeyCode::Char('D') => self.delete_to_line_end(),
+            KeyCode::Char('C') => {
+                self.delete_to_line_end();
+                if let Some(b) = self.active_buffer() {
+                    // D leaves the cursor on the last remaining character; C appends after it.
+                    b.col = b.lines[b.row].graphemes(true).count();
+                }
+                return Mode::Insert;
+            }
+            KeyCode::Char('S') => {
+                if let Some(b) = self.editable_buffer() {
+                    let line = std::mem::take(&mut b.lines[b.row]);
+                    if !line.is_empty() {
+                        b.modified = true;
+                    }
+                    b.col = 0;
+                    self.registers.delete(Register { lines: vec![line], kind: RegisterKind::Linewise });
+                }
+                return Mode::Insert;
+            }
+            KeyCode::Char('R') => {
+                self.replaced_graphemes.clear();
+                return Mode::Replace;
+            }
+            KeyCode::Char(':') => {
+                self.command_input.clear();
+                self.command_message.clear();
+                return Mode::Command;
+            }
+            KeyCode::Char(c @ ('/' | '?')) => {
+                self.command_prompt = c;
+                self.command_input.clear();
+                self.command_message.clear();
+                return Mode::Command;
+            }
+            KeyCode::Char('n') => self.search_next(false, count),
+            KeyCode::Char('N') => self.search_next(true, count),
+            KeyCode::Char(c @ ('*' | '#')) => self.search_word_under_cursor(c == '*', true, count),
+            KeyCode::Char('h') | KeyCode::Left => {
+                if let Some(b) = self.active_buffer() { b.col = b.col.saturating_sub(count); }
+            }
+            KeyCode::Char('l') | KeyCode::Right => {
+                if let Some(b) = self.active_buffer() { b.col += count; }
+            }
+            KeyCode::Char('j') | KeyCode::Down => self.move_vertically(count, false),
+            KeyCode::Char('k') | KeyCode::Up => self.move_vertically(count, true),
+            KeyCode::Char('w') => {
+                if let Some(b) = self.active_buffer() { for _ in 0..count { b.move_word_forward(); } }
+            }
+            KeyCode::Char('b') => {
+                if let Some(b) = self.active_buffer() { for _ in 0..count { b.move_word_backward(); } }
+            }
+            KeyCode::Char('e') => {
+                if let Some(b) = self.active_buffer() { for _ in 0..count { b.move_word_end(); } }
+            }
+            KeyCode::Char('0') => {
+                if let Some(b) = self.active_buffer() { b.col = 0; }
+            }
+            KeyCode::Char('^') => {
+                if let Some(b) = self.active_buffer() { b.col = first_non_blank(&b.lines[b.row]); }
+            }
+            KeyCode::Home => {
+                if let Some(b) = self.active_buffer() { b.smart_home(); }
+            }
+            KeyCode::Char('$') => {
+                if let Some(b) = self.active_buffer() {
+                    b.col = b.lines[b.row].graphemes(true).count().saturating_sub(1);
+                    // Like Vim, `j` and `k` then stay at the end of each line.
+                    b.desired_col = Some(usize::MAX);
+                }
+            }
+            KeyCode::Char('~') => {
+                if let Some(b) = self.editable_buffer() {
+                    let start = b.col;
+                    b.col = Self::convert_case_in_line(b, start, start + count, CaseChange::Toggle);
+                }
+            }
+            // `x` deletes `count` graphemes from the cursor, `X` as many before it;
+            // both stop at the ends of the line. Deleting the last one moves the
+            // cursor back onto the new last grapheme.
+            KeyCode::Char(c @ ('x' | 'X')) => {
+                if let Some(buffer) = self.editable_buffer() {
+                    let len = buffer.lines[buffer.row].graphemes(true).count();
+                    let col = buffer.col.min(len);
+                    let range = match c {
+                        'x' => col..(col + count).min(len),
+                        _ => col.saturating_sub(count)..col,
+                    };
+                    if !range.is_empty() {
+                        let bytes = grapheme_byte_range(&buffer.lines[buffer.row], range.clone());
+                        let deleted: String = buffer.lines[buffer.row].drain(bytes).collect();
+                        buffer.col = range.start.min((len - range.len()).saturating_sub(1));
+                        buffer.modified = true;
+                        self.registers.delete(Register { lines: vec![deleted], kind: RegisterKind::Charwise });
+                    }
+                }
+            }
+            KeyCode::Char('d') => self.pending_operator = Some(Operator::Delete),
+            KeyCode::Char('c') => self.pending_operator = Some(Operator::Change),
+            KeyCode::Char(c @ ('g' | '[' | ']' | 'z')) => self.pending_command_prefix = Some(c),
+            KeyCode::Char('!') => self.pending_operator = Some(Operator::Filter),
+            KeyCode::Char('{') => {
+                self.push_jump();
+                self.jump_to_paragraph(false);
+            }
+            KeyCode::Char('}') => {
+                self.push_jump();
+                self.jump_to_paragraph(true);
+            }
+            KeyCode::Char('G') => {
+                self.push_jump();
+                let last = self.buffers.get(self.active_buffer_index).map_or(1, |b| b.lines.len());
+                self.goto_line(self.pending_count.unwrap_or(last));
+            }
+            KeyCode::Char('o') => {
+                if let Some(b) = self.editable_buffer() {
+                    // On a closed fold the line goes below all of it, leaving the fold closed.
+                    b.row = b.closed_fold_at(b.row).map_or(b.row, |(_, end)| end) + 1;
+                    b.splice_lines(b.row..b.row, vec![String::new()]);
+                    b.col = 0;
+                    b.modified = true;
+                }
+                return Mode::Insert;
+            }
+            KeyCode::Char('O') => {
+                if let Some(b) = self.editable_buffer() {
+                    b.splice_lines(b.row..b.row, vec![String::new()]);
+                    b.col = 0;
+                    b.modified = true;
+                }
+                return Mode::Insert;
+            }
+            KeyCode::Tab if self.tree_visible => self.tree_view_active = true,
+            _ => {}
+        }
+        Mode::Normal
+    }
+
+    /// `Ctrl-Down`/`Ctrl-Up`: adds `count` cursors in the cursor's column on the
+    /// lines below the lowest cursor (above the highest with `up`).
+    fn add_cursor(&mut self, up: bool, count: usize) {
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+        let primary = (buffer.row, buffer.col);
+        for _ in 0..count {
+            let cursors = self.extra_cursors.iter().chain(std::iter::once(&primary));
+            let row = if up { cursors.map(|c| c.0).min() } else { cursors.map(|c| c.0).max() }.unwrap_or(primary.0);
+            let row = match up {
+                true if row > 0 => row - 1,
+                false if row + 1 < buffer.lines.len() => row + 1,
+                _ => break,
+            };
+            self.extra_cursors.push((row, primary.1));
+        }
+        self.command_message = Message::Info(format!("{} cursors", self.extra_cursors.len() + 1));
+    }
+
+    fn forget_desired_col(&mut self) {
+        if let Some(buffer) = self.active_buffer() {
+            buffer.desired_col = None;
+        }
+    }
+
+    /// `j` / `k`: moves `count` screen rows down or `up`, to the display column the
+    /// cursor was in before a run of such moves, or as near as each line allows.
+    fn move_vertically(&mut self, count: usize, up: bool) {
+        let width = self.settings.width;
+        let virtual_edit = self.settings.virtual_edit;
+        let Some(b) = self.active_buffer() else { return };
+        let want = b.desired_col.unwrap_or_else(|| display_col_of(&b.lines[b.row], b.col, &width));
+        b.row = b.step_rows(b.row, count, up);
+        let line = &b.lines[b.row];
+        let (len, line_width) = (line.graphemes(true).count(), display_width(line, &width));
+        b.col = match want >= line_width {
+            false => block_grapheme_range(line, want, want, &width).start,
+            // With `virtualedit` the cursor may go past the end, one column per cell.
+            true if virtual_edit && want != usize::MAX => len + want - line_width,
+            true => len.saturating_sub(1),
+        };
+        b.desired_col = Some(want);
+    }
+
+    /// `D`: deletes from the cursor to the end of the line, leaving the cursor on
+    /// the new last character.
+    fn delete_to_line_end(&mut self) {
+        if let Some(buffer) = self.editable_buffer() {
+            let line = &mut buffer.lines[buffer.row];
+            let byte_idx = line.grapheme_indices(true).nth(buffer.col).map_or(line.len(), |(i, _)| i);
+            buffer.col = buffer.col.saturating_sub(1);
+            if byte_idx < line.len() {
+                let deleted = line.split_off(byte_idx);
+                buffer.modified = true;
+                self.registers.delete(Register { lines: vec![deleted], kind: RegisterKind::Charwise });
+            }
+        }
+    }
+
+    /// Resolves the motion or text object keyed after a pending operator to a
+    /// range and applies the operator to it. Repeating the operator's own key
+    /// (`guu`, `gUU`, `g~~`, `dd`, `cc`) applies it to `count` whole lines, as
+    /// many as there are.
+    fn apply_operator_motion(&mut self, op: Operator, key_code: KeyCode, count: usize) -> Mode {
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return Mode::Normal };
+        if let Some(inner) = self.pending_text_object.take() {
+            let KeyCode::Char(object) = key_code else { return Mode::Normal };
+            let cursor = (buffer.row, buffer.col);
+            return match text_object_range(&buffer.lines, cursor, object, inner, count) {
+                Some(range) => self.apply_operator(op, range),
+                None => Mode::Normal,
+            };
+        }
+        if let KeyCode::Char(c @ ('i' | 'a')) = key_code {
+            self.pending_operator = Some(op);
+            self.pending_text_object = Some(c == 'i');
+            return Mode::Normal;
+        }
+        match key_code {
+            KeyCode::Char(c @ ('/' | '?')) => {
+                self.search_operator = Some((op, count));
+                self.command_prompt = c;
+                self.command_input.clear();
+                self.command_message.clear();
+                return Mode::Command;
+            }
+            KeyCode::Char(c @ ('n' | 'N')) => return self.apply_operator_to_search(op, c == 'N', count),
+            _ => {}
+        }
+        let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+        let len = graphemes.len();
+        let col = buffer.col.min(len);
+        let row = buffer.row;
+        let last = buffer.lines.len() - 1;
+        // The doubled key (`guu`, `gcc`, `!!`) acts on whole lines.
+        let line_key = match op {
+            Operator::Case(CaseChange::Lower) => Some('u'),
+            Operator::Case(CaseChange::Upper) => Some('U'),
+            Operator::Case(CaseChange::Toggle) => Some('~'),
+            Operator::Comment => Some('c'),
+            Operator::Filter => Some('!'),
+            Operator::Fold => None,
+            Operator::Delete => Some('d'),
+            Operator::Change => Some('c'),
+        };
+        // Like Vim, `cw` on a word stops at its end instead of eating the blanks
+        // after it. Unlike `e`, it looks for that end from the cursor itself, so on
+        // the last letter of a word it changes just that letter.
+        let change_word = matches!(op, Operator::Change) && graphemes.get(col).is_some_and(|g| grapheme_class(g) != 0);
+        let range = match key_code {
+            KeyCode::Char(c) if Some(c) == line_key => Some(TextRange::rows(row, (row + count - 1).min(last))),
+            KeyCode::Char('j') | KeyCode::Down => Some(TextRange::rows(row, (row + count).min(last))),
+            KeyCode::Char('k') | KeyCode::Up => Some(TextRange::rows(row.saturating_sub(count), row)),
+            KeyCode::Char('}') => Some(TextRange::rows(row, (0..count).fold(row, |r, _| paragraph_row(&buffer.lines, r, true)))),
+            KeyCode::Char('{') => Some(TextRange::rows((0..count).fold(row, |r, _| paragraph_row(&buffer.lines, r, false)), row)),
+            KeyCode::Char('G') => Some(TextRange::rows(row, last)),
+            KeyCode::Char('w') if change_word => word_end(&graphemes, col)
+                .and_then(|first| (1..count).try_fold(first, |end, _| word_end(&graphemes, end + 1)))
+                .map(|end| TextRange::chars(row, col, end + 1)),
+            KeyCode::Char('w') => Some(TextRange::chars(row, col, (0..count).fold(col, |i, _| next_word_start(&graphemes, i)))),
+            KeyCode::Char('e') => (0..count)
+                .try_fold(col, |i, _| word_end(&graphemes, i + 1))
+                .map(|end| TextRange::chars(row, col, end + 1)),
+            KeyCode::Char('b') => Some(TextRange::chars(row, (0..count).fold(col, |i, _| prev_word_start(&graphemes, i)), col)),
+            KeyCode::Char('h') | KeyCode::Left => Some(TextRange::chars(row, col.saturating_sub(count), col)),
+            KeyCode::Char('l') | KeyCode::Right => Some(TextRange::chars(row, col, (col + count).min(len))),
+            KeyCode::Char('0') => Some(TextRange::chars(row, 0, col)),
+            KeyCode::Char('^') => {
+                let first = first_non_blank(&buffer.lines[row]);
+                Some(TextRange::chars(row, first.min(col), first.max(col)))
+            }
+            KeyCode::Char('$') => Some(TextRange::chars(row, col, len)),
+            _ => None,
+        };
+        self.apply_operator_to(op, range)
+    }
+
+    /// Applies `op` to the range a motion resolved to, if it resolved to one the
+    /// operator takes.
+    fn apply_operator_to(&mut self, op: Operator, range: Option<TextRange>) -> Mode {
+        match range {
+            // Line operators only take motions that move between lines.
+            Some(range) if range.linewise || !matches!(op, Operator::Comment | Operator::Filter | Operator::Fold) => self.apply_operator(op, range),
+            _ => Mode::Normal,
+        }
+    }
+
+    /// Applies `op` to `range`, leaving the cursor at its start, and returns the
+    /// mode to continue in.
+    fn apply_operator(&mut self, op: Operator, range: TextRange) -> Mode {
+        let (first, last) = (range.start.0, range.end.0);
+        if !matches!(op, Operator::Fold) && self.editable_buffer().is_none() {
+            return Mode::Normal;
+        }
+        match op {
+            Operator::Filter => {
+                // Like Vim, ask for the command on the command line, after the range.
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.row = first;
+                }
+                self.command_input = match last - first {
+                    0 => ".!".to_string(),
+                    lines => format!(".,.+{}!", lines),
+                };
+                return Mode::Command;
+            }
+            Operator::Fold => {
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.folds.push(Fold { start: first, end: last, open: false });
+                    buffer.row = first;
+                }
+            }
+            Operator::Comment => self.toggle_comment(first, last),
+            Operator::Case(change) => {
+                let Some(buffer) = self.active_buffer() else { return Mode::Normal };
+                for row in first..=last {
+                    buffer.row = row;
+                    let start = if row == first && !range.linewise { range.start.1 } else { 0 };
+                    let end = if row == last && !range.linewise { range.end.1 } else { usize::MAX };
+                    Self::convert_case_in_line(buffer, start, end, change);
+                }
+                buffer.row = first;
+                buffer.col = range.start.1;
+            }
+            Operator::Delete | Operator::Change => {
+                let Some(buffer) = self.active_buffer() else { return Mode::Normal };
+                let deleted = if range.linewise {
+                    // Deleting every line leaves one empty line; the cursor goes to
+                    // the line after the deleted ones, or the new last line.
+                    let deleted = buffer.splice_lines(first..last + 1, Vec::new());
+                    if op == Operator::Change || buffer.lines.is_empty() {
+                        buffer.splice_lines(first..first, vec![String::new()]);
+                    }
+                    buffer.row = first.min(buffer.lines.len() - 1);
+                    buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+                    Register { lines: deleted, kind: RegisterKind::Linewise }
+                } else {
+                    let byte = |line: &str, col: usize| line.grapheme_indices(true).nth(col).map_or(line.len(), |(i, _)| i);
+                    let start = byte(&buffer.lines[first], range.start.1);
+                    let end = byte(&buffer.lines[last], range.end.1);
+                    if (first, start) >= (last, end) {
+                        return Mode::Normal;
+                    }
+                    let tail = buffer.lines[last].split_off(end);
+                    let mut deleted = buffer.splice_lines(first + 1..last + 1, Vec::new());
+                    deleted.insert(0, buffer.lines[first].split_off(start));
+                    buffer.lines[first].push_str(&tail);
+                    buffer.row = first;
+                    buffer.col = range.start.1;
+                    if op == Operator::Delete {
+                        // Stay on a character, as `D` does.
+                        buffer.col = buffer.col.min(buffer.lines[first].graphemes(true).count().saturating_sub(1));
+                    }
+                    Register { lines: deleted, kind: RegisterKind::Charwise }
+                };
+                buffer.modified = true;
+                self.registers.delete(deleted);
+                if op == Operator::Change {
+                    return Mode::Insert;
+                }
+            }
+        }
+        Mode::Normal
+    }
+
+    /// Toggles line comments on rows `first..=last`. If any non-blank line in the
+    /// range is uncommented, every line gets commented; otherwise all are uncommented.
+    /// The prefix goes after each line's indentation, which is left untouched.
+    fn toggle_comment(&mut self, first: usize, last: usize) {
+        let Some(buffer) = self.active_buffer() else { return };
+        let Some(prefix) = comment_prefix(buffer.filetype.as_deref()) else {
+            self.command_message = Message::Info("No comment prefix for this file type".to_string());
+            return;
+        };
+        let rows = first..=last;
+        let is_commented = |line: &String| line.trim_start().starts_with(prefix);
+        let comment = buffer.lines[rows.clone()].iter().any(|l| !l.trim().is_empty() && !is_commented(l));
+        for line in &mut buffer.lines[rows] {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Blank lines are left alone, so only other lines change the text.
+            buffer.modified = true;
+            let indent = line.len() - line.trim_start().len();
+            let body = &line[indent..];
+            let new_body = if comment {
+                format!("{} {}", prefix, body)
+            } else {
+                let rest = &body[prefix.len()..];
+                rest.strip_prefix(' ').unwrap_or(rest).to_string()
+            };
+            line.truncate(indent);
+            line.push_str(&new_body);
+        }
+        buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+    }
+
+    /// Rewrites graphemes `start..end` of the cursor line with their case converted
+    /// and returns the grapheme index just past the converted text, which can differ
+    /// from `end` when a mapping expands (`ß` -> `SS`).
+    fn convert_case_in_line(buffer: &mut Buffer, start: usize, end: usize, change: CaseChange) -> usize {
+        let line = &buffer.lines[buffer.row];
+        let graphemes: Vec<&str> = line.graphemes(true).collect();
+        let end = end.min(graphemes.len());
+        if start >= end {
+            return start;
+        }
+        let converted = convert_case(&graphemes[start..end].concat(), change);
+        let new_end = start + converted.graphemes(true).count();
+        let new_line = format!("{}{}{}", graphemes[..start].concat(), converted, graphemes[end..].concat());
+        if new_line != *line {
+            buffer.lines[buffer.row] = new_line;
+            buffer.modified = true;
+        }
+        new_end
+    }
+
+    /// Moves the cursor to the first non-blank of a 1-based line number, clamped to the buffer.
+    fn goto_line(&mut self, line: usize) {
+        if let Some(buffer) = self.active_buffer() {
+            buffer.row = line.saturating_sub(1).min(buffer.lines.len() - 1);
+            buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+        }
+    }
+
+    fn current_position(&self) -> Option<(usize, usize, usize)> {
+        self.buffers.get(self.active_buffer_index).map(|b| (self.active_buffer_index, b.row, b.col))
+    }
+
+    /// Records the cursor position before a jump, dropping any positions that
+    /// were ahead of it after `Ctrl-o`.
+    fn push_jump(&mut self) {
+        let Some(position) = self.current_position() else { return };
+        self.jumplist.truncate(self.jumplist_index);
+        if self.jumplist.last() != Some(&position) {
+            self.jumplist.push(position);
+            if self.jumplist.len() > JUMPLIST_MAX {
+                self.jumplist.remove(0);
+            }
+        }
+        self.jumplist_index = self.jumplist.len();
+    }
+
+    /// `Ctrl-o`: goes back to the previous position in the jump list.
+    fn jump_back(&mut self) {
+        // Remember where we jumped back from so `Ctrl-i` can return to it.
+        if self.jumplist_index == self.jumplist.len() {
+            if let Some(position) = self.current_position() {
+                if self.jumplist.last() != Some(&position) {
+                    self.jumplist.push(position);
+                }
+            }
+            self.jumplist_index = self.jumplist.len().saturating_sub(1);
+        }
+        if self.jumplist_index == 0 {
+            return;
+        }
+        self.jumplist_index -= 1;
+        self.restore_jump();
+    }
+
+    /// `Ctrl-i`: goes forward again after `Ctrl-o`.
+    fn jump_forward(&mut self) {
+        if self.jumplist_index + 1 >= self.jumplist.len() {
+            return;
+        }
+        self.jumplist_index += 1;
+        self.restore_jump();
+    }
+
+    fn restore_jump(&mut self) {
+        self.go_to_position(self.jumplist[self.jumplist_index]);
+    }
+
+    fn go_to_position(&mut self, (buffer_index, row, col): (usize, usize, usize)) {
+        if buffer_index >= self.buffers.len() {
+            self.command_message = Message::Info("Jump target buffer no longer exists".to_string());
+            return;
+        }
+        self.switch_to_buffer(buffer_index);
+        if let Some(buffer) = self.active_buffer() {
+            buffer.row = row.min(buffer.lines.len() - 1);
+            buffer.col = col;
+        }
+    }
+
+    /// Definitions of `name` from the `tags` file in the tree root. The file is
+    /// parsed once and again only after it changes on disk.
+    fn lookup_tag(&mut self, name: &str) -> Result<Vec<TagEntry>, EditorError> {
+        let path = self.current_path.join("tags");
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if self.tags.as_ref().is_none_or(|tags| tags.modified != modified || modified.is_none()) {
+            let content = std::fs::read_to_string(&path).map_err(|source| EditorError::Read { path: path.clone(), source })?;
+            self.tags = Some(TagsCache { modified, entries: parse_tags(&content, &self.current_path) });
+        }
+        Ok(self.tags.as_ref().and_then(|tags| tags.entries.get(name)).cloned().unwrap_or_default())
+    }
+
+    /// `Ctrl-]`: jumps to the definition of the word under the cursor, or lists
+    /// the definitions when there are several.
+    fn jump_to_tag(&mut self) {
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+        let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+        let col = buffer.col.min(graphemes.len());
+        let start = graphemes[..col].iter().rposition(|g| grapheme_class(g) != 1).map_or(0, |i| i + 1);
+        let end = graphemes[col..].iter().position(|g| grapheme_class(g) != 1).map_or(graphemes.len(), |i| col + i);
+        let word = graphemes[start..end].concat();
+        if word.is_empty() {
+            self.command_message = Message::Info("No identifier under the cursor".to_string());
+            return;
+        }
+        match self.lookup_tag(&word) {
+            Err(e) => self.report_error(e),
+            Ok(entries) => match entries.len() {
+                0 => self.command_message = Message::Info(format!("Tag not found: {}", word)),
+                1 => self.go_to_tag(&entries[0]),
+                n => {
+                    self.command_message = Message::Info(format!("{} definitions of {}, pick one with 1-9", n, word));
+                    self.tag_choices = Some(entries.into_iter().take(9).collect());
+                }
+            },
+        }
+    }
+
+    /// Opens the file of `entry` at its definition, recording where we came from
+    /// for `Ctrl-t` and `Ctrl-o`.
+    fn go_to_tag(&mut self, entry: &TagEntry) {
+        if !entry.file.exists() {
+            self.command_message = Message::Info(format!("File of tag {} not found: {}", entry.name, entry.file.display()));
+            return;
+        }
+        let Some(position) = self.current_position() else { return };
+        self.push_jump();
+        self.tag_stack.push(position);
+        self.open_file(entry.file.clone());
+        self.tree_view_active = false;
+        let Some(buffer) = self.active_buffer() else { return };
+        match find_tag_row(&buffer.lines, &entry.address) {
+            Some(row) => {
+                let line = &buffer.lines[row];
+                buffer.row = row;
+                buffer.col = line.find(&entry.name).map_or_else(|| first_non_blank(line), |i| line[..i].graphemes(true).count());
+            }
+            None => self.command_message = Message::Info(format!("Definition of {} not found in {}", entry.name, entry.file.display())),
+        }
+    }
+
+    /// The word list to check the active buffer against, if spell checking applies to it.
+    fn active_spell_words(&self) -> Option<&HashSet<String>> {
+        let buffer = self.buffers.get(self.active_buffer_index)?;
+        if !self.settings.spell || !is_prose(buffer.filetype.as_deref()) {
+            return None;
+        }
+        self.spell_words.as_ref()
+    }
+
+    /// Loads the word list once `:set spell` is on. If it can't be read, spell
+    /// checking is turned back off.
+    fn load_spell_words_if_needed(&mut self) {
+        if !self.settings.spell || self.spell_words.is_some() {
+            return;
+        }
+        match load_spell_words(&self.settings.spellfile) {
+            Ok(words) => self.spell_words = Some(words),
+            Err(source) => {
+                self.settings.spell = false;
+                self.report_error(EditorError::WordList { path: self.settings.spellfile.clone(), source });
+            }
+        }
+    }
+
+    /// Searches for `pattern` from the cursor (`/` forward, `?` backward) and makes it
+    /// the pattern `n` and `N` repeat. An empty pattern repeats the last search.
+    fn start_search(&mut self, pattern: &str, forward: bool) {
+        if self.set_search(pattern, forward) {
+            self.search_next(false, 1);
+        }
+    }
+
+    /// Makes `pattern` the last search, or reuses the last one's pattern if it
+    /// is empty. False if there is no pattern to reuse.
+    fn set_search(&mut self, pattern: &str, forward: bool) -> bool {
+        let search = match (&self.search, pattern.is_empty()) {
+            (Some(last), true) => Search { forward, ..last.clone() },
+            (None, true) => {
+                self.command_message = Message::Info("No previous search pattern".to_string());
+                return false;
+            }
+            (_, false) => Search { pattern: pattern.to_string(), whole_word: false, forward },
+        };
+        self.search = Some(search);
+        self.search_highlight = true;
+        true
+    }
+
+    /// `*` / `#` (and `g*` / `g#` without `whole_word`): searches for the word
+    /// under the cursor, or the next one on the line.
+    fn search_word_under_cursor(&mut self, forward: bool, whole_word: bool, count: usize) {
+        let Some(buffer) = self.active_buffer() else { return };
+        let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+        let Some(start) = (buffer.col.min(graphemes.len())..graphemes.len()).find(|&i| grapheme_class(graphemes[i]) == 1) else {
+            self.command_message = Message::Info("No identifier under cursor".to_string());
+            return;
+        };
+        // From inside the word, a backward search would find its own start first.
+        let start = prev_word_start(&graphemes, start + 1);
+        let end = (start..graphemes.len()).find(|&i| grapheme_class(graphemes[i]) != 1).unwrap_or(graphemes.len());
+        let pattern = graphemes[start..end].concat();
+        buffer.col = start;
+        self.search = Some(Search { pattern, whole_word, forward });
+        self.search_highlight = true;
+        self.search_next(false, count);
+    }
+
+    /// `n` (or `N` with `reverse`): moves to the `count`th next match of the last
+    /// search in its direction (or the opposite one), wrapping around the buffer.
+    fn search_next(&mut self, reverse: bool, count: usize) {
+        let Some((row, col)) = self.find_search_match(reverse, count) else { return };
+        self.push_jump();
+        if let Some(buffer) = self.active_buffer() {
+            buffer.row = row;
+            buffer.col = col;
+        }
+    }
+
+    /// Where `n` (or `N` with `reverse`) would go, without moving the cursor.
+    /// Reports wrapping around the buffer, or why there is no match.
+    fn find_search_match(&mut self, reverse: bool, count: usize) -> Option<(usize, usize)> {
+        let Some(search) = self.search.clone() else {
+            self.command_message = Message::Info("No previous search pattern".to_string());
+            return None;
+        };
+        let forward = search.forward != reverse;
+        let regex = match self.settings.search_regex(&search) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.report_error(e);
+                return None;
+            }
+        };
+        let buffer = self.buffers.get(self.active_buffer_index)?;
+        let (mut row, mut col) = (buffer.row, buffer.col);
+        let mut wrapped = false;
+        for _ in 0..count {
+            let cursor_byte = grapheme_byte_range(&buffer.lines[row], col..col).start;
+            match find_wrapping(&buffer.lines, row, cursor_byte, forward, |line| search_matches(line, &search, &regex)) {
+                Some((r, c, w)) => {
+                    (row, col) = (r, c);
+                    wrapped |= w;
+                }
+                None => {
+                    self.command_message = Message::Info(format!("Pattern not found: {}", search.pattern));
+                    return None;
+                }
+            }
+        }
+        let prompt = if forward { '/' } else { '?' };
+        self.command_message = Message::Info(match wrapped {
+            true if forward => "search hit BOTTOM, continuing at TOP".to_string(),
+            true => "search hit TOP, continuing at BOTTOM".to_string(),
+            false => format!("{}{}", prompt, search.pattern),
+        });
+        Some((row, col))
+    }
+
+    /// Applies `op` from the cursor up to, but not including, the `count`th next
+    /// search match (`d/foo<CR>`, `dn`, `dN`). Without a match nothing changes.
+    fn apply_operator_to_search(&mut self, op: Operator, reverse: bool, count: usize) -> Mode {
+        let Some(target) = self.find_search_match(reverse, count) else { return Mode::Normal };
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return Mode::Normal };
+        let cursor = (buffer.row, buffer.col);
+        let (start, mut end) = (cursor.min(target), cursor.max(target));
+        // Like Vim, an exclusive motion ending at the start of a later line stops
+        // at the end of the line before, leaving the line break alone. If it also
+        // starts at or before the first non-blank, it takes those lines whole.
+        if end.0 > start.0 && end.1 == 0 {
+            if start.1 <= first_non_blank(&buffer.lines[start.0]) {
+                return self.apply_operator_to(op, Some(TextRange::rows(start.0, end.0 - 1)));
+            }
+            end = (end.0 - 1, buffer.lines[end.0 - 1].graphemes(true).count());
+        }
+        self.apply_operator_to(op, Some(TextRange { start, end, linewise: false }))
+    }
+
+    /// `]s` / `[s`: moves to the next (or previous) misspelled word, wrapping
+    /// around the end of the buffer.
+    fn jump_to_misspelling(&mut self, forward: bool) {
+        let Some(words) = self.active_spell_words() else {
+            self.command_message = Message::Info("Spell checking is off for this buffer".to_string());
+            return;
+        };
+        let buffer = &self.buffers[self.active_buffer_index];
+        let cursor_byte = grapheme_byte_range(&buffer.lines[buffer.row], buffer.col..buffer.col).start;
+        match find_wrapping(&buffer.lines, buffer.row, cursor_byte, forward, |line| misspelled_words(line, words)) {
+            Some((row, col, wrapped)) => {
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.row = row;
+                    buffer.col = col;
+                }
+                if wrapped {
+                    self.command_message = Message::Info(if forward { "search hit BOTTOM, continuing at TOP" } else { "search hit TOP, continuing at BOTTOM" }.to_string());
+                }
+            }
+            None => self.command_message = Message::Info("No misspelled words".to_string()),
+        }
+    }
+
+    /// `z=`: lists spelling suggestions for the word under the cursor.
+    fn suggest_spelling(&mut self) {
+        let Some(words) = self.active_spell_words() else {
+            self.command_message = Message::Info("Spell checking is off for this buffer".to_string());
+            return;
+        };
+        let buffer = &self.buffers[self.active_buffer_index];
+        let line = &buffer.lines[buffer.row];
+        let cursor_byte = grapheme_byte_range(line, buffer.col..buffer.col).start;
+        let word = line
+            .split_word_bound_indices()
+            .find(|(i, w)| (*i..i + w.len()).contains(&cursor_byte) && w.starts_with(char::is_alphabetic));
+        let Some((start, word)) = word else {
+            self.command_message = Message::Info("No word under the cursor".to_string());
+            return;
+        };
+        let suggestions = spell_suggestions(word, words);
+        if suggestions.is_empty() {
+            self.command_message = Message::Info(format!("No suggestions for {}", word));
+            return;
+        }
+        self.spell_popup = Some(SpellPopup { row: buffer.row, range: start..start + word.len(), suggestions });
+    }
+
+    /// Replaces bytes `range` of `row` with `word` and puts the cursor at its start.
+    fn replace_word(&mut self, row: usize, range: Range<usize>, word: &str) {
+        let Some(buffer) = self.editable_buffer() else { return };
+        let Some(line) = buffer.lines.get_mut(row) else { return };
+        line.replace_range(range.clone(), word);
+        buffer.row = row;
+        buffer.col = line[..range.start].graphemes(true).count();
+        buffer.modified = true;
+    }
+
+    /// Moves to the nearest non-blank line (after the cursor if `forward`) whose
+    /// indentation is lower than or equal to the current line's, for `]]` / `[[`.
+    fn jump_to_block(&mut self, forward: bool) {
+        if let Some(buffer) = self.active_buffer() {
+            let tabstop = buffer.indent.tabstop;
+            let current = indent_width(&buffer.lines[buffer.row], tabstop);
+            let is_target = |line: &String| !line.trim().is_empty() && indent_width(line, tabstop) <= current;
+            let target = if forward {
+                (buffer.row + 1..buffer.lines.len()).find(|&i| is_target(&buffer.lines[i]))
+            } else {
+                (0..buffer.row).rev().find(|&i| is_target(&buffer.lines[i]))
+            };
+            if let Some(row) = target {
+                buffer.row = row;
+                buffer.col = first_non_blank(&buffer.lines[row]);
+            }
+        }
+    }
+
+    /// Moves to the next (or previous) blank line separating paragraphs, for `}` / `{`.
+    /// Stops at the first or last line when there is no blank line left.
+    fn jump_to_paragraph(&mut self, forward: bool) {
+        if let Some(buffer) = self.active_buffer() {
+            buffer.row = paragraph_row(&buffer.lines, buffer.row, forward);
+            buffer.col = 0;
+        }
+    }
+
+    /// Handles key presses in insert mode.
+    fn handle_insert_mode_key(&mut self, key_code: KeyCode, modifiers: KeyModifiers) -> Mode {
+        if let Some(mut code) = self.literal_input.take() {
+            let first = code.chars().next();
+            let (radix, len) = first.and_then(literal_code_format).unwrap_or((10, 0));
+            match key_code {
+                // The key after `Ctrl-v` that starts a code.
+                KeyCode::Char(c) if first.is_none() && modifiers.is_empty() && literal_code_format(c).is_some() => {
+                    code.push(c);
+                    self.literal_input = Some(code);
+                    return Mode::Insert;
+                }
+                KeyCode::Char(c) if first.is_some() && c.is_digit(radix) => {
+                    code.push(c);
+                    let digits = code.len() - usize::from(!code.starts_with(|c: char| c.is_ascii_digit()));
+                    if digits < len {
+                        self.literal_input = Some(code);
+                    } else if let Some(c) = literal_code_char(&code) {
+                        self.insert_literal(c);
+                    }
+                    return Mode::Insert;
+                }
+                // Any other key inserts itself: `Tab` a tab even with `expandtab`,
+                // `Ctrl-a` to `Ctrl-z` their control characters. `Esc` gives up.
+                _ if first.is_none() => {
+                    let c = match key_code {
+                        KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => {
+                            Some(c.to_ascii_lowercase()).filter(char::is_ascii_lowercase).map(|c| (c as u8 - b'a' + 1) as char)
+                        }
+                        KeyCode::Char(c) => Some(c),
+                        KeyCode::Tab => Some('\t'),
+                        KeyCode::Enter => Some('\r'),
+                        _ => None,
+                    };
+                    if let Some(c) = c {
+                        self.insert_literal(c);
+                    }
+                    return Mode::Insert;
+                }
+                // A key that can't continue the code ends it and then acts as usual.
+                _ => {
+                    if let Some(c) = literal_code_char(&code) {
+                        self.insert_literal(c);
+                    }
+                }
+            }
+        }
+        if key_code == KeyCode::Esc {
+            if let Some(block_insert) = self.block_insert.take() {
+                self.finish_block_insert(block_insert);
+            }
+        }
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl && matches!(key_code, KeyCode::Char('n' | 'p')) {
+            self.complete(key_code == KeyCode::Char('n'));
+            return Mode::Insert;
+        }
+        if ctrl && key_code == KeyCode::Char('v') {
+            self.completion = None;
+            self.literal_input = Some(String::new());
+            return Mode::Insert;
+        }
+        // Any other key accepts the word as it stands.
+        self.completion = None;
+        match key_code {
+            KeyCode::Esc if !self.inserted_text.is_empty() => {
+                let lines = std::mem::take(&mut self.inserted_text).split('\n').map(String::from).collect();
+                self.registers.last_insert = Some(Register { lines, kind: RegisterKind::Charwise });
+            }
+            KeyCode::Char(c) if !ctrl => self.inserted_text.push(c),
+            KeyCode::Tab => self.inserted_text.push('\t'),
+            KeyCode::Enter => self.inserted_text.push('\n'),
+            KeyCode::Backspace => {
+                self.inserted_text.pop();
+            }
+            _ => {}
+        }
+        if key_code == KeyCode::Esc {
+            self.extra_cursors.clear();
+            return Mode::Normal;
+        }
+        let autopairs = self.settings.autopairs;
+        let width = self.settings.width;
+        let mut extra_cursors = std::mem::take(&mut self.extra_cursors);
+        let Some(buffer) = self.active_buffer() else { return Mode::Insert };
+        if extra_cursors.is_empty() {
+            // Past the end of the line (`virtualedit`), Backspace only moves back and
+            // typing first fills the gap with spaces.
+            let len = buffer.lines[buffer.row].graphemes(true).count();
+            if buffer.col > len {
+                match key_code {
+                    KeyCode::Backspace => {
+                        buffer.col -= 1;
+                        return Mode::Insert;
+                    }
+                    KeyCode::Char(_) | KeyCode::Tab if !ctrl => {
+                        let padding = " ".repeat(buffer.col - len);
+                        buffer.lines[buffer.row].push_str(&padding);
+                    }
+                    _ => {}
+                }
+            }
+            buffer.modified = true;
+            Self::insert_mode_edit(buffer, key_code, ctrl, autopairs, width);
+            return Mode::Insert;
+        }
+        // Edit at every cursor from the top of the buffer down, moving the cursors
+        // after each edit along with the text they are on.
+        let primary = extra_cursors.len();
+        extra_cursors.push((buffer.row, buffer.col));
+        let mut order: Vec<usize> = (0..extra_cursors.len()).collect();
+        order.sort_by_key(|&i| extra_cursors[i]);
+        let mut modified = false;
+        for (n, &i) in order.iter().enumerate() {
+            let (row, col) = extra_cursors[i];
+            let row = row.min(buffer.lines.len() - 1);
+            let col = col.min(buffer.lines[row].graphemes(true).count());
+            let (line_count, line_len) = (buffer.lines.len(), buffer.lines[row].graphemes(true).count());
+            (buffer.row, buffer.col) = (row, col);
+            buffer.modified = true;
+            Self::insert_mode_edit(buffer, key_code, ctrl, autopairs, width);
+            modified |= buffer.modified;
+            extra_cursors[i] = (buffer.row, buffer.col);
+            let added_rows = buffer.lines.len() as isize - line_count as isize;
+            for &j in &order[n + 1..] {
+                let (r, c) = extra_cursors[j];
+                extra_cursors[j] = if r != row {
+                    ((r as isize + added_rows) as usize, c)
+                } else if added_rows == 0 && buffer.row == row {
+                    // Same line: shift by what the line grew or shrank (an auto-pair adds two).
+                    (r, (c + buffer.lines[row].graphemes(true).count()).saturating_sub(line_len))
+                } else {
+                    // The line was split or joined: keep the distance from the edited cursor.
+                    (buffer.row, buffer.col + c.saturating_sub(col))
+                };
+            }
+        }
+        buffer.modified = modified;
+        (buffer.row, buffer.col) = extra_cursors.remove(primary);
+        // Cursors that ran into each other (Backspace at the start of lines) become one.
+        let cursor = (buffer.row, buffer.col);
+        extra_cursors.retain(|&c| c != cursor);
+        extra_cursors.sort();
+        extra_cursors.dedup();
+        self.extra_cursors = extra_cursors;
+        Mode::Insert
+    }
+
+    /// Inserts `c` at every cursor as typed, but without expanding tabs or
+    /// auto-closing pairs.
+    fn insert_literal(&mut self, c: char) {
+        let autopairs = std::mem::replace(&mut self.settings.autopairs, false);
+        self.handle_insert_mode_key(KeyCode::Char(c), KeyModifiers::NONE);
+        self.settings.autopairs = autopairs;
+    }
+
+    /// Applies an Insert mode key at the cursor of `buffer`, clearing `modified`
+    /// for keys that change nothing.
+    fn insert_mode_edit(buffer: &mut Buffer, key_code: KeyCode, ctrl: bool, autopairs: bool, width: WidthConfig) {
+        match key_code {
+            KeyCode::Tab => {
+                let line = &mut buffer.lines[buffer.row];
+                let byte_idx = grapheme_byte_range(line, buffer.col..buffer.col).start;
+                if buffer.indent.expandtab {
+                    let shiftwidth = buffer.indent.shiftwidth();
+                    let col = text_width(&line[..byte_idx], buffer.indent.tabstop, &width);
+                    let spaces = shiftwidth - col % shiftwidth;
+                    line.insert_str(byte_idx, &" ".repeat(spaces));
+                    buffer.col += spaces;
+                } else {
+                    line.insert(byte_idx, '\t');
+                    buffer.col += 1;
+                }
+            }
+            // Ctrl-w deletes the word before the cursor, using the same boundaries as `b`.
+            KeyCode::Char('w') if ctrl && buffer.col > 0 => {
+                let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                let col = buffer.col.min(graphemes.len());
+                let start = prev_word_start(&graphemes, col);
+                buffer.lines[buffer.row] = format!("{}{}", graphemes[..start].concat(), graphemes[col..].concat());
+                buffer.col = start;
+            }
+            // Ctrl-u deletes everything before the cursor on this line.
+            KeyCode::Char('u') if ctrl && buffer.col > 0 => {
+                let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                let col = buffer.col.min(graphemes.len());
+                buffer.lines[buffer.row] = graphemes[col..].concat();
+                buffer.col = 0;
+            }
+            KeyCode::Char('w' | 'u') if ctrl => {
+                // At the start of a line both join it onto the previous one, like Backspace.
+                if buffer.row > 0 {
+                    let line = buffer.splice_lines(buffer.row..buffer.row + 1, Vec::new()).concat();
+                    buffer.row -= 1;
+                    buffer.col = buffer.lines[buffer.row].graphemes(true).count();
+                    buffer.lines[buffer.row].push_str(&line);
+                }
+            }
+            KeyCode::Enter => {
+                // FIX: Split line at the correct byte index for the grapheme.
+                let line = &mut buffer.lines[buffer.row];
+                let byte_idx = line.grapheme_indices(true).nth(buffer.col).map_or(line.len(), |(i, _)| i);
+                let new_line = line.split_off(byte_idx);
+                buffer.splice_lines(buffer.row + 1..buffer.row + 1, vec![new_line]);
+                buffer.row += 1;
+                buffer.col = 0;
+            }
+            KeyCode::Backspace => {
+                if buffer.col > 0 {
+                    // FIX: Remove previous grapheme.
+                    let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                    buffer.col -= 1;
+                    let removed = graphemes.remove(buffer.col);
+                    // Deleting the opener of an empty pair also deletes its closer.
+                    if autopairs {
+                        let closer = removed.chars().next().and_then(pair_closer);
+                        if closer.is_some() && graphemes.get(buffer.col).map(|g| g.chars().next()) == Some(closer) {
+                            graphemes.remove(buffer.col);
+                        }
+                    }
+                    buffer.lines[buffer.row] = graphemes.join("");
+                } else if buffer.row > 0 {
+                    let prev_line = buffer.splice_lines(buffer.row..buffer.row + 1, Vec::new()).concat();
+                    buffer.row -= 1;
+                    buffer.col = buffer.lines[buffer.row].graphemes(true).count();
+                    buffer.lines[buffer.row].push_str(&prev_line);
+                }
+            }
+            KeyCode::Left => buffer.col = buffer.col.saturating_sub(1),
+            KeyCode::Right => buffer.col += 1,
+            KeyCode::Up => buffer.row = buffer.row.saturating_sub(1),
+            KeyCode::Down => buffer.row += 1,
+            KeyCode::Home => buffer.smart_home(),
+            KeyCode::Char(c) => {
+                // FIX: Insert by grapheme.
+                let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                let char_str = c.to_string();
+                // Typing a closer that is already under the cursor just steps over it.
+                if autopairs && matches!(c, ')' | ']' | '}' | '"' | '\'') && graphemes.get(buffer.col) == Some(&char_str.as_str()) {
+                    buffer.col += 1;
+                    return;
+                }
+                // Quotes right after a word character are apostrophes (`don't`), not pairs.
+                let after_word = buffer.col > 0 && grapheme_class(graphemes[buffer.col - 1]) == 1;
+                let closer = pair_closer(c)
+                    .filter(|_| autopairs)
+                    .filter(|_| !(matches!(c, '"' | '\'') && after_word))
+                    .map(|close| close.to_string());
+                if let Some(close) = &closer {
+                    graphemes.insert(buffer.col, close);
+                }
+                graphemes.insert(buffer.col, &char_str);
+                // This is a bit inefficient, but safe.
+                buffer.lines[buffer.row] = graphemes.join("");
+                buffer.col += 1;
+            }
+            _ => buffer.modified = false, // No change for other keys
+        }
+    }
+
+    /// The rectangle spanned by the visual anchor and the cursor.
+    fn visual_block(&self) -> Option<VisualBlock> {
+        let buffer = self.buffers.get(self.active_buffer_index)?;
+        let (anchor_row, anchor_col) = self.visual_anchor;
+        let anchor_row = anchor_row.min(buffer.lines.len() - 1);
+        let anchor_x = display_col_of(&buffer.lines[anchor_row], anchor_col, &self.settings.width);
+        let cursor_x = display_col_of(&buffer.lines[buffer.row], buffer.col, &self.settings.width);
+        Some(VisualBlock {
+            top: anchor_row.min(buffer.row),
+            bottom: anchor_row.max(buffer.row),
+            left: anchor_x.min(cursor_x),
+            right: anchor_x.max(cursor_x),
+        })
+    }
+
+    /// Handles key presses in visual-block mode. Motions move the cursor corner
+    /// through the normal-mode handler; the other keys act on the whole block.
+    fn handle_visual_block_key(&mut self, key_code: KeyCode, modifiers: KeyModifiers) -> Mode {
+        let Some(block) = self.visual_block() else { return Mode::Normal };
+        let motion = is_visual_motion(key_code);
+        let prefix = self.pending_command_prefix;
+        // As in Visual Line, a key that isn't part of a motion ends anything half
+        // typed, so the `z` of `zf` isn't left waiting for the next Normal mode key.
+        if !motion {
+            self.cancel_pending();
+            self.forget_desired_col();
+        }
+        match key_code {
+            KeyCode::Esc => Mode::Normal,
+            KeyCode::Char('v') if modifiers.contains(KeyModifiers::CONTROL) => Mode::Normal,
+            KeyCode::Char('o') => {
+                if let Some(buffer) = self.buffers.get_mut(self.active_buffer_index) {
+                    let cursor = (buffer.row, buffer.col);
+                    (buffer.row, buffer.col) = self.visual_anchor;
+                    self.visual_anchor = cursor;
+                }
+                Mode::VisualBlock
+            }
+            KeyCode::Char('d' | 'x') => {
+                self.delete_block(block);
+                Mode::Normal
+            }
+            KeyCode::Char('y') => {
+                let width = self.settings.width;
+                let Some(buffer) = self.active_buffer() else { return Mode::Normal };
+                let lines = buffer.lines[block.top..=block.bottom]
+                    .iter()
+                    .map(|line| {
+                        let range = block_grapheme_range(line, block.left, block.right, &width);
+                        line.graphemes(true).skip(range.start).take(range.len()).collect()
+                    })
+                    .collect();
+                buffer.row = block.top;
+                buffer.col = block_grapheme_range(&buffer.lines[block.top], block.left, block.left, &width).start;
+                self.registers.yank(Register { lines, kind: RegisterKind::Blockwise });
+                Mode::Normal
+            }
+            KeyCode::Char('c') => {
+                self.delete_block(block);
+                self.start_block_insert(block, block.left, false)
+            }
+            KeyCode::Char('f') if prefix == Some('z') => {
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.folds.push(Fold { start: block.top, end: block.bottom, open: false });
+                    buffer.row = block.top;
+                }
+                Mode::Normal
+            }
+            KeyCode::Char('z') => {
+                self.pending_command_prefix = Some('z');
+                Mode::VisualBlock
+            }
+            KeyCode::Char(':') => {
+                self.visual_marks = Some((block.top, block.bottom));
+                self.command_input = "'<,'>".to_string();
+                self.command_message.clear();
+                Mode::Command
+            }
+            KeyCode::Char('I') => self.start_block_insert(block, block.left, false),
+            KeyCode::Char('A') => self.start_block_insert(block, block.right + 1, true),
+            _ if motion => {
+                self.handle_normal_mode_key(key_code, modifiers);
+                Mode::VisualBlock
+            }
+            _ => Mode::VisualBlock,
+        }
+    }
+
+    /// Rows `first..=last` spanned by the visual anchor and the cursor.
+    fn visual_lines(&self) -> Option<(usize, usize)> {
+        let buffer = self.buffers.get(self.active_buffer_index)?;
+        let anchor = self.visual_anchor.0.min(buffer.lines.len() - 1);
+        Some((anchor.min(buffer.row), anchor.max(buffer.row)))
+    }
+
+    /// Handles key presses in visual-line mode. Motions move the cursor through
+    /// the normal-mode handler; the other keys act on every selected line.
+    fn handle_visual_line_key(&mut self, key_code: KeyCode, modifiers: KeyModifiers) -> Mode {
+        let Some((first, last)) = self.visual_lines() else { return Mode::Normal };
+        let lines = TextRange::rows(first, last);
+        let motion = is_visual_motion(key_code);
+        // A key that isn't part of a motion ends one left half typed, so `gu`
+        // doesn't leave the `g` waiting for the next Normal mode key.
+        if !motion {
+            self.cancel_pending();
+            self.forget_desired_col();
+        }
+        match key_code {
+            KeyCode::Esc => Mode::Normal,
+            KeyCode::Char('V') => Mode::Normal,
+            KeyCode::Char('o') => {
+                if let Some(buffer) = self.buffers.get_mut(self.active_buffer_index) {
+                    let cursor = (buffer.row, buffer.col);
+                    (buffer.row, buffer.col) = self.visual_anchor;
+                    self.visual_anchor = cursor;
+                }
+                Mode::VisualLine
+            }
+            KeyCode::Char('y' | 'Y') => {
+                let Some(buffer) = self.active_buffer() else { return Mode::Normal };
+                let yanked = buffer.lines[first..=last].to_vec();
+                buffer.row = first;
+                buffer.col = buffer.col.min(buffer.lines[first].graphemes(true).count().saturating_sub(1));
+                self.registers.yank(Register { lines: yanked, kind: RegisterKind::Linewise });
+                if last > first {
+                    self.command_message = Message::Info(format!("{} lines yanked", last - first + 1));
+                }
+                Mode::Normal
+            }
+            KeyCode::Char('d' | 'x' | 'D' | 'X') => self.apply_operator(Operator::Delete, lines),
+            KeyCode::Char('c' | 'S' | 'C') => self.apply_operator(Operator::Change, lines),
+            KeyCode::Char('u') => self.apply_operator(Operator::Case(CaseChange::Lower), lines),
+            KeyCode::Char('U') => self.apply_operator(Operator::Case(CaseChange::Upper), lines),
+            KeyCode::Char('~') => self.apply_operator(Operator::Case(CaseChange::Toggle), lines),
+            KeyCode::Char(':') => {
+                self.visual_marks = Some((first, last));
+                self.command_input = "'<,'>".to_string();
+                self.command_message.clear();
+                Mode::Command
+            }
+            _ if motion => {
+                self.handle_normal_mode_key(key_code, modifiers);
+                Mode::VisualLine
+            }
+            _ => Mode::VisualLine,
+        }
+    }
+
+    /// `p` / `P`: puts the unnamed register after / before the cursor, `count`
+    /// times. Whole lines go below / above the cursor line (below a closed fold),
+    /// a block goes in a column from the cursor down, other text into the line.
+    fn put(&mut self, after: bool, count: usize) {
+        let Some(register) = self.registers.get('"').cloned() else {
+            self.command_message = Message::Info("Nothing in register \"".to_string());
+            return;
+        };
+        let width = self.settings.width;
+        let Some(buffer) = self.editable_buffer() else { return };
+        let row = buffer.row;
+        let len = buffer.lines[row].graphemes(true).count();
+        match register.kind {
+            RegisterKind::Linewise => {
+                let at = match after {
+                    true => buffer.closed_fold_at(row).map_or(row, |(_, end)| end) + 1,
+                    false => row,
+                };
+                let lines = register.lines.iter().cycle().take(register.lines.len() * count).cloned().collect();
+                buffer.splice_lines(at..at, lines);
+                buffer.row = at;
+                buffer.col = first_non_blank(&buffer.lines[at]);
+            }
+            RegisterKind::Charwise => {
+                let col = if after { (buffer.col + 1).min(len) } else { buffer.col.min(len) };
+                let text = register.lines.join("\n").repeat(count);
+                let byte = grapheme_byte_range(&buffer.lines[row], col..col).start;
+                let tail = buffer.lines[row].split_off(byte);
+                let mut pieces = text.split('\n');
+                buffer.lines[row].push_str(pieces.next().unwrap_or_default());
+                let rest: Vec<String> = pieces.map(str::to_string).collect();
+                let last = row + rest.len();
+                buffer.splice_lines(row + 1..row + 1, rest);
+                buffer.lines[last].push_str(&tail);
+                // Text within a line leaves the cursor on its last character,
+                // text across lines at its start.
+                buffer.col = match last == row {
+                    true => (col + text.graphemes(true).count()).saturating_sub(1),
+                    false => col,
+                };
+            }
+            RegisterKind::Blockwise => {
+                let cursor = cursor_cells(&buffer.lines[row], buffer.col.min(len), &width);
+                let x = if after && len > 0 { cursor.end } else { cursor.start };
+                let block_width = register.lines.iter().map(|piece| display_width(piece, &width)).max().unwrap_or(0);
+                for (i, piece) in register.lines.iter().enumerate() {
+                    if row + i == buffer.lines.len() {
+                        buffer.lines.push(String::new());
+                    }
+                    let line = &mut buffer.lines[row + i];
+                    let col = insert_col_for_display(line, x, true, &width).unwrap_or(0);
+                    let byte = grapheme_byte_range(line, col..col).start;
+                    // Short pieces are padded so the text after the block stays lined up.
+                    let padding = match byte < line.len() {
+                        true => " ".repeat(block_width - display_width(piece, &width)),
+                        false => String::new(),
+                    };
+                    line.insert_str(byte, &format!("{}{}", piece, padding).repeat(count));
+                }
+                buffer.col = block_grapheme_range(&buffer.lines[row], x, x, &width).start;
+            }
+        }
+        buffer.modified = true;
+    }
+
+    /// Removes the graphemes inside the block from every row and puts the cursor at its top-left.
+    fn delete_block(&mut self, block: VisualBlock) {
+        let width = self.settings.width;
+        let Some(buffer) = self.editable_buffer() else { return };
+        let mut deleted = Vec::new();
+        for row in block.top..=block.bottom {
+            let line = &buffer.lines[row];
+            let range = block_grapheme_range(line, block.left, block.right, &width);
+            let graphemes: Vec<&str> = line.graphemes(true).collect();
+            deleted.push(graphemes[range.clone()].concat());
+            if range.is_empty() {
+                continue;
+            }
+            buffer.lines[row] = format!("{}{}", graphemes[..range.start].concat(), graphemes[range.end..].concat());
+            buffer.modified = true;
+        }
+        buffer.row = block.top;
+        buffer.col = block_grapheme_range(&buffer.lines[block.top], block.left, block.left, &width).start;
+        self.registers.delete(Register { lines: deleted, kind: RegisterKind::Blockwise });
+    }
+
+    /// Enters Insert mode on the top row of the block at `display_col`. What gets
+    /// typed there is copied to the other rows by `finish_block_insert`.
+    fn start_block_insert(&mut self, block: VisualBlock, display_col: usize, pad: bool) -> Mode {
+        let width = self.settings.width;
+        let Some(buffer) = self.editable_buffer() else { return Mode::Normal };
+        let col = insert_col_for_display(&mut buffer.lines[block.top], display_col, pad, &width);
+        buffer.row = block.top;
+        buffer.col = col.unwrap_or_else(|| buffer.lines[block.top].graphemes(true).count());
+        self.block_insert = Some(BlockInsert { top: block.top, bottom: block.bottom, display_col, pad, start_col: buffer.col });
+        Mode::Insert
+    }
+
+    fn finish_block_insert(&mut self, block_insert: BlockInsert) {
+        let width = self.settings.width;
+        let Some(buffer) = self.active_buffer() else { return };
+        // Only text typed on the top row, without leaving it, is repeated.
+        if buffer.row != block_insert.top || buffer.col <= block_insert.start_col {
+            return;
+        }
+        let text: String = buffer.lines[block_insert.top]
+            .graphemes(true)
+            .skip(block_insert.start_col)
+            .take(buffer.col - block_insert.start_col)
+            .collect();
+        for row in block_insert.top + 1..=block_insert.bottom.min(buffer.lines.len() - 1) {
+            let line = &mut buffer.lines[row];
+            if let Some(col) = insert_col_for_display(line, block_insert.display_col, block_insert.pad, &width) {
+                let byte_idx = line.grapheme_indices(true).nth(col).map_or(line.len(), |(i, _)| i);
+                line.insert_str(byte_idx, &text);
+            }
+        }
+        buffer.modified = true;
+    }
+
+    /// `Ctrl-n` / `Ctrl-p`: replaces the word before the cursor with the next (or
+    /// previous) buffer word that starts with it, cycling back to what was typed.
+    fn complete(&mut self, forward: bool) {
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+        if self.completion.as_ref().is_none_or(|c| c.row != buffer.row) {
+            let graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+            let col = buffer.col.min(graphemes.len());
+            let start_col = graphemes[..col].iter().rposition(|g| grapheme_class(g) != 1).map_or(0, |i| i + 1);
+            let prefix = graphemes[start_col..col].concat();
+            let candidates = completion_candidates(&buffer.lines, buffer.row, &prefix);
+            if candidates.is_empty() {
+                self.command_message = Message::Info("No match".to_string());
+                return;
+            }
+            self.completion = Some(Completion { row: buffer.row, start_col, prefix, candidates, index: None });
+        }
+        let Some(completion) = self.completion.as_mut() else { return };
+        let last = completion.candidates.len() - 1;
+        completion.index = match (completion.index, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(last),
+            (Some(i), true) if i < last => Some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+            (Some(_), true) => None,
+        };
+        let text = completion.index.map_or(&completion.prefix, |i| &completion.candidates[i]).clone();
+        let start_col = completion.start_col;
+        if let Some(buffer) = self.active_buffer() {
+            let line = &mut buffer.lines[buffer.row];
+            let range = grapheme_byte_range(line, start_col..buffer.col);
+            line.replace_range(range, &text);
+            buffer.col = start_col + text.graphemes(true).count();
+            buffer.modified = true;
+        }
+    }
+
+    /// Handles key presses in replace mode: typed characters overwrite the grapheme
+    /// under the cursor, and Backspace undoes the overwrites made since entering it.
+    fn handle_replace_mode_key(&mut self, key_code: KeyCode) -> Mode {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer_index) else { return Mode::Replace };
+        match key_code {
+            KeyCode::Esc => {
+                self.replaced_graphemes.clear();
+                return Mode::Normal;
+            }
+            KeyCode::Char(c) => {
+                // Past the end of the line (`virtualedit`) the gap is filled with spaces first.
+                let len = buffer.lines[buffer.row].graphemes(true).count();
+                if buffer.col > len {
+                    buffer.lines[buffer.row].push_str(&" ".repeat(buffer.col - len));
+                }
+                let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                let char_str = c.to_string();
+                if buffer.col < graphemes.len() {
+                    let old = std::mem::replace(&mut graphemes[buffer.col], &char_str);
+                    self.replaced_graphemes.push(Some(old.to_string()));
+                } else {
+                    graphemes.push(&char_str);
+                    self.replaced_graphemes.push(None);
+                }
+                buffer.lines[buffer.row] = graphemes.join("");
+                buffer.col += 1;
+                buffer.modified = true;
+            }
+            KeyCode::Backspace => {
+                if let Some(old) = self.replaced_graphemes.pop() {
+                    buffer.col -= 1;
+                    let mut graphemes: Vec<&str> = buffer.lines[buffer.row].graphemes(true).collect();
+                    match &old {
+                        Some(old) => graphemes[buffer.col] = old,
+                        None => { graphemes.remove(buffer.col); }
+                    }
+                    buffer.lines[buffer.row] = graphemes.join("");
+                } else {
+                    // Before the replaced text Backspace only moves the cursor.
+                    buffer.col = buffer.col.saturating_sub(1);
+                }
+            }
+            KeyCode::Enter => {
+                // A line break is inserted rather than replacing anything, and ends
+                // what Backspace can restore.
+                let line = &mut buffer.lines[buffer.row];
+                let byte_idx = line.grapheme_indices(true).nth(buffer.col).map_or(line.len(), |(i, _)| i);
+                let new_line = line.split_off(byte_idx);
+                buffer.splice_lines(buffer.row + 1..buffer.row + 1, vec![new_line]);
+                buffer.row += 1;
+                buffer.col = 0;
+                buffer.modified = true;
+                self.replaced_graphemes.clear();
+            }
+            KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down => {
+                match key_code {
+                    KeyCode::Left => buffer.col = buffer.col.saturating_sub(1),
+                    KeyCode::Right => buffer.col += 1,
+                    KeyCode::Up => buffer.row = buffer.row.saturating_sub(1),
+                    _ => buffer.row += 1,
+                }
+                self.replaced_graphemes.clear();
+            }
+            _ => {}
+        }
+        Mode::Replace
+    }
+
+    /// Handles key presses in command mode.
+    fn handle_command_mode_key(&mut self, key_code: KeyCode) -> Mode {
+        match key_code {
+            KeyCode::Esc => {
+                self.command_input.clear();
+                self.command_message.clear();
+                self.substitute_preview = None;
+                self.command_prompt = ':';
+                self.search_operator = None;
+                return Mode::Normal;
+            }
+            KeyCode::Enter => {
+                let command = self.command_input.trim().to_string();
+                self.substitute_preview = None;
+                let pattern = self.command_input.clone();
+                let mode = match (std::mem::replace(&mut self.command_prompt, ':'), self.search_operator.take()) {
+                    (':', _) => {
+                        self.execute_command(&command);
+                        Mode::Normal
+                    }
+                    (prompt, None) => {
+                        self.start_search(&pattern, prompt == '/');
+                        Mode::Normal
+                    }
+                    (prompt, Some((op, count))) => match self.set_search(&pattern, prompt == '/') {
+                        true => self.apply_operator_to_search(op, false, count),
+                        false => Mode::Normal,
+                    },
+                };
+                self.command_input.clear();
+                // The command may have changed either side of an open diff.
+                self.refresh_diff();
+                return mode;
+            }
+            KeyCode::Backspace => {
+                self.command_input.pop();
+            }
+            KeyCode::Char(c) => {
+                self.command_input.push(c);
+            }
+            _ => {}
+        }
+        if self.command_prompt != ':' {
+            // Highlight what the search would find while it is typed.
+            self.substitute_preview = Some(self.command_input.clone()).filter(|pattern| !pattern.is_empty());
+            return Mode::Command;
+        }
+        let input = self.command_input.trim();
+        let input = split_range(input).1;
+        self.substitute_preview = parse_substitute(input)
+            .map(|sub| sub.pattern)
+            .or_else(|| parse_global(input).map(|global| global.pattern))
+            .filter(|pattern| !pattern.is_empty());
+        Mode::Command
+    }
+
+    /// Handles key presses in the tree view.
+    fn handle_tree_view_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.selected_item_index = (self.selected_item_index + 1).min(self.tree_items.len().saturating_sub(1));
+            }
             KeyCode::Char('k') | KeyCode::Up => {
                 self.selected_item_index = self.selected_item_index.saturating_sub(1);
             }
-            KeyCode::Enter => {
-                if let Some(selected) = self.tree_items.get(self.selected_item_index) {
-                    let path = selected.path.clone();
-                    if selected.is_dir {
-                        if self.expanded_dirs.contains(&path) {
-                            self.expanded_dirs.remove(&path);
-                        } else {
-                            self.expanded_dirs.insert(path);
+            KeyCode::Enter => {
+                if let Some(selected) = self.tree_items.get(self.selected_item_index) {
+                    let path = selected.path.clone();
+                    if selected.broken {
+                        let target = std::fs::read_link(&path).unwrap_or_default();
+                        self.report_error(EditorError::BrokenLink(path, target));
+                    } else if let Some(target) = &selected.cycle {
+                        let target = target.clone();
+                        self.report_error(EditorError::LinkCycle(path, target));
+                    } else if selected.is_dir {
+                        if self.expanded_dirs.contains(&path) {
+                            self.expanded_dirs.remove(&path);
+                        } else {
+                            self.expanded_dirs.insert(path);
+                        }
+                        self.tree_dirty = true;
+                    } else {
+                        self.open_file(path);
+                        self.tree_view_active = false;
+                    }
+                }
+            }
+            KeyCode::Char('r') => self.tree_dirty = true,
+            // Makes the selected directory (a file's own directory) the working
+            // directory and the tree root, as `:cd` does.
+            KeyCode::Char('C') => {
+                if let Some(selected) = self.tree_items.get(self.selected_item_index) {
+                    let dir = match selected.is_dir {
+                        true => selected.path.clone(),
+                        false => selected.path.parent().map_or_else(|| self.current_path.clone(), Path::to_path_buf),
+                    };
+                    if let Err(e) = self.change_directory(&dir) {
+                        self.report_error(e);
+                    }
+                }
+            }
+            KeyCode::Char('R') => {
+                if let Some(selected) = self.tree_items.get(self.selected_item_index) {
+                    let name = selected.path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+                    let action = PromptAction::Rename(selected.path.clone());
+                    self.prompt_text("Rename to: ", &name, action);
+                }
+            }
+            KeyCode::Tab | KeyCode::Esc => {
+                self.tree_view_active = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Recursively gets items for the directory tree. `ancestors` holds the real
+    /// paths of the directories being listed, so links back to them aren't followed.
+    fn get_tree_items(&self, path: &PathBuf, prefix: String, ancestors: &mut Vec<PathBuf>) -> Vec<TreeItem> {
+        let mut items = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(path) {
+            let mut dirs = Vec::new();
+            let mut files = Vec::new();
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if path.is_dir() { dirs.push(path); } else { files.push(path); }
+            }
+            dirs.sort();
+            files.sort();
+
+            for item_path in dirs.into_iter().chain(files) {
+                let is_dir = item_path.is_dir();
+                let symlink = item_path.is_symlink();
+                let broken = symlink && !item_path.exists();
+                let target = if is_dir { item_path.canonicalize().ok() } else { None };
+                let cycle = target.clone().filter(|target| symlink && ancestors.contains(target));
+                let expand = is_dir && cycle.is_none() && self.expanded_dirs.contains(&item_path);
+                items.push(TreeItem { path: item_path.clone(), prefix: prefix.clone(), is_dir, symlink, broken, cycle });
+                if expand {
+                    ancestors.push(target.unwrap_or_else(|| item_path.clone()));
+                    items.extend(self.get_tree_items(&item_path, format!("{}  ", prefix), ancestors));
+                    ancestors.pop();
+                }
+            }
+        }
+        items
+    }
+
+    /// Expands the directories from the tree root down to `path` and selects it.
+    /// Returns false, changing nothing, if `path` is outside the tree.
+    fn reveal_in_tree(&mut self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.current_path) else { return false };
+        let mut dir = self.current_path.clone();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            self.expanded_dirs.insert(dir.clone());
+        }
+        self.update_tree_items();
+        if let Some(index) = self.tree_items.iter().position(|item| item.path == path) {
+            self.selected_item_index = index;
+        }
+        true
+    }
+
+    /// `:tf`: shows the tree with the active buffer's file selected. A file outside
+    /// the tree roots the tree at the closest directory containing both.
+    fn reveal_active_file(&mut self) {
+        let Some(file) = self.buffers.get(self.active_buffer_index).and_then(|b| b.filename.as_deref()) else {
+            self.command_message = Message::Info("No file name".to_string());
+            return;
+        };
+        let Ok(file) = file.canonicalize().or_else(|_| std::path::absolute(file)) else { return };
+        if !file.starts_with(&self.current_path) {
+            let root = self.current_path.ancestors().find(|dir| file.starts_with(dir)).unwrap_or(Path::new("/"));
+            self.current_path = root.to_path_buf();
+            self.expanded_dirs.insert(self.current_path.clone());
+        }
+        self.tree_visible = true;
+        self.tree_view_active = true;
+        if !self.reveal_in_tree(&file) || self.tree_items.get(self.selected_item_index).is_none_or(|item| item.path != file) {
+            self.command_message = Message::Info(format!("{} is not in the tree", file.display()));
+        }
+    }
+
+    fn update_tree_items(&mut self) {
+        let root = self.current_path.canonicalize().unwrap_or_else(|_| self.current_path.clone());
+        self.tree_items = self.get_tree_items(&self.current_path, String::new(), &mut vec![root]);
+        self.tree_dirty = false;
+        self.selected_item_index = self.selected_item_index.min(self.tree_items.len().saturating_sub(1));
+        // The files may have changed under the tree, and under the buffers too.
+        self.check_deleted_files();
+    }
+
+    /// Marks buffers whose file has gone from disk since it was read or written,
+    /// keeping their text, and says how to write it back. A file that comes back
+    /// clears the mark.
+    fn check_deleted_files(&mut self) {
+        let mut deleted = Vec::new();
+        for buffer in &mut self.buffers {
+            // Only files that were on disk; a new file isn't written yet.
+            let Some(path) = buffer.filename.as_ref().filter(|_| buffer.baseline.is_some() && buffer.loading.is_none()) else { continue };
+            match (buffer.deleted_on_disk, path.exists()) {
+                (false, false) => {
+                    buffer.deleted_on_disk = true;
+                    buffer.modified = true;
+                    deleted.push(path.display().to_string());
+                }
+                (true, true) => buffer.deleted_on_disk = false,
+                _ => {}
+            }
+        }
+        if !deleted.is_empty() {
+            self.command_message = Message::Info(format!("{} deleted on disk; the text is kept, :w writes it back", deleted.join(", ")));
+        }
+    }
+
+    /// Splits the screen into tree, separator, text, scroll indicators and status
+    /// areas. Rendering and scroll computation both use this so they always agree.
+    fn layout(&self, area: Rect) -> EditorLayout {
+        let (tree, separator, editor_area) = if self.tree_visible {
+            let chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([
+                    Constraint::Length(self.tree_width), // Tree
+                    Constraint::Length(1),               // Separator
+                    Constraint::Min(0),                  // Editor
+                ])
+                .split(area);
+            (Some(chunks[0]), Some(chunks[1]), chunks[2])
+        } else {
+            (None, None, area)
+        };
+
+        let hscrollbar_height = u16::from(self.settings.hscrollbar);
+        let editor_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(hscrollbar_height), Constraint::Length(2)].as_ref())
+            .split(editor_area);
+
+        let mut text = editor_chunks[0];
+        let vscrollbar = if self.settings.scrollbar && text.width > 1 {
+            text.width -= 1;
+            Some(Rect::new(text.x + text.width, text.y, 1, text.height))
+        } else {
+            None
+        };
+        let hscrollbar = self.settings.hscrollbar.then_some(editor_chunks[1]);
+
+        EditorLayout { tree, separator, text, vscrollbar, hscrollbar, status: editor_chunks[2] }
+    }
+
+    /// Draws a one-column bar whose thumb shows which part of the buffer is on screen.
+    fn draw_vertical_scrollbar(&self, f: &mut Frame, area: Rect, buffer: &Buffer) {
+        let height = area.height as usize;
+        let total = buffer.lines.len().max(height).max(1);
+        let thumb_len = (height * height / total).max(1);
+        let thumb_start = (buffer.top_row * height / total).min(height - thumb_len.min(height));
+        for (i, y) in (area.y..area.y + area.height).enumerate() {
+            let in_thumb = i >= thumb_start && i < thumb_start + thumb_len;
+            let cell = f.buffer_mut().get_mut(area.x, y);
+            cell.set_symbol(if in_thumb { "┃" } else { "│" });
+            cell.set_style(Style::default().fg(if in_thumb { Color::Gray } else { Color::DarkGray }));
+        }
+    }
+
+    /// Draws a one-row bar whose thumb shows how far right the view is scrolled,
+    /// relative to the widest line on screen.
+    fn draw_horizontal_scrollbar(&self, f: &mut Frame, area: Rect, buffer: &Buffer, gutter_width: usize, text_height: usize) {
+        let visible_rows = buffer.top_row..(buffer.top_row + text_height).min(buffer.lines.len());
+        let widest = buffer.lines[visible_rows].iter().map(|l| display_width(l, &self.settings.width)).max().unwrap_or(0);
+        let track = (area.width as usize).saturating_sub(gutter_width);
+        if track == 0 {
+            return;
+        }
+        let total = widest.max(self.scroll_offset_col + track).max(1);
+        let thumb_len = (track * track / total).max(1);
+        let thumb_start = (self.scroll_offset_col * track / total).min(track - thumb_len.min(track));
+        let label = format!("{:>width$} ", format!("+{}", self.scroll_offset_col), width = gutter_width.saturating_sub(1));
+        let bar: String = (0..track).map(|i| if i >= thumb_start && i < thumb_start + thumb_len { '━' } else { '─' }).collect();
+        let line = Line::from(vec![
+            Span::styled(label, Style::default().fg(Color::DarkGray)),
+            Span::styled(bar, Style::default().fg(Color::DarkGray)),
+        ]);
+        f.render_widget(Paragraph::new(line), area);
+    }
+
+    fn draw_tree_view(&self, f: &mut Frame, area: Rect) {
+        let tree_block = tree_block(tree_breadcrumb(&self.current_path, area.width as usize));
+        let inner_area = tree_block.inner(area);
+        let mut lines = Vec::new();
+
+        for (i, item) in self.tree_items.iter().enumerate().skip(self.tree_scroll_pos) {
+            if i >= self.tree_scroll_pos + inner_area.height as usize { break; }
+            let indicator = match item.is_dir {
+                true if item.cycle.is_some() => "[~]",
+                true if self.expanded_dirs.contains(&item.path) => "[-]",
+                true => "[+]",
+                false if item.broken => " ! ",
+                false => "   ",
+            };
+            // Links are marked with `@`, as by `ls -F`; broken ones are red.
+            let display_text = format!(
+                "{}{}{}{}",
+                item.prefix,
+                indicator,
+                item.path.file_name().unwrap_or_default().to_string_lossy(),
+                if item.symlink { "@" } else { "" }
+            );
+            let mut line = Line::from(display_text);
+            if item.broken {
+                line = line.style(Style::default().fg(Color::Red));
+            }
+            if i == self.selected_item_index {
+                line = line.patch_style(Style::default().bg(Color::DarkGray));
+            }
+            lines.push(line);
+        }
+        let paragraph = Paragraph::new(lines).block(tree_block);
+        f.render_widget(paragraph, area);
+    }
+
+    /// Main UI drawing function.
+    fn ui(&mut self, f: &mut Frame) {
+        // --- Layouts ---
+        let layout = self.layout(f.size());
+        let text_buffer_area = layout.text;
+        let status_area = layout.status;
+
+        // --- Widgets ---
+        if let (Some(tree_area), Some(separator_area)) = (layout.tree, layout.separator) {
+            self.draw_tree_view(f, tree_area);
+            for y in separator_area.y..separator_area.y + separator_area.height.saturating_sub(2) {
+                 f.buffer_mut().get_mut(separator_area.x, y).set_symbol("│");
+            }
+        }
+
+        if let Some(diff) = &self.diff {
+            self.draw_diff(f, text_buffer_area, diff);
+        } else if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+            let sign_width = buffer.sign_column_width(self.settings.signcolumn);
+            let line_num_width = buffer.gutter_width(&self.settings);
+            let mut buffer_content: Vec<Line> = Vec::new();
+            let visual_block = if self.mode == Mode::VisualBlock { self.visual_block() } else { None };
+            let visual_lines = if self.mode == Mode::VisualLine { self.visual_lines() } else { None };
+            let spell_words = self.active_spell_words();
+            // A pattern still being typed may not compile yet; it just shows no matches.
+            let preview = self.substitute_preview.as_ref().and_then(|pattern| self.settings.regex(pattern).ok());
+            let search = self.search.as_ref()
+                .filter(|_| self.search_highlight)
+                .and_then(|search| Some((search, self.settings.search_regex(search).ok()?)));
+
+            for i in buffer.screen_rows(buffer.top_row, text_buffer_area.height as usize) {
+                let line = &buffer.lines[i];
+                let line_number_str = format!("{:>width$}", i + 1, width = line_num_width - sign_width - 1);
+                let line_number_span = Span::styled(format!("{} ", line_number_str), Style::default().fg(Color::DarkGray));
+                let mut spans = vec![line_number_span];
+                if sign_width > 0 {
+                    spans.push(match (buffer.signs.get(&i), buffer.change_marks.get(&i)) {
+                        (Some(sign), _) => Span::styled(sign.symbol.to_string(), sign.style),
+                        (None, Some(&mark)) => {
+                            let style = match mark {
+                                '+' => self.ui_style.change_added_style,
+                                '~' => self.ui_style.change_changed_style,
+                                _ => self.ui_style.change_removed_style,
+                            };
+                            Span::styled(mark.to_string(), style)
+                        }
+                        (None, None) => Span::raw(" "),
+                    });
+                }
+                if let Some((start, end)) = buffer.closed_fold_at(i) {
+                    let summary = format!("+-- {} lines: {}", end - start + 1, line.trim());
+                    spans.extend(scroll_spans(vec![Span::styled(summary, self.ui_style.fold_style)], self.scroll_offset_col, &self.settings.width));
+                    buffer_content.push(Line::from(spans));
+                    continue;
+                }
+                // Lowest layer first: spelling, then search matches (or the
+                // `:s` pattern being typed in their place), then the selection.
+                let mut highlights: Vec<(Range<usize>, Style)> = Vec::new();
+                if let Some(words) = spell_words {
+                    highlights.extend(misspelled_words(line, words).into_iter().map(|range| (range, self.ui_style.spell_error_style)));
+                }
+                let matches = match (&preview, &search) {
+                    (Some(regex), _) => find_matches(line, regex),
+                    (None, Some((search, regex))) => search_matches(line, search, regex),
+                    (None, None) => Vec::new(),
+                };
+                highlights.extend(matches.into_iter().map(|range| (range, self.ui_style.search_result_style)));
+                if let Some(block) = visual_block.filter(|block| (block.top..=block.bottom).contains(&i)) {
+                    let selected = grapheme_byte_range(line, block_grapheme_range(line, block.left, block.right, &self.settings.width));
+                    highlights.push((selected, self.ui_style.selection_style));
+                }
+                if visual_lines.is_some_and(|(first, last)| (first..=last).contains(&i)) {
+                    highlights.push((0..line.len(), self.ui_style.selection_style));
+                }
+                // The gutter stays put while the text scrolls sideways.
+                spans.extend(scroll_spans(highlight_spans(line, &highlights), self.scroll_offset_col, &self.settings.width));
+                buffer_content.push(Line::from(spans));
+            }
+
+            let paragraph = Paragraph::new(buffer_content);
+            f.render_widget(paragraph, text_buffer_area);
+
+            if let Some(area) = layout.vscrollbar {
+                self.draw_vertical_scrollbar(f, area, buffer);
+            }
+            if let Some(area) = layout.hscrollbar {
+                self.draw_horizontal_scrollbar(f, area, buffer, line_num_width, text_buffer_area.height as usize);
+            }
+            if let Some(popup) = &self.spell_popup {
+                let lines = popup.suggestions.iter().enumerate().map(|(i, w)| Line::from(format!("{} {}", i + 1, w))).collect();
+                let col = buffer.lines[popup.row][..popup.range.start].graphemes(true).count();
+                let position = self.screen_position(text_buffer_area, buffer, line_num_width, popup.row, col);
+                self.draw_popup(f, text_buffer_area, position, "Change to", lines);
+            }
+            if let Some(choices) = &self.tag_choices {
+                let lines = choices.iter().enumerate().map(|(i, entry)| {
+                    let file = entry.file.strip_prefix(&self.current_path).unwrap_or(&entry.file);
+                    let location = match &entry.address {
+                        TagAddress::Line(line) => format!("line {}", line),
+                        TagAddress::Pattern(pattern) => pattern.trim_start_matches('^').trim_end_matches('$').trim().to_string(),
+                    };
+                    Line::from(format!("{} {}: {}", i + 1, file.display(), location))
+                }).collect();
+                let position = self.screen_position(text_buffer_area, buffer, line_num_width, buffer.row, buffer.col);
+                self.draw_popup(f, text_buffer_area, position, "Definitions", lines);
+            }
+            if let Some(completion) = &self.completion {
+                // Show at most ten candidates, scrolled to keep the current one in view.
+                let first = completion.index.map_or(0, |i| i.saturating_sub(9));
+                let lines = completion.candidates.iter().enumerate().skip(first).take(10).map(|(i, w)| {
+                    let style = if completion.index == Some(i) { self.ui_style.selection_style } else { Style::default() };
+                    Line::styled(w.as_str(), style)
+                }).collect();
+                let position = self.screen_position(text_buffer_area, buffer, line_num_width, completion.row, completion.start_col);
+                self.draw_popup(f, text_buffer_area, position, "", lines);
+            }
+            if let Some(rows) = &self.registers_popup {
+                let lines = rows.iter().map(|row| Line::from(row.as_str())).collect();
+                let bottom_left = (text_buffer_area.x, text_buffer_area.bottom());
+                self.draw_popup(f, text_buffer_area, bottom_left, "Registers", lines);
+            }
+        } else {
+            let placeholder = Paragraph::new("No buffer. Open a file with :e <filename>")
+                .style(Style::default().fg(Color::DarkGray))
+                .alignment(Alignment::Center);
+            let middle = text_buffer_area.height / 2;
+            f.render_widget(placeholder, Rect::new(text_buffer_area.x, text_buffer_area.y + middle, text_buffer_area.width, 1).intersection(text_buffer_area));
+        }
+
+        let (status_left, status_right) = if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+            let filename = buffer.filename.as_ref().map_or("[No Name]".to_string(), |p| p.display().to_string());
+            let modified_str = if buffer.modified { "[+]" } else { "" };
+            let binary_str = match (buffer.binary, buffer.deleted_on_disk) {
+                (true, _) => "[binary]",
+                (false, true) => "[deleted]",
+                (false, false) => "",
+            };
+            let loading_str = buffer.loading.as_ref().map_or(String::new(), |l| format!("[loading, {} lines]", l.received));
+            let left = format!("-- {} -- {} {}{}{}", self.mode_str(), filename, binary_str, loading_str, modified_str);
+            let right = match self.settings.ruler {
+                true => ruler(buffer, &self.settings.width),
+                false => format!("{}:{}", buffer.row + 1, buffer.col + 1),
+            };
+            (left, right)
+        } else {
+            (format!("-- {} --", self.mode_str()), String::new())
+        };
+
+        let status_bar = Paragraph::new(Line::from(vec![
+            Span::raw(&status_left),
+            Span::raw(" ".repeat(status_area.width.saturating_sub(status_left.len() as u16 + status_right.len() as u16) as usize)),
+            Span::raw(&status_right),
+        ])).style(Style::default().fg(Color::White).bg(Color::DarkGray));
+        // Clip to the status area so a terminal shorter than two rows doesn't draw off-screen.
+        f.render_widget(status_bar, Rect::new(status_area.x, status_area.y, status_area.width, 1).intersection(status_area));
+
+        let (command_line_text, command_line_style) = if let Some(prompt) = &self.prompt {
+            match &prompt.kind {
+                PromptKind::YesNo | PromptKind::Choice(_) => (prompt.message.clone(), Style::default()),
+                PromptKind::Text(input) => (format!("{}{}", prompt.message, input), Style::default()),
+            }
+        } else if self.mode == Mode::Command {
+            (format!("{}{}", self.command_prompt, self.command_input), Style::default())
+        } else if let Message::Error(error) = &self.command_message {
+            (error.to_string(), self.ui_style.error_message_style)
+        } else {
+            (self.command_message.to_string(), Style::default())
+        };
+        let command_line = Paragraph::new(command_line_text).style(command_line_style);
+        f.render_widget(command_line, Rect::new(status_area.x, status_area.y + 1, status_area.width, 1).intersection(status_area));
+
+        // Like Vim's `showcmd`, the keys of an unfinished command go at the right.
+        let pending_keys = match (&self.mode, &self.literal_input) {
+            (Mode::Insert, Some(code)) => format!("^V{}", code),
+            (Mode::Normal | Mode::VisualBlock | Mode::VisualLine, _) => self.pending_keys(),
+            _ => String::new(),
+        };
+        if !pending_keys.is_empty() && self.prompt.is_none() {
+            let width = (pending_keys.width() as u16).min(status_area.width);
+            let area = Rect::new(status_area.right() - width, status_area.y + 1, width, 1).intersection(status_area);
+            f.render_widget(Clear, area);
+            f.render_widget(Paragraph::new(pending_keys).alignment(Alignment::Right), area);
+        }
+
+        if !self.settings.termguicolors {
+            downgrade_rgb_colors(f.buffer_mut());
+        }
+
+        // --- Cursor ---
+        if !self.extra_cursors.is_empty() && !self.tree_view_active && self.diff.is_none() {
+            if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+                let line_num_width = buffer.gutter_width(&self.settings);
+                let rows = buffer.screen_rows(buffer.top_row, text_buffer_area.height as usize);
+                for &(row, col) in &self.extra_cursors {
+                    if row >= buffer.lines.len() || !rows.contains(&buffer.screen_row_start(row)) {
+                        continue;
+                    }
+                    let (x, y) = self.screen_position(text_buffer_area, buffer, line_num_width, row, col);
+                    if x < text_buffer_area.right() {
+                        f.buffer_mut().get_mut(x, y).set_style(self.ui_style.extra_cursor_style);
+                    }
+                }
+            }
+        }
+        if let Some(Prompt { message, kind: PromptKind::Text(input), .. }) = &self.prompt {
+            let x = status_area.x + (message.width() + input.width()) as u16;
+            f.set_cursor(x.min(status_area.right().saturating_sub(1)), status_area.y + 1);
+        } else if self.mode != Mode::Command && !self.tree_view_active && self.diff.is_none() {
+            if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+                let line_num_width = buffer.gutter_width(&self.settings);
+                // FIX: Calculate cursor X position based on the visual width of graphemes.
+                let (cursor_x, cursor_y) = self.screen_position(text_buffer_area, buffer, line_num_width, buffer.row, buffer.col);
+                f.set_cursor(cursor_x, cursor_y);
+            }
+        }
+    }
+
+    /// The count, operator and prefix typed so far for a command that isn't complete.
+    fn pending_keys(&self) -> String {
+        let mut keys = self.pending_count.map_or(String::new(), |count| count.to_string());
+        if let Some(op) = self.pending_operator {
+            keys.push_str(op.keys());
+        }
+        if let Some(inner) = self.pending_text_object {
+            keys.push(if inner { 'i' } else { 'a' });
+        }
+        if let Some(prefix) = self.pending_command_prefix {
+            keys.push(prefix);
+        }
+        keys
+    }
+
+    /// Screen cell of grapheme `col` on `row` of `buffer`, drawn in `text_area`
+    /// after a gutter `gutter_width` columns wide.
+    fn screen_position(&self, text_area: Rect, buffer: &Buffer, gutter_width: usize, row: usize, col: usize) -> (u16, u16) {
+        let display_col = display_col_of(&buffer.lines[row], col, &self.settings.width);
+        let x = text_area.x + gutter_width as u16 + (display_col as u16).saturating_sub(self.scroll_offset_col as u16);
+        let screen_row = buffer.screen_row_start(row);
+        let rows = buffer.screen_rows(buffer.top_row, text_area.height as usize);
+        let offset = rows.iter().position(|&r| r == screen_row).unwrap_or(row.saturating_sub(buffer.top_row));
+        let y = text_area.y + offset as u16;
+        (x, y)
+    }
+
+    /// Draws a box of `lines` below the screen cell `(x, y)` (above it near the
+    /// bottom of the text area), for `z=` suggestions and completion candidates.
+    fn draw_popup(&self, f: &mut Frame, text_area: Rect, (x, y): (u16, u16), title: &str, lines: Vec<Line>) {
+        let width = lines.iter().map(Line::width).max().unwrap_or(0).max(title.len()) as u16 + 2;
+        let height = lines.len() as u16 + 2;
+        let y = if y + 1 + height <= text_area.bottom() { y + 1 } else { y.saturating_sub(height) };
+        let x = x.min(text_area.right().saturating_sub(width));
+        let area = Rect::new(x, y, width, height).intersection(text_area);
+        f.render_widget(Clear, area);
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+
+    /// Draws the two buffers of `diff` side by side, each with its own line numbers.
+    fn draw_diff(&self, f: &mut Frame, area: Rect, diff: &DiffView) {
+        let (Some(left), Some(right)) = (self.buffers.get(diff.left), self.buffers.get(diff.right)) else { return };
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Ratio(1, 2), Constraint::Length(1), Constraint::Ratio(1, 2)])
+            .split(area);
+        for y in panes[1].y..panes[1].bottom() {
+            f.buffer_mut().get_mut(panes[1].x, y).set_symbol("│");
+        }
+        for (pane, buffer, is_left) in [(panes[0], left, true), (panes[2], right, false)] {
+            let number_width = buffer.lines.len().to_string().len() + 1;
+            let text_width = (pane.width as usize).saturating_sub(number_width + 1);
+            let lines: Vec<Line> = diff.rows.iter().enumerate().skip(diff.top).take(pane.height as usize).map(|(i, row)| {
+                let number_style = if i == diff.cursor { Style::default().fg(Color::Yellow) } else { Style::default().fg(Color::DarkGray) };
+                let Some(index) = (if is_left { row.left } else { row.right }) else {
+                    return Line::from(vec![
+                        Span::raw(" ".repeat(number_width + 1)),
+                        Span::styled("-".repeat(text_width), self.ui_style.diff_filler_style),
+                    ]);
+                };
+                let text = &buffer.lines[index];
+                let style = match row.kind {
+                    DiffKind::Equal => Style::default(),
+                    DiffKind::Added => self.ui_style.diff_added_style,
+                    DiffKind::Removed => self.ui_style.diff_removed_style,
+                    DiffKind::Changed => self.ui_style.diff_changed_style,
+                };
+                // Pad so the background covers the whole row.
+                let padding = " ".repeat(text_width.saturating_sub(display_width(text, &self.settings.width)));
+                Line::from(vec![
+                    Span::styled(format!("{:>width$} ", index + 1, width = number_width), number_style),
+                    Span::styled(format!("{}{}", text, padding), style),
+                ])
+            }).collect();
+            f.render_widget(Paragraph::new(lines), pane);
+        }
+    }
+
+    fn mode_str(&self) -> &str {
+        if self.diff.is_some() && self.mode == Mode::Normal {
+            return "DIFF";
+        }
+        match self.mode {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Replace => "REPLACE",
+            Mode::VisualBlock => "VISUAL BLOCK",
+            Mode::VisualLine => "VISUAL LINE",
+            Mode::Command => "COMMAND",
+        }
+    }
+
+    /// What the alias `name` stands for: an `:alias`, else a built-in one.
+    fn alias(&self, name: &str) -> Option<&str> {
+        self.aliases.get(name).map(String::as_str)
+            .or_else(|| BUILTIN_ALIASES.iter().find(|(alias, _)| *alias == name).map(|(_, expansion)| *expansion))
+    }
+
+    /// Replaces the alias that `command` starts with, if any, by what it stands
+    /// for, keeping the arguments after it. Repeats while the result starts with
+    /// an alias too, failing if one comes around again.
+    fn expand_alias(&self, command: &str) -> Result<String, EditorError> {
+        let mut command = command.to_string();
+        let mut seen: Vec<String> = Vec::new();
+        loop {
+            let name = command.split_whitespace().next().unwrap_or("");
+            let Some(expansion) = self.alias(name) else { return Ok(command) };
+            if seen.iter().any(|alias| alias == name) {
+                return Err(EditorError::RecursiveAlias(name.to_string()));
+            }
+            seen.push(name.to_string());
+            command = format!("{}{}", expansion, &command[name.len()..]);
+        }
+    }
+
+    fn execute_command(&mut self, command: &str) {
+        // Commands that edit or move check for a buffer themselves, so the rest
+        // (`:q`, `:e`, `:set`, ...) still work without one.
+        let (cursor, last) = self.buffers.get(self.active_buffer_index).map_or((0, 0), |buffer| (buffer.row, buffer.lines.len() - 1));
+        let (range, command) = split_range(command);
+        let expanded = match self.expand_alias(command.trim_start()) {
+            Ok(command) => command,
+            Err(e) => return self.report_error(e),
+        };
+        // Without a typed range, the alias may bring its own (`:alias D 1d`).
+        let (range, command) = match range.is_empty() {
+            true => split_range(&expanded),
+            false => (range, expanded.as_str()),
+        };
+        let command = command.trim_start();
+        let command_rows = match resolve_range(range, cursor, last, self.visual_marks) {
+            Ok(rows) => rows,
+            Err(e) => return self.report_error(e),
+        };
+        // Substitutions are parsed whole since their pattern may contain spaces.
+        if let Some(sub) = parse_substitute(command) {
+            let (first, last) = command_rows.unwrap_or((cursor, cursor));
+            self.substitute(&sub, first, last);
+            return;
+        }
+        if let Some(global) = parse_global(command) {
+            self.global(&global, command_rows.unwrap_or((0, last)));
+            return;
+        }
+        if let Some(shell) = command.strip_prefix('!') {
+            match command_rows {
+                Some((first, last)) => self.filter_lines(first, last, shell.trim()),
+                None => self.start_shell_command(shell, ShellTarget::Scratch),
+            }
+            return;
+        }
+        if let Some(shell) = command.strip_prefix('r').map(str::trim_start).and_then(|rest| rest.strip_prefix('!')) {
+            let row = command_rows.map_or(cursor, |(_, last)| last);
+            let target = ShellTarget::InsertBelow { buffer: self.active_buffer_index, row, removed: self.removed_buffers.len() };
+            self.start_shell_command(shell, target);
+            return;
+        }
+        // Line commands may run into their address (`:m$`, `:t0`).
+        let name = &command[..command.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(command.len())];
+        if matches!(name, "d" | "delete" | "y" | "yank" | "t" | "co" | "copy" | "m" | "move") {
+            let (first, last) = command_rows.unwrap_or((cursor, cursor));
+            if let Err(e) = self.line_command(name, command[name.len()..].trim(), first, last) {
+                self.report_error(e);
+            }
+            return;
+        }
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        let Some(&cmd) = parts.first() else {
+            // A range alone (`:42`, `:$`) goes to its last line.
+            if let Some((_, last)) = command_rows {
+                self.push_jump();
+                self.goto_line(last + 1);
+            }
+            return;
+        };
+        let args = &parts[1..];
+        if command_rows.is_some() && !matches!(cmd, "sort" | "sort!") {
+            return self.report_error(EditorError::NoRangeAllowed(cmd.to_string()));
+        }
+
+        match cmd {
+            "q" => {
+                if let Some(b) = self.buffers.get(self.active_buffer_index) {
+                    if b.modified {
+                        self.prompt_yes_no("Unsaved changes. Quit anyway? (y/n)", PromptAction::Quit);
+                        return;
+                    }
+                }
+                self.should_exit = true;
+            }
+            "q!" => self.should_exit = true,
+            "w" => {
+                if let Err(e) = self.save_file(args.first().map(PathBuf::from)) {
+                    self.report_error(e);
+                }
+            }
+            "wq" => {
+                match self.save_file(args.first().map(PathBuf::from)) {
+                    Ok(()) => {
+                        if let Some(b) = self.buffers.get(self.active_buffer_index) {
+                            if !b.modified { self.should_exit = true; }
+                        }
+                    }
+                    Err(e) => self.report_error(e),
+                }
+            }
+            "registers" | "reg" | "display" | "di" => self.show_registers(),
+            "nohlsearch" | "noh" => self.search_highlight = false,
+            "cd" => {
+                // Like Vim, `:cd` alone goes to the home directory.
+                let target = match args.first() {
+                    Some(path) => PathBuf::from(path),
+                    None => std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/")),
+                };
+                if let Err(e) = self.change_directory(&target) {
+                    self.report_error(e);
+                }
+            }
+            "mksession" | "mks" => {
+                let path = PathBuf::from(args.first().copied().unwrap_or(DEFAULT_SESSION_FILE));
+                match self.make_session(&path) {
+                    Ok(count) => self.command_message = Message::Info(format!("Session with {} files written to {}", count, path.display())),
+                    Err(e) => self.report_error(e),
+                }
+            }
+            "source" | "so" => {
+                let path = PathBuf::from(args.first().copied().unwrap_or(DEFAULT_SESSION_FILE));
+                if let Err(e) = self.source_session(&path) {
+                    self.report_error(e);
+                }
+            }
+            "pwd" => match std::env::current_dir() {
+                Ok(dir) => self.command_message = Message::Info(dir.display().to_string()),
+                Err(source) => self.report_error(EditorError::Read { path: PathBuf::from("."), source }),
+            },
+            "checktime" | "checkt" => {
+                self.command_message.clear();
+                self.check_deleted_files();
+            }
+            "sort" | "sort!" => {
+                let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+                let (first, last) = command_rows.unwrap_or((0, buffer.lines.len() - 1));
+                let flags: String = args.concat();
+                if let Some(flag) = flags.chars().find(|c| !matches!(c, 'n' | 'u')) {
+                    self.command_message = Message::Info(format!("Invalid flag for :sort: {}", flag));
+                    return;
+                }
+                self.sort_lines(first, last, cmd == "sort!", flags.contains('n'), flags.contains('u'));
+            }
+            "e!" if args.is_empty() => {
+                if let Err(e) = self.reload_buffer(false) {
+                    self.report_error(e);
+                }
+            }
+            "e" | "e!" => {
+                if let Some(filename_str) = args.first() {
+                    self.open_file(PathBuf::from(filename_str));
+                } else {
+                    self.command_message = Message::Info("Filename needed for :e".to_string());
+                }
+            }
+            "bn" => {
+                if !self.buffers.is_empty() {
+                    self.switch_to_buffer((self.active_buffer_index + 1) % self.buffers.len());
+                }
+            }
+            "bp" => {
+                if !self.buffers.is_empty() {
+                    self.switch_to_buffer((self.active_buffer_index + self.buffers.len() - 1) % self.buffers.len());
+                }
+            }
+            "bd" | "bdelete" | "bd!" | "bdelete!" => self.close_buffer(cmd.ends_with('!')),
+            "bun" | "bundo" => self.reopen_closed_buffer(),
+            "tt" => {
+                self.tree_visible = !self.tree_visible;
+                if !self.tree_visible { self.tree_view_active = false; }
+                // The filesystem may have changed while the tree was hidden.
+                self.tree_dirty = true;
+            }
+            "tf" => self.reveal_active_file(),
+            "wc" => self.report_word_count(),
+            "diff" => match args.first() {
+                Some(file) => self.start_diff(PathBuf::from(file)),
+                None => self.command_message = Message::Info("Filename needed for :diff".to_string()),
+            },
+            "diffoff" => self.diff = None,
+            "sign" => {
+                let Some(row) = self.buffers.get(self.active_buffer_index).map(|b| b.row) else { return };
+                match args.first().map(|a| a.chars().collect::<Vec<_>>()) {
+                    Some(chars) if chars.len() == 1 => {
+                        let style = match chars[0] {
+                            'E' => Style::default().fg(Color::Red),
+                            'W' => Style::default().fg(Color::Yellow),
+                            '+' => Style::default().fg(Color::Green),
+                            _ => Style::default(),
+                        };
+                        self.set_sign(row, chars[0], style);
+                    }
+                    Some(_) => self.command_message = Message::Info("A sign is a single character".to_string()),
+                    None => self.clear_sign(row),
+                }
+            }
+            "alias" => {
+                let rest = command[cmd.len()..].trim();
+                match rest.split_once(char::is_whitespace) {
+                    Some((name, expansion)) => {
+                        self.aliases.insert(name.to_string(), expansion.trim().to_string());
+                    }
+                    None if rest.is_empty() => {
+                        let builtin = BUILTIN_ALIASES.iter().filter(|(name, _)| !self.aliases.contains_key(*name)).copied();
+                        let user = self.aliases.iter().map(|(n, e)| (n.as_str(), e.as_str()));
+                        self.command_message = Message::Info(builtin.chain(user).map(|(n, e)| format!("{} → {}", n, e)).collect::<Vec<_>>().join("  "));
+                    }
+                    None => match self.alias(rest) {
+                        Some(expansion) => self.command_message = Message::Info(format!("{} → {}", rest, expansion)),
+                        None => self.report_error(EditorError::NoSuchAlias(rest.to_string())),
+                    },
+                }
+            }
+            "unalias" => {
+                for name in args {
+                    if self.aliases.remove(*name).is_none() {
+                        self.report_error(EditorError::NoSuchAlias(name.to_string()));
+                    }
+                }
+            }
+            "set" => {
+                if args.is_empty() {
+                    self.command_message = Message::Info("Option name needed for :set".to_string());
+                }
+                let spellfile = self.settings.spellfile.clone();
+                for arg in args {
+                    if matches!(*arg, "binary" | "bin") {
+                        // Reading the file again as text would drop any changes.
+                        match self.buffers.get(self.active_buffer_index) {
+                            Some(Buffer { binary: true, modified: false, .. }) => {
+                                if let Err(e) = self.reload_buffer(true) {
+                                    self.report_error(e);
+                                }
+                            }
+                            Some(Buffer { binary: true, filename, .. }) => {
+                                let name = filename.as_ref().map_or("[No Name]".to_string(), |p| p.display().to_string());
+                                self.report_error(EditorError::Unsaved(name));
+                            }
+                            _ => self.command_message = Message::Info("Not a binary file".to_string()),
+                        }
+                        continue;
+                    }
+                    if let Some(filetype) = arg.strip_prefix("filetype=").or_else(|| arg.strip_prefix("ft=")) {
+                        if let Some(buffer) = self.active_buffer() {
+                            buffer.set_filetype(filetype);
+                        }
+                        continue;
+                    }
+                    if matches!(*arg, "filetype" | "ft") {
+                        let filetype = self.active_buffer().and_then(|b| b.filetype.clone()).unwrap_or_default();
+                        self.command_message = Message::Info(format!("filetype={}", filetype));
+                        continue;
+                    }
+                    if *arg == "indent" {
+                        if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
+                            self.command_message = Message::Info(indent_summary(&buffer.indent, buffer.indent_style));
                         }
-                        self.update_tree_items();
-                    } else {
-                        self.open_file(path);
-                        self.tree_view_active = false;
+                        continue;
+                    }
+                    if let Some(buffer) = self.active_buffer() {
+                        match buffer.indent.apply(arg) {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(e) => {
+                                self.report_error(e);
+                                return;
+                            }
+                        }
+                        if apply_bool_option(buffer, arg, Buffer::bool_option) {
+                            buffer.modified = true;
+                            continue;
+                        }
+                    }
+                    if let Err(e) = self.settings.apply(arg) {
+                        self.report_error(e);
+                        return;
+                    }
+                }
+                if self.settings.spellfile != spellfile {
+                    self.spell_words = None;
+                }
+                self.load_spell_words_if_needed();
+                let foldmethod_set = args.iter().any(|arg| arg.starts_with("foldmethod=") || arg.starts_with("fdm="));
+                if foldmethod_set && self.settings.fold_by_indent {
+                    if let Some(buffer) = self.active_buffer() {
+                        buffer.fold_by_indent();
+                        buffer.row = buffer.screen_row_start(buffer.row);
                     }
                 }
             }
-            KeyCode::Tab | KeyCode::Esc => {
+            _ => self.report_error(EditorError::UnknownCommand(cmd.to_string())),
+        }
+    }
+
+    /// `:diff file`: compares the active buffer (left) with `file` (right), opening
+    /// it if needed. The active buffer stays the same.
+    fn start_diff(&mut self, file: PathBuf) {
+        let left = self.active_buffer_index;
+        if left >= self.buffers.len() {
+            return;
+        }
+        self.open_file(file);
+        let right = self.active_buffer_index;
+        self.switch_to_buffer(left);
+        if right == left {
+            self.command_message = Message::Info("Cannot diff a buffer with itself".to_string());
+            return;
+        }
+        let rows = diff_rows(&self.buffers[left].lines, &self.buffers[right].lines);
+        let changes = rows.iter().filter(|row| row.kind != DiffKind::Equal).count();
+        self.command_message = Message::Info(if changes == 0 { "No differences".to_string() } else { format!("{} lines differ", changes) });
+        self.diff = Some(DiffView { left, right, rows, cursor: 0, top: 0 });
+        self.tree_view_active = false;
+    }
+
+    /// Recomputes the rows of the open diff from the current buffer contents.
+    fn refresh_diff(&mut self) {
+        let Some(diff) = &mut self.diff else { return };
+        let (Some(left), Some(right)) = (self.buffers.get(diff.left), self.buffers.get(diff.right)) else {
+            self.diff = None;
+            return;
+        };
+        diff.rows = diff_rows(&left.lines, &right.lines);
+    }
+
+    /// Keys in the diff view: scrolling both panes together and jumping between
+    /// changes. Editing is not possible until `:diffoff` (or `q`).
+    fn handle_diff_key(&mut self, key_code: KeyCode) -> Mode {
+        let prefix = self.pending_command_prefix.take();
+        let Some(diff) = &mut self.diff else { return Mode::Normal };
+        let last = diff.rows.len().saturating_sub(1);
+        let is_change_start = |rows: &[DiffRow], i: usize| rows[i].kind != DiffKind::Equal && (i == 0 || rows[i - 1].kind == DiffKind::Equal);
+        match (prefix, key_code) {
+            (Some(']'), KeyCode::Char('c')) => match (diff.cursor + 1..diff.rows.len()).find(|&i| is_change_start(&diff.rows, i)) {
+                Some(i) => diff.cursor = i,
+                None => self.command_message = Message::Info("No more changes".to_string()),
+            },
+            (Some('['), KeyCode::Char('c')) => match (0..diff.cursor).rev().find(|&i| is_change_start(&diff.rows, i)) {
+                Some(i) => diff.cursor = i,
+                None => self.command_message = Message::Info("No more changes".to_string()),
+            },
+            (_, KeyCode::Char(c @ ('[' | ']'))) => self.pending_command_prefix = Some(c),
+            (_, KeyCode::Char('j') | KeyCode::Down) => diff.cursor = (diff.cursor + 1).min(last),
+            (_, KeyCode::Char('k') | KeyCode::Up) => diff.cursor = diff.cursor.saturating_sub(1),
+            (_, KeyCode::Char('g')) => diff.cursor = 0,
+            (_, KeyCode::Char('G')) => diff.cursor = last,
+            (_, KeyCode::Char('q') | KeyCode::Esc) => self.diff = None,
+            (_, KeyCode::Char(':')) => return Mode::Command,
+            (_, KeyCode::Tab) if self.tree_visible => self.tree_view_active = true,
+            _ => {}
+        }
+        Mode::Normal
+    }
+
+    /// Runs `command` on a worker thread so a slow command does not block input.
+    /// The result is picked up by the main loop.
+    fn start_shell_command(&mut self, command: &str, target: ShellTarget) {
+        let command = command.trim().to_string();
+        if command.is_empty() {
+            self.command_message = Message::Info("Command needed for :!".to_string());
+            return;
+        }
+        let sender = self.shell_sender.clone();
+        self.command_message = Message::Info(format!("Running {}...", command));
+        thread::spawn(move || {
+            let output = shell_command(&command).stdin(Stdio::null()).output();
+            // The editor may have quit while the command ran.
+            let _ = sender.send(ShellResult { command, target, output });
+        });
+    }
+
+    /// `:sort`: sorts rows `first..=last` alphabetically, or by the first number on
+    /// each line with `numeric` (lines without one go first, in their original
+    /// order). `unique` keeps only the first of lines that compare equal.
+    fn sort_lines(&mut self, first: usize, last: usize, reverse: bool, numeric: bool, unique: bool) {
+        let Some(buffer) = self.editable_buffer() else { return };
+        let compare = |a: &String, b: &String| {
+            let ordering = if numeric { first_number(a).cmp(&first_number(b)) } else { a.cmp(b) };
+            if reverse { ordering.reverse() } else { ordering }
+        };
+        let mut sorted = buffer.lines[first..=last].to_vec();
+        sorted.sort_by(compare);
+        if unique {
+            sorted.dedup_by(|a, b| compare(a, b).is_eq());
+        }
+        let count = last - first + 1;
+        let removed = count - sorted.len();
+        if sorted[..] != buffer.lines[first..=last] {
+            buffer.splice_lines(first..last + 1, sorted);
+            buffer.modified = true;
+        }
+        buffer.row = first;
+        buffer.col = 0;
+        self.command_message = Message::Info(match removed {
+            0 => format!("{} lines sorted", count),
+            _ => format!("{} lines sorted, {} duplicates removed", count, removed),
+        });
+    }
+
+    /// Replaces rows `first..=last` with the output of `command`, fed those rows on
+    /// stdin. The buffer is left alone if the command cannot run or fails.
+    fn filter_lines(&mut self, first: usize, last: usize, command: &str) {
+        if command.is_empty() {
+            self.command_message = Message::Info("Command needed for :!".to_string());
+            return;
+        }
+        if self.editable_buffer().is_none() {
+            return;
+        }
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+        let mut input = buffer.lines[first..=last].join("\n");
+        input.push('\n');
+        let child = shell_command(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(source) => {
+                self.report_error(EditorError::Shell { command: command.to_string(), source });
+                return;
+            }
+        };
+        // Write from another thread so a command that answers before reading all
+        // of its input cannot deadlock against a full pipe.
+        let stdin = child.stdin.take();
+        let writer = thread::spawn(move || {
+            if let Some(mut stdin) = stdin {
+                let _ = io::Write::write_all(&mut stdin, input.as_bytes());
+            }
+        });
+        let output = child.wait_with_output();
+        let _ = writer.join();
+        let output = match output {
+            Ok(output) => output,
+            Err(source) => {
+                self.report_error(EditorError::Shell { command: command.to_string(), source });
+                return;
+            }
+        };
+        if !output.status.success() {
+            let status = match output.status.code() {
+                Some(code) => format!("shell returned {}", code),
+                None => "terminated by signal".to_string(),
+            };
+            self.command_message = Message::Info(match output_lines(&output.stderr).first() {
+                Some(err) => format!("{} ({}), lines left unchanged", err, status),
+                None => format!("{} ({}), lines left unchanged", command, status),
+            });
+            return;
+        }
+        let lines = output_lines(&output.stdout);
+        let count = lines.len();
+        let Some(buffer) = self.active_buffer() else { return };
+        buffer.splice_lines(first..last + 1, lines);
+        if buffer.lines.is_empty() {
+            buffer.lines.push(String::new());
+        }
+        buffer.row = first.min(buffer.lines.len() - 1);
+        buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+        buffer.modified = true;
+        self.command_message = Message::Info(format!("{} lines filtered into {}", last - first + 1, count));
+    }
+
+    fn finish_shell_command(&mut self, result: ShellResult) {
+        let output = match result.output {
+            Ok(output) => output,
+            Err(source) => {
+                self.report_error(EditorError::Shell { command: result.command, source });
+                return;
+            }
+        };
+        let status = match output.status.code() {
+            Some(0) => String::new(),
+            Some(code) => format!(" (shell returned {})", code),
+            None => " (terminated by signal)".to_string(),
+        };
+        let mut lines = output_lines(&output.stdout);
+        match result.target {
+            ShellTarget::InsertBelow { buffer, row, removed } => {
+                // Buffers closed while the command ran move the later ones down.
+                let buffer = self.removed_buffers[removed..].iter().try_fold(buffer, |buffer, &gone| match buffer > gone {
+                    true => Some(buffer - 1),
+                    false => (buffer < gone).then_some(buffer),
+                });
+                let Some(buffer) = buffer.and_then(|buffer| self.buffers.get_mut(buffer)) else {
+                    self.command_message = Message::Info(format!("Output of {} dropped: its buffer was closed", result.command));
+                    return;
+                };
+                if let (true, Some(path)) = (buffer.binary, buffer.filename.clone()) {
+                    return self.report_error(EditorError::Binary(path));
+                }
+                let count = lines.len();
+                if count > 0 {
+                    // Lines may have been deleted while the command ran.
+                    let at = (row + 1).min(buffer.lines.len());
+                    buffer.splice_lines(at..at, lines);
+                    buffer.modified = true;
+                }
+                let stderr = output_lines(&output.stderr);
+                self.command_message = Message::Info(match stderr.first() {
+                    Some(err) if count == 0 => format!("{}{}", err, status),
+                    _ => format!("{} lines read from {}{}", count, result.command, status),
+                });
+            }
+            ShellTarget::Scratch => {
+                lines.extend(output_lines(&output.stderr));
+                if lines.is_empty() {
+                    lines.push(String::new());
+                }
+                let count = lines.len();
+                // Without a file there is nothing to fail.
+                let _ = self.open_file_in_new_buffer(None);
+                if let Some(buffer) = self.active_buffer() {
+                    buffer.lines = lines;
+                }
                 self.tree_view_active = false;
+                self.command_message = Message::Info(format!("{}: {} lines{}", result.command, count, status));
             }
-            _ => {}
         }
     }
 
-    /// Recursively gets items for the directory tree.
-    fn get_tree_items(&self, path: &PathBuf, prefix: String) -> Vec<TreeItem> {
-        let mut items = Vec::new();
-        if let Ok(entries) = std::fs::read_dir(path) {
-            let mut dirs = Vec::new();
-            let mut files = Vec::new();
-            for entry in entries.filter_map(|e| e.ok()) {
-                let path = entry.path();
-                if path.is_dir() { dirs.push(path); } else { files.push(path); }
+    /// Starts the editor as asked on the command line: runs the config file (or the
+    /// one given with `-u`; `-u NONE` skips it), then the flags, which override it,
+    /// then opens the files and applies the startup options.
+    fn startup(&mut self, args: &[String]) {
+        let mut config = config_file().filter(|path| path.exists());
+        let mut flags = Vec::new();
+        let mut files = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-u" => config = args.next().filter(|file| *file != "NONE").map(PathBuf::from),
+                "--no-tree" => flags.push("set nostarttree"),
+                "--no-tree-focus" => flags.push("set notreefocus"),
+                "--insert" => flags.push("set startinsert"),
+                _ => files.push(arg.clone()),
             }
-            dirs.sort();
-            files.sort();
+        }
+        let config_result = config.map_or(Ok(()), |path| self.source_config(&path));
+        for flag in flags {
+            self.execute_command(flag);
+        }
+        self.open_startup_files(&files);
+        if !self.settings.start_tree {
+            self.tree_visible = false;
+        }
+        if !self.tree_visible || !self.settings.tree_focus || self.settings.start_insert {
+            self.tree_view_active = false;
+        }
+        if self.settings.start_insert && self.buffers.get(self.active_buffer_index).is_some_and(|b| !b.binary) {
+            self.mode = Mode::Insert;
+        }
+        if let Err(e) = config_result {
+            self.report_error(e);
+        }
+    }
 
-            for item_path in dirs.into_iter().chain(files.into_iter()) {
-                let is_dir = item_path.is_dir();
-                items.push(TreeItem { path: item_path.clone(), prefix: prefix.clone(), is_dir });
-                if is_dir && self.expanded_dirs.contains(&item_path) {
-                    items.extend(self.get_tree_items(&item_path, format!("{}  ", prefix)));
+    /// Runs each line of a config file as a `:` command (the `:` is optional).
+    /// Blank lines and lines starting with `"` are skipped. All lines run; the
+    /// first one that failed is returned.
+    fn source_config(&mut self, path: &Path) -> Result<(), EditorError> {
+        let content = std::fs::read_to_string(path).map_err(|source| EditorError::Read { path: path.to_path_buf(), source })?;
+        let mut result = Ok(());
+        for (i, line) in content.lines().enumerate() {
+            let command = line.trim().trim_start_matches(':');
+            if command.is_empty() || command.starts_with('"') {
+                continue;
+            }
+            self.execute_command(command);
+            if let Message::Error(error) = std::mem::take(&mut self.command_message) {
+                if result.is_ok() {
+                    result = Err(EditorError::Config { path: path.to_path_buf(), line: i + 1, source: Box::new(error) });
                 }
             }
         }
-        items
+        self.command_message.clear();
+        result
     }
 
-    fn update_tree_items(&mut self) {
-        self.tree_items = self.get_tree_items(&self.current_path, String::new());
-        self.selected_item_index = self.selected_item_index.min(self.tree_items.len().saturating_sub(1));
+    /// Opens the files named on the command line. Each may carry a `:line[:col]`
+    /// suffix or be preceded by a `+line` argument. The first file ends up active.
+    fn open_startup_files(&mut self, args: &[String]) {
+        let mut pending_line = None;
+        let mut first_buffer = None;
+        let mut messages = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-S" {
+                let Some(session) = args.next() else { continue };
+                match self.source_session(Path::new(session)) {
+                    Ok(()) => messages.push(std::mem::take(&mut self.command_message).to_string()),
+                    Err(e) => messages.push(e.to_string()),
+                }
+                if self.buffers.iter().any(|b| b.filename.is_some()) {
+                    first_buffer.get_or_insert(self.active_buffer_index);
+                }
+                continue;
+            }
+            if let Some(line) = arg.strip_prefix('+').and_then(|n| n.parse::<usize>().ok()) {
+                pending_line = Some(line);
+                continue;
+            }
+            let (path, line, col) = parse_file_position(arg);
+            self.open_file(path.clone());
+            first_buffer.get_or_insert(self.active_buffer_index);
+            if let Some(line) = pending_line.take().or(line) {
+                if let Some(buffer) = self.active_buffer().filter(|b| b.loading.is_some()) {
+                    // The line count isn't known yet; the cursor is kept in the file once it is.
+                    let row = line.saturating_sub(1);
+                    buffer.restore_view(ViewState { row, col: col.unwrap_or(1).saturating_sub(1), top_row: row });
+                    continue;
+                }
+                let line_count = self.buffers[self.active_buffer_index].lines.len();
+                if line > line_count {
+                    messages.push(format!("{}: line {} is past the end, moved to line {}", path.display(), line, line_count));
+                }
+                self.goto_line(line);
+                if let (Some(col), Some(buffer)) = (col, self.active_buffer()) {
+                    buffer.col = col.saturating_sub(1);
+                }
+            }
+        }
+        // Root the tree where the files are if they are outside it, and show the first one.
+        let files: Vec<PathBuf> = self.buffers.iter()
+            .filter_map(|b| b.filename.as_deref())
+            .filter_map(|f| f.canonicalize().or_else(|_| std::path::absolute(f)).ok())
+            .collect();
+        let common = files.iter().filter_map(|f| f.parent()).map(Path::to_path_buf).reduce(|common, dir| {
+            let shared = common.ancestors().find(|ancestor| dir.starts_with(ancestor));
+            shared.unwrap_or(Path::new("/")).to_path_buf()
+        });
+        if let Some(root) = common.filter(|root| !root.starts_with(&self.current_path) && root.is_dir()) {
+            self.expanded_dirs.insert(root.clone());
+            self.current_path = root;
+        }
+        if let Some(file) = files.first() {
+            self.reveal_in_tree(file);
+        }
+        if let Some(index) = first_buffer {
+            self.active_buffer_index = index;
+            self.drop_scratch_buffer();
+            self.tree_view_active = false;
+        }
+        if !messages.is_empty() {
+            self.command_message = Message::Info(messages.join("; "));
+        }
     }
 
-    fn draw_tree_view(&self, f: &mut Frame, area: Rect) {
-        let tree_block = Block::default()
-            .title("ファイル")
-            .padding(Padding::horizontal(1));
-        let inner_area = tree_block.inner(area);
-        let mut lines = Vec::new();
+    /// Makes `index` the active buffer, remembering where the cursor was in the one being left.
+    fn switch_to_buffer(&mut self, index: usize) {
+        if index != self.active_buffer_index {
+            self.remember_view_state(self.active_buffer_index);
+            self.persist_view_states();
+        }
+        self.active_buffer_index = index;
+        self.extra_cursors.clear();
+        self.check_deleted_files();
+    }
 
-        for (i, item) in self.tree_items.iter().enumerate().skip(self.tree_scroll_pos) {
-            if i >= self.tree_scroll_pos + inner_area.height as usize { break; }
-            let indicator = if item.is_dir { if self.expanded_dirs.contains(&item.path) { "[-]" } else { "[+]" } } else { "   " };
-            let display_text = format!("{}{}{}", item.prefix, indicator, item.path.file_name().unwrap_or_default().to_string_lossy());
-            let mut line = Line::from(display_text);
-            if i == self.selected_item_index {
-                line = line.style(Style::default().bg(Color::DarkGray));
+    /// `:bd`: closes the active buffer, refusing if it has unsaved changes unless
+    /// `force`. Its file goes on the stack `:bun` reopens from, and its cursor
+    /// position into the view-state store.
+    fn close_buffer(&mut self, force: bool) {
+        let index = self.active_buffer_index;
+        let Some(buffer) = self.buffers.get(index) else {
+            self.command_message = Message::Info("No buffer to close".to_string());
+            return;
+        };
+        if buffer.modified && !force {
+            let name = buffer.filename.as_ref().map_or("[No Name]".to_string(), |p| p.display().to_string());
+            return self.report_error(EditorError::Unsaved(name));
+        }
+        self.remember_view_state(index);
+        self.persist_view_states();
+        let buffer = self.remove_buffer(index);
+        if let Some(path) = buffer.filename {
+            self.command_message = Message::Info(format!("Closed {} (:bun reopens it)", path.display()));
+            self.closed_buffers.retain(|closed| *closed != path);
+            self.closed_buffers.push(path);
+            if self.closed_buffers.len() > CLOSED_BUFFERS_MAX {
+                self.closed_buffers.remove(0);
             }
-            lines.push(line);
         }
-        let paragraph = Paragraph::new(lines).block(tree_block);
-        f.render_widget(paragraph, area);
+        self.extra_cursors.clear();
+        // There is always a buffer to edit, as when the editor starts.
+        if self.buffers.is_empty() {
+            self.buffers.push(Buffer::new(None));
+            self.tree_view_active = self.tree_visible;
+        }
     }
 
-    /// Main UI drawing function.
-    fn ui(&mut self, f: &mut Frame) {
-        // --- Layouts ---
-        let main_chunks = if self.tree_visible {
-            Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([
-                    Constraint::Length(self.tree_width), // Tree
-                    Constraint::Length(1),               // Separator
-                    Constraint::Min(0),                  // Editor
-                ])
-                .split(f.size())
-        } else {
-            Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Min(0)]) // Editor only
-                .split(f.size())
+    /// Removes buffer `index` and keeps what refers to buffers by index in step:
+    /// jumps and tags into it go, those into later buffers follow them down, and
+    /// a diff showing it closes.
+    fn remove_buffer(&mut self, index: usize) -> Buffer {
+        let buffer = self.buffers.remove(index);
+        for positions in [&mut self.jumplist, &mut self.tag_stack] {
+            positions.retain(|&(buffer, _, _)| buffer != index);
+            for (buffer, _, _) in positions.iter_mut().filter(|(buffer, _, _)| *buffer > index) {
+                *buffer -= 1;
+            }
+        }
+        self.jumplist_index = self.jumplist_index.min(self.jumplist.len());
+        match &mut self.diff {
+            Some(diff) if diff.left == index || diff.right == index => self.diff = None,
+            Some(diff) => {
+                for side in [&mut diff.left, &mut diff.right] {
+                    if *side > index {
+                        *side -= 1;
+                    }
+                }
+            }
+            None => {}
+        }
+        self.removed_buffers.push(index);
+        if self.active_buffer_index > index {
+            self.active_buffer_index -= 1;
+        }
+        self.active_buffer_index = self.active_buffer_index.min(self.buffers.len().saturating_sub(1));
+        buffer
+    }
+
+    /// `:bun` / `gB`: opens the most recently closed buffer again, at the cursor
+    /// position it was closed with. A file that is gone leaves an empty buffer.
+    fn reopen_closed_buffer(&mut self) {
+        let Some(path) = self.closed_buffers.pop() else {
+            self.command_message = Message::Info("No closed buffer to reopen".to_string());
+            return;
         };
+        self.tree_view_active = false;
+        if path.exists() {
+            return self.open_file(path);
+        }
+        let _ = self.open_file_in_new_buffer(None);
+        self.command_message = Message::Info(format!("{} no longer exists; opened an empty buffer", path.display()));
+    }
 
-        let editor_area = if self.tree_visible { main_chunks[2] } else { main_chunks[0] };
+    fn view_states(&mut self) -> &mut HashMap<PathBuf, ViewState> {
+        self.view_states.get_or_insert_with(load_view_states)
+    }
 
-        let editor_chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([Constraint::Min(1), Constraint::Length(2)].as_ref())
-            .split(editor_area);
+    /// Records the cursor position of a named buffer in the view-state table.
+    fn remember_view_state(&mut self, index: usize) {
+        let Some(buffer) = self.buffers.get(index) else { return };
+        let Some(path) = buffer.filename.as_ref().and_then(|p| p.canonicalize().ok()) else { return };
+        let state = ViewState { row: buffer.row, col: buffer.col, top_row: buffer.top_row };
+        self.view_states().insert(path, state);
+    }
 
-        let text_buffer_area = editor_chunks[0];
-        let status_area = editor_chunks[1];
+    /// Reopens the tree as it was left by the last session in the working
+    /// directory, skipping directories that are gone.
+    fn restore_tree_state(&mut self) {
+        let Ok(dir) = std::env::current_dir() else { return };
+        let Some(state) = load_tree_states().remove(&dir) else { return };
+        if state.root.is_dir() {
+            self.current_path = state.root;
+        }
+        self.expanded_dirs.extend(state.expanded_dirs.into_iter().filter(|path| path.is_dir()));
+        self.tree_dirty = true;
+    }
 
-        // --- Widgets ---
-        if self.tree_visible {
-            self.draw_tree_view(f, main_chunks[0]);
-            let separator_area = main_chunks[1];
-            for y in separator_area.y..separator_area.y + separator_area.height.saturating_sub(2) {
-                 f.buffer_mut().get_mut(separator_area.x, y).set_symbol("│");
+    /// `:cd`: makes `path` the working directory and the root of the tree. Buffer
+    /// file names are made absolute first so they keep pointing at the same files.
+    fn change_directory(&mut self, path: &Path) -> Result<(), EditorError> {
+        let dir = path.canonicalize().map_err(|source| EditorError::ChangeDir { path: path.to_path_buf(), source })?;
+        if !dir.is_dir() {
+            return Err(EditorError::NotADirectory(path.to_path_buf()));
+        }
+        for buffer in &mut self.buffers {
+            if let Some(absolute) = buffer.filename.as_deref().and_then(|f| std::path::absolute(f).ok()) {
+                buffer.filename = Some(absolute);
             }
         }
+        std::env::set_current_dir(&dir).map_err(|source| EditorError::ChangeDir { path: path.to_path_buf(), source })?;
+        self.expanded_dirs.retain(|expanded| expanded.starts_with(&dir));
+        self.expanded_dirs.insert(dir.clone());
+        self.current_path = dir;
+        self.tree_scroll_pos = 0;
+        self.selected_item_index = 0;
+        self.tree_dirty = true;
+        self.tags = None;
+        self.command_message = Message::Info(self.current_path.display().to_string());
+        Ok(())
+    }
 
-        if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
-            let line_num_width = buffer.lines.len().to_string().len() + 2;
-            let mut buffer_content: Vec<Line> = Vec::new();
+    /// Saves the expanded tree directories for the working directory, or forgets
+    /// them with `:set notreesession`.
+    fn save_tree_state(&self) {
+        let Ok(dir) = std::env::current_dir() else { return };
+        let mut states = load_tree_states();
+        if self.settings.tree_session {
+            let expanded_dirs = self.expanded_dirs.iter().filter(|path| **path != self.current_path).cloned().collect();
+            states.insert(dir, TreeState { root: self.current_path.clone(), expanded_dirs });
+        } else if states.remove(&dir).is_none() {
+            return;
+        }
+        // Like the view state, the tree shape is a convenience, so a failed write is not reported.
+        let _ = write_tree_states(&states);
+    }
 
-            for (i, line) in buffer.lines.iter().enumerate().skip(buffer.top_row) {
-                if i >= buffer.top_row + text_buffer_area.height as usize { break; }
-                let line_number_str = format!("{:>width$}", i + 1, width = line_num_width - 1);
-                let line_number_span = Span::styled(format!("{} ", line_number_str), Style::default().fg(Color::DarkGray));
-                let text_span = Span::raw(line.clone());
-                buffer_content.push(Line::from(vec![line_number_span, text_span]));
+    /// `:mksession`: writes the open files, their cursor positions and the tree
+    /// to `path`. Unnamed buffers are left out.
+    fn make_session(&self, path: &Path) -> Result<usize, EditorError> {
+        let mut content = String::new();
+        if let Ok(dir) = std::env::current_dir() {
+            content.push_str(&format!("cwd\t{}\n", dir.display()));
+        }
+        content.push_str(&format!("root\t{}\n", self.current_path.display()));
+        let mut expanded: Vec<&PathBuf> = self.expanded_dirs.iter().collect();
+        expanded.sort();
+        for dir in expanded {
+            content.push_str(&format!("expanded\t{}\n", dir.display()));
+        }
+        let mut count = 0;
+        for (index, buffer) in self.buffers.iter().enumerate() {
+            let Some(file) = buffer.filename.as_deref().and_then(|f| std::path::absolute(f).ok()) else { continue };
+            content.push_str(&format!("buffer\t{}\t{}\t{}\t{}\n", buffer.row, buffer.col, buffer.top_row, file.display()));
+            if index == self.active_buffer_index {
+                content.push_str(&format!("active\t{}\n", count));
             }
+            count += 1;
+        }
+        std::fs::write(path, content).map_err(|source| EditorError::Save { path: path.to_path_buf(), source })?;
+        Ok(count)
+    }
 
-            let paragraph = Paragraph::new(buffer_content)
-                .scroll((0, self.scroll_offset_col as u16));
-            f.render_widget(paragraph, text_buffer_area);
+    /// `:source` and `-S`: reopens the files of a session written by
+    /// `:mksession` and restores the tree. Files that no longer exist are
+    /// skipped and listed in the message.
+    fn source_session(&mut self, path: &Path) -> Result<(), EditorError> {
+        let content = std::fs::read_to_string(path).map_err(|source| EditorError::Read { path: path.to_path_buf(), source })?;
+        let session = parse_session(&content);
+        if let Some(dir) = session.cwd.filter(|dir| dir.is_dir()) {
+            self.change_directory(&dir)?;
+        }
+        if let Some(root) = session.root.filter(|root| root.is_dir()) {
+            self.current_path = root;
         }
+        self.expanded_dirs.extend(session.expanded_dirs.into_iter().filter(|dir| dir.is_dir()));
+        self.tree_dirty = true;
+        let mut missing = Vec::new();
+        let mut active = None;
+        for (index, (file, state)) in session.buffers.into_iter().enumerate() {
+            if !file.exists() {
+                missing.push(file.display().to_string());
+                continue;
+            }
+            self.open_file(file);
+            if let Some(buffer) = self.active_buffer() {
+                buffer.restore_view(state);
+            }
+            if session.active == Some(index) {
+                active = Some(self.active_buffer_index);
+            }
+        }
+        if let Some(index) = active {
+            self.switch_to_buffer(index);
+        }
+        self.drop_scratch_buffer();
+        if self.buffers.iter().any(|b| b.filename.is_some()) {
+            self.tree_view_active = false;
+        }
+        self.command_message = Message::Info(match missing.is_empty() {
+            true => format!("Restored session {}", path.display()),
+            false => format!("Restored session {}; missing: {}", path.display(), missing.join(", ")),
+        });
+        Ok(())
+    }
 
-        let (status_left, status_right) = if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
-            let filename = buffer.filename.as_ref().map_or("[No Name]".to_string(), |p| p.display().to_string());
-            let modified_str = if buffer.modified { "[+]" } else { "" };
-            let left = format!("-- {} -- {} {}", self.mode_str(), filename, modified_str);
-            let right = format!("{}:{}", buffer.row + 1, buffer.col + 1);
-            (left, right)
-        } else {
-            (format!("-- {} --", self.mode_str()), String::new())
-        };
+    /// Drops the empty buffer `new` starts with once other buffers are open.
+    fn drop_scratch_buffer(&mut self) {
+        if self.buffers.len() > 1 && self.buffers[0].filename.is_none() && !self.buffers[0].modified {
+            self.remove_buffer(0);
+        }
+    }
 
-        let status_bar = Paragraph::new(Line::from(vec![
-            Span::raw(&status_left),
-            Span::raw(" ".repeat(status_area.width.saturating_sub(status_left.len() as u16 + status_right.len() as u16) as usize)),
-            Span::raw(&status_right),
-        ])).style(Style::default().fg(Color::White).bg(Color::DarkGray));
-        f.render_widget(status_bar, Rect::new(status_area.x, status_area.y, status_area.width, 1));
+    fn persist_view_states(&mut self) {
+        if let Some(states) = &self.view_states {
+            // Losing view state is harmless, so a failed write is not reported.
+            let _ = write_view_states(states);
+        }
+    }
 
-        let command_line_text = if self.mode == Mode::Command {
-            format!(":{}", self.command_input)
-        } else {
-            self.command_message.clone()
+    /// Runs a `:s` command on the cursor line (or every line for `%s`) and reports
+    /// how many replacements were made.
+    fn substitute(&mut self, sub: &Substitute, first: usize, last: usize) {
+        if sub.pattern.is_empty() {
+            self.command_message = Message::Info("Empty pattern for :s".to_string());
+            return;
+        }
+        let regex = match self.settings.regex(&sub.pattern) {
+            Ok(regex) => regex,
+            Err(e) => return self.report_error(e),
         };
-        let command_line = Paragraph::new(command_line_text);
-        f.render_widget(command_line, Rect::new(status_area.x, status_area.y + 1, status_area.width, 1));
-
-        // --- Cursor ---
-        if self.mode != Mode::Command && !self.tree_view_active {
-            if let Some(buffer) = self.buffers.get(self.active_buffer_index) {
-                let line_num_width = buffer.lines.len().to_string().len() + 2;
-                // FIX: Calculate cursor X position based on the visual width of graphemes.
-                let pre_cursor_text: String = buffer.lines[buffer.row].graphemes(true).take(buffer.col).collect();
-                let pre_cursor_width = UnicodeWidthStr::width(pre_cursor_text.as_str());
-
-                let cursor_x = text_buffer_area.x + line_num_width as u16 + (pre_cursor_width as u16).saturating_sub(self.scroll_offset_col as u16);
-                let cursor_y = text_buffer_area.y + (buffer.row as u16).saturating_sub(buffer.top_row as u16);
-                f.set_cursor(cursor_x, cursor_y);
+        let Some(buffer) = self.editable_buffer() else { return };
+        let rows = first..last + 1;
+        if sub.confirm {
+            let last_row = rows.end - 1;
+            let Some((row, range)) = next_match(&buffer.lines, &regex, rows.start, 0, last_row) else {
+                self.command_message = Message::Info(format!("Pattern not found: {}", sub.pattern));
+                return;
+            };
+            let confirm = SubstituteConfirm { sub: sub.clone(), regex, row, range, last_row, replaced: 0, changed_rows: HashSet::new() };
+            self.ask_substitute(confirm);
+            return;
+        }
+        let mut replaced = 0;
+        let mut changed_lines = 0;
+        let mut last_row = None;
+        for row in rows {
+            let Some((new_line, count)) = substitute_line(&buffer.lines[row], sub, &regex) else { continue };
+            buffer.lines[row] = new_line;
+            replaced += count;
+            changed_lines += 1;
+            last_row = Some(row);
+        }
+        match last_row {
+            Some(row) => {
+                buffer.modified = true;
+                buffer.row = row;
+                buffer.col = first_non_blank(&buffer.lines[row]);
+                self.command_message = Message::Info(format!("{} substitutions on {} lines", replaced, changed_lines));
             }
+            None => self.command_message = Message::Info(format!("Pattern not found: {}", sub.pattern)),
         }
     }
 
-    fn mode_str(&self) -> &str {
-        match self.mode {
-            Mode::Normal => "NORMAL",
-            Mode::Insert => "INSERT",
-            Mode::Command => "COMMAND",
+    /// `:g/pattern/command`: runs `d` or `s/.../.../` on every line that matches
+    /// (or, for `:v`, doesn't). Rows are collected before anything changes, so
+    /// deleting lines doesn't shift the ones still to be visited.
+    fn global(&mut self, global: &Global, (first, last): (usize, usize)) {
+        if global.pattern.is_empty() {
+            self.command_message = Message::Info("Empty pattern for :g".to_string());
+            return;
+        }
+        let sub = parse_substitute(&global.command);
+        if global.command != "d" && sub.is_none() {
+            self.command_message = Message::Info(format!("Unsupported command for :g: {}", global.command));
+            return;
+        }
+        if sub.as_ref().is_some_and(|sub| sub.pattern.is_empty()) {
+            self.command_message = Message::Info("Empty pattern for :s".to_string());
+            return;
         }
+        let compiled = self.settings.regex(&global.pattern)
+            .and_then(|regex| Ok((regex, sub.as_ref().map(|sub| self.settings.regex(&sub.pattern)).transpose()?)));
+        let (regex, sub_regex) = match compiled {
+            Ok(compiled) => compiled,
+            Err(e) => return self.report_error(e),
+        };
+        let Some(buffer) = self.editable_buffer() else { return };
+        let rows: Vec<usize> = (first..=last)
+            .filter(|&row| find_matches(&buffer.lines[row], &regex).is_empty() == global.invert)
+            .collect();
+        let Some(&first_row) = rows.first() else {
+            self.command_message = Message::Info(format!("Pattern not found: {}", global.pattern));
+            return;
+        };
+        if let (Some(sub), Some(sub_regex)) = (sub, sub_regex) {
+            let mut replaced = 0;
+            let mut changed_lines = 0;
+            for &row in &rows {
+                let Some((new_line, count)) = substitute_line(&buffer.lines[row], &sub, &sub_regex) else { continue };
+                buffer.lines[row] = new_line;
+                buffer.row = row;
+                replaced += count;
+                changed_lines += 1;
+            }
+            if changed_lines == 0 {
+                self.command_message = Message::Info(format!("Pattern not found: {}", sub.pattern));
+                return;
+            }
+            buffer.modified = true;
+            buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+            self.command_message = Message::Info(format!("{} substitutions on {} lines", replaced, changed_lines));
+            return;
+        }
+        let mut deleted: Vec<String> = rows.iter().rev().flat_map(|&row| buffer.splice_lines(row..row + 1, Vec::new())).collect();
+        deleted.reverse();
+        if buffer.lines.is_empty() {
+            buffer.lines.push(String::new());
+        }
+        buffer.row = first_row.min(buffer.lines.len() - 1);
+        buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+        buffer.modified = true;
+        self.command_message = Message::Info(format!("{} fewer lines", deleted.len()));
+        self.registers.delete(Register { lines: deleted, kind: RegisterKind::Linewise });
     }
 
-    fn execute_command(&mut self, command: &str) {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.is_empty() { return; }
-        let cmd = parts[0];
-        let args = &parts[1..];
+    /// Asks a yes/no question on the command line; a yes runs `action`.
+    fn prompt_yes_no(&mut self, message: &str, action: PromptAction) {
+        self.prompt = Some(Prompt { message: message.to_string(), kind: PromptKind::YesNo, action });
+    }
 
-        match cmd {
-            "q" => {
-                if let Some(b) = self.buffers.get(self.active_buffer_index) {
-                    if b.modified {
-                        self.command_message = "Unsaved changes. Use q! to force quit.".to_string();
-                        return;
-                    }
-                }
-                self.should_exit = true;
+    /// Asks for a line of text, starting out as `initial`, that `action` then uses.
+    fn prompt_text(&mut self, message: &str, initial: &str, action: PromptAction) {
+        self.prompt = Some(Prompt { message: message.to_string(), kind: PromptKind::Text(initial.to_string()), action });
+    }
+
+    /// Feeds a key to the open prompt, running its action once it is answered
+    /// and keeping the prompt open otherwise.
+    fn handle_prompt_key(&mut self, mut prompt: Prompt, key_code: KeyCode) {
+        let answer = match (&mut prompt.kind, key_code) {
+            (PromptKind::YesNo, KeyCode::Char('y' | 'Y')) => Some(String::new()),
+            (PromptKind::YesNo, KeyCode::Char('n' | 'N') | KeyCode::Esc) => None,
+            (PromptKind::Text(input), KeyCode::Enter) => Some(std::mem::take(input)),
+            (PromptKind::Text(_), KeyCode::Esc) => None,
+            (PromptKind::Text(input), KeyCode::Char(c)) => {
+                input.push(c);
+                self.prompt = Some(prompt);
+                return;
             }
-            "q!" => self.should_exit = true,
-            "w" => self.save_file(args.get(0).map(|s| PathBuf::from(s))),
-            "wq" => {
-                self.save_file(args.get(0).map(|s| PathBuf::from(s)));
-                if let Some(b) = self.buffers.get(self.active_buffer_index) {
-                    if !b.modified { self.should_exit = true; }
-                }
+            (PromptKind::Text(input), KeyCode::Backspace) => {
+                input.pop();
+                self.prompt = Some(prompt);
+                return;
             }
-            "e" => {
-                if let Some(filename_str) = args.get(0) {
-                    self.open_file(PathBuf::from(filename_str));
-                } else {
-                    self.command_message = "Filename needed for :e".to_string();
-                }
+            (PromptKind::Choice(keys), KeyCode::Char(c)) if keys.contains(c) => Some(c.to_string()),
+            (PromptKind::Choice(_), KeyCode::Esc) => None,
+            _ => {
+                self.prompt = Some(prompt);
+                return;
             }
-            "bn" => {
-                if !self.buffers.is_empty() {
-                    self.active_buffer_index = (self.active_buffer_index + 1) % self.buffers.len();
-                }
+        };
+        self.command_message.clear();
+        let Some(answer) = answer else {
+            // A cancelled `:s///c` still reports what it has replaced so far.
+            if let PromptAction::Substitute(confirm) = prompt.action {
+                self.answer_substitute(*confirm, 'q');
             }
-            "bp" => {
-                if !self.buffers.is_empty() {
-                    self.active_buffer_index = (self.active_buffer_index + self.buffers.len() - 1) % self.buffers.len();
-                }
+            return;
+        };
+        match prompt.action {
+            PromptAction::Quit => self.should_exit = true,
+            PromptAction::Rename(path) => self.rename_path(&path, &answer),
+            PromptAction::Substitute(confirm) => self.answer_substitute(*confirm, answer.chars().next().unwrap_or('q')),
+        }
+    }
+
+    /// Renames `path` to `name` in the same directory, following any buffer
+    /// that has it open.
+    fn rename_path(&mut self, path: &Path, name: &str) {
+        let name = name.trim();
+        if name.is_empty() || name.contains(std::path::MAIN_SEPARATOR) {
+            return self.report_error(EditorError::invalid_value("name", name));
+        }
+        let target = path.with_file_name(name);
+        if target.exists() {
+            return self.report_error(EditorError::Rename { path: path.to_path_buf(), source: io::ErrorKind::AlreadyExists.into() });
+        }
+        if let Err(source) = std::fs::rename(path, &target) {
+            return self.report_error(EditorError::Rename { path: path.to_path_buf(), source });
+        }
+        // Where something at `old` (or under it) has moved to.
+        let moved = |old: &Path| {
+            let rest = old.strip_prefix(path).ok()?;
+            Some(if rest.as_os_str().is_empty() { target.clone() } else { target.join(rest) })
+        };
+        for buffer in &mut self.buffers {
+            let Some(filename) = buffer.filename.as_deref() else { continue };
+            let absolute = std::path::absolute(filename).unwrap_or_else(|_| filename.to_path_buf());
+            if let Some(new) = moved(&absolute) {
+                buffer.filename = Some(new);
             }
-            "tt" => {
-                self.tree_visible = !self.tree_visible;
-                if !self.tree_visible { self.tree_view_active = false; }
+        }
+        // Directories open inside a renamed one stay open under its new name.
+        self.expanded_dirs = std::mem::take(&mut self.expanded_dirs).into_iter().map(|dir| moved(&dir).unwrap_or(dir)).collect();
+        self.tree_dirty = true;
+        self.command_message = Message::Info(format!("Renamed to {}", target.display()));
+    }
+
+    /// Puts the cursor on the match `confirm` is about, highlights the pattern and
+    /// asks what to do with it.
+    fn ask_substitute(&mut self, confirm: SubstituteConfirm) {
+        let Some(buffer) = self.active_buffer() else { return };
+        buffer.row = confirm.row;
+        buffer.col = buffer.lines[confirm.row][..confirm.range.start].graphemes(true).count();
+        self.substitute_preview = Some(confirm.sub.pattern.clone());
+        let message = format!("replace with {} (y/n/a/q/l)?", confirm.sub.replacement);
+        self.prompt = Some(Prompt { message, kind: PromptKind::Choice("ynalq"), action: PromptAction::Substitute(Box::new(confirm)) });
+    }
+
+    /// Handles the answer to a `:s///c` prompt: `y` replaces this match, `n` skips
+    /// it, `a` replaces it and all the rest, `l` replaces it and stops, `q` stops.
+    fn answer_substitute(&mut self, mut confirm: SubstituteConfirm, answer: char) {
+        let (replace, stop, all) = match answer {
+            'y' => (true, false, false),
+            'n' => (false, false, false),
+            'a' => (true, false, true),
+            'l' => (true, true, false),
+            _ => (false, true, false),
+        };
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer_index) else { return };
+        loop {
+            let row = confirm.row;
+            let range = confirm.range.clone();
+            // Carry on searching after the replacement, whose length may differ from the match.
+            let mut from = if replace {
+                let line = &buffer.lines[row];
+                let captures = confirm.regex.captures(line).into_iter().find(|c| c[0].as_ref() == Some(&range)).unwrap_or_default();
+                let replacement = expand_replacement(&confirm.sub.replacement, line, &captures);
+                buffer.lines[row].replace_range(range.clone(), &replacement);
+                buffer.modified = true;
+                confirm.replaced += 1;
+                confirm.changed_rows.insert(row);
+                range.start + replacement.len()
+            } else {
+                range.end
+            };
+            // An empty match would be found again at the same place.
+            if range.is_empty() {
+                from += buffer.lines[row][from..].chars().next().map_or(1, char::len_utf8);
+            }
+            let next = match (stop, confirm.sub.global) {
+                (true, _) => None,
+                (false, true) => next_match(&buffer.lines, &confirm.regex, row, from, confirm.last_row),
+                (false, false) if row < confirm.last_row => next_match(&buffer.lines, &confirm.regex, row + 1, 0, confirm.last_row),
+                (false, false) => None,
+            };
+            let Some((row, range)) = next else { break };
+            confirm.row = row;
+            confirm.range = range;
+            if !all {
+                self.ask_substitute(confirm);
+                return;
+            }
+        }
+        buffer.col = buffer.col.min(buffer.lines[buffer.row].graphemes(true).count().saturating_sub(1));
+        self.substitute_preview = None;
+        self.command_message = Message::Info(format!("{} substitutions on {} lines", confirm.replaced, confirm.changed_rows.len()));
+    }
+
+    /// Reports line, word, grapheme and byte counts for the active buffer, and which
+    /// word the cursor is on, for `:wc` and `g Ctrl-g`.
+    fn report_word_count(&mut self) {
+        let Some(buffer) = self.buffers.get(self.active_buffer_index) else { return };
+        let mut words = 0;
+        let mut cursor_word = 0;
+        let mut graphemes = 0;
+        let mut bytes = buffer.lines.len() - 1; // newlines between lines
+        for (i, line) in buffer.lines.iter().enumerate() {
+            if i == buffer.row {
+                let cursor_byte = line.grapheme_indices(true).nth(buffer.col).map_or(line.len(), |(b, _)| b);
+                cursor_word = words + line.unicode_word_indices().filter(|(start, _)| *start <= cursor_byte).count();
             }
-            _ => self.command_message = format!("Unknown command: {}", cmd),
+            words += line.unicode_words().count();
+            graphemes += line.graphemes(true).count();
+            bytes += line.len();
+        }
+        self.command_message = Message::Info(format!(
+            "{} lines, {} words, {} chars, {} bytes; word {} of {}",
+            buffer.lines.len(), words, graphemes, bytes, cursor_word, words
+        ));
+    }
+
+    /// Places a sign on a line of the active buffer, replacing any sign already there.
+    fn set_sign(&mut self, line: usize, symbol: char, style: Style) {
+        if let Some(buffer) = self.active_buffer() {
+            buffer.signs.insert(line, Sign { symbol, style });
+        }
+    }
+
+    fn clear_sign(&mut self, line: usize) {
+        if let Some(buffer) = self.active_buffer() {
+            buffer.signs.remove(&line);
+        }
+    }
+
+    /// `:registers`: lists every register that holds text, plus `"%` for the
+    /// current file name.
+    fn show_registers(&mut self) {
+        // Long entries are cut short; the popup is clipped to the window anyway.
+        let max_width = 60;
+        let mut rows: Vec<String> = ['"', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '-', '.']
+            .into_iter()
+            .filter_map(|name| self.registers.get(name).map(|register| register_summary(name, register, max_width)))
+            .collect();
+        if let Some(filename) = self.buffers.get(self.active_buffer_index).and_then(|b| b.filename.as_ref()) {
+            let register = Register { lines: vec![filename.display().to_string()], kind: RegisterKind::Charwise };
+            rows.push(register_summary('%', &register, max_width));
+        }
+        if rows.is_empty() {
+            self.command_message = Message::Info("All registers are empty".to_string());
+        } else {
+            self.registers_popup = Some(rows);
         }
     }
 
-    fn open_file_in_new_buffer(&mut self, filename: Option<PathBuf>) {
+    /// Opens `filename` (or nothing) in a new buffer and makes it active. A file
+    /// that can't be read still gets its buffer, empty, before the error returns.
+    fn open_file_in_new_buffer(&mut self, filename: Option<PathBuf>) -> Result<(), EditorError> {
         let mut new_buffer = Buffer::new(filename.clone());
-        let mut message = "Opened new buffer".to_string();
+        let mut message = Ok("Opened new buffer".to_string());
 
         if let Some(path) = &filename {
-            if path.exists() {
-                match std::fs::read_to_string(path) {
-                    Ok(content) => {
-                        new_buffer.lines = content.lines().map(|s| s.to_string()).collect();
-                        if new_buffer.lines.is_empty() {
-                            new_buffer.lines.push(String::new());
+            let view_state = path.canonicalize().ok().and_then(|p| self.view_states().get(&p).copied());
+            if path.metadata().is_ok_and(|m| m.len() >= ASYNC_LOAD_MIN_BYTES) {
+                let id = self.start_file_load(path.clone());
+                new_buffer.loading = Some(Loading { id, received: 0, cursor: view_state });
+                message = Ok(format!("Loading {}...", path.display()));
+            } else if path.exists() {
+                match read_lines(path, false) {
+                    Ok(text) => {
+                        new_buffer.baseline = Some(text.lines.clone());
+                        new_buffer.lines = text.lines;
+                        new_buffer.bom = text.bom;
+                        new_buffer.binary = text.binary;
+                        new_buffer.detect_indent();
+                        // The file may have shrunk since the position was saved.
+                        if let Some(state) = view_state {
+                            new_buffer.restore_view(state);
                         }
-                        message = format!("Opened {}", path.display());
+                        message = Ok(opened_message(path, new_buffer.binary));
                     }
-                    Err(e) => message = format!("Error loading {}: {}", path.display(), e),
+                    Err(e) => message = Err(EditorError::load(path, e)),
                 }
             } else {
-                message = format!("New file: {}", path.display());
+                message = Ok(format!("New file: {}", path.display()));
             }
         }
+        if self.settings.fold_by_indent {
+            new_buffer.fold_by_indent();
+            new_buffer.open_folds_at(new_buffer.row);
+        }
         self.buffers.push(new_buffer);
-        self.active_buffer_index = self.buffers.len() - 1;
-        self.command_message = message;
+        self.switch_to_buffer(self.buffers.len() - 1);
+        self.command_message = Message::Info(message?);
+        Ok(())
+    }
+
+    /// Reads `path` on a worker thread, which sends its lines to the main loop
+    /// in batches as it reads them and then how the file ended. Returns the
+    /// number of the load.
+    fn start_file_load(&mut self, path: PathBuf) -> usize {
+        self.last_load += 1;
+        let load = self.last_load;
+        let sender = self.load_sender.clone();
+        thread::spawn(move || {
+            // The editor may have quit while the file loaded.
+            let send = |part| sender.send(FileChunk { load, part }).is_ok();
+            let part = match read_lines_in_batches(&path, |lines| send(LoadPart::Lines(lines))) {
+                Ok(Some((bom, binary))) => LoadPart::Done { bom, binary },
+                Ok(None) => return,
+                Err(e) => LoadPart::Failed(e),
+            };
+            send(part);
+        });
+        load
+    }
+
+    /// Adds a batch from a loading thread to its buffer. Once the file is all in,
+    /// the cursor goes to its remembered position and the keys typed meanwhile run.
+    fn receive_file_chunk(&mut self, chunk: FileChunk) {
+        let fold_by_indent = self.settings.fold_by_indent;
+        let Some(buffer) = self.buffers.iter_mut().find(|b| b.loading.as_ref().is_some_and(|loading| loading.id == chunk.load)) else {
+            return;
+        };
+        let path = buffer.filename.clone().unwrap_or_default();
+        match chunk.part {
+            LoadPart::Lines(lines) => {
+                let Some(loading) = buffer.loading.as_mut() else { return };
+                if loading.received == 0 {
+                    buffer.lines.clear();
+                }
+                loading.received += lines.len();
+                buffer.lines.extend(lines);
+                return;
+            }
+            LoadPart::Done { bom, binary } => {
+                buffer.bom = bom;
+                buffer.binary = binary;
+                buffer.detect_indent();
+                buffer.baseline = Some(buffer.lines.clone());
+                let cursor = buffer.loading.take().and_then(|loading| loading.cursor);
+                if let Some(state) = cursor {
+                    buffer.restore_view(state);
+                }
+                if fold_by_indent {
+                    buffer.fold_by_indent();
+                    buffer.open_folds_at(buffer.row);
+                }
+                self.command_message = Message::Info(opened_message(&path, binary));
+            }
+            LoadPart::Failed(e) => {
+                // Lines read before the error are dropped, as when a small file fails.
+                buffer.loading = None;
+                buffer.lines = vec![String::new()];
+                self.report_error(EditorError::load(&path, e));
+            }
+        }
+        for (code, modifiers) in std::mem::take(&mut self.typeahead) {
+            self.feed_key(code, modifiers);
+        }
     }
 
     fn open_file(&mut self, filename: PathBuf) {
@@ -650,55 +7112,216 @@ impl Editor {
                 if let Some(buf_filename) = &buffer.filename {
                     if let Ok(buf_abs_path) = buf_filename.canonicalize() {
                         if buf_abs_path == abs_path {
-                            self.active_buffer_index = i;
-                            self.command_message = format!("Switched to buffer {}", abs_path.display());
+                            self.switch_to_buffer(i);
+                            self.command_message = Message::Info(format!("Switched to buffer {}", abs_path.display()));
                             return;
                         }
                     }
                 }
             }
         }
-        self.open_file_in_new_buffer(Some(filename));
+        if let Err(e) = self.open_file_in_new_buffer(Some(filename)) {
+            self.report_error(e);
+        }
+    }
+
+    /// `:d`, `:y`, `:t` and `:m` on rows `first..=last`. `:t` and `:m` take the
+    /// address of the line to copy or move after; `0` puts the lines at the top.
+    fn line_command(&mut self, name: &str, address: &str, first: usize, last: usize) -> Result<(), EditorError> {
+        let marks = self.visual_marks;
+        if !matches!(name, "y" | "yank") {
+            self.check_editable()?;
+        }
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer_index) else { return Ok(()) };
+        let count = last - first + 1;
+        let copy_or_move = matches!(name, "t" | "co" | "copy" | "m" | "move");
+        if !copy_or_move && !address.is_empty() {
+            return Err(EditorError::TrailingCharacters(address.to_string()));
+        }
+        // Index the lines are inserted at.
+        let target = match address {
+            _ if !copy_or_move => 0,
+            "" => return Err(EditorError::AddressRequired(name.to_string())),
+            "0" => 0,
+            _ => resolve_range(address, buffer.row, buffer.lines.len() - 1, marks)?.map_or(0, |(_, row)| row + 1),
+        };
+        let lines: Vec<String> = buffer.lines[first..=last].to_vec();
+        match name {
+            "y" | "yank" => {
+                self.registers.yank(Register { lines, kind: RegisterKind::Linewise });
+                self.command_message = Message::Info(format!("{} lines yanked", count));
+                return Ok(());
+            }
+            "d" | "delete" => {
+                buffer.splice_lines(first..last + 1, Vec::new());
+                if buffer.lines.is_empty() {
+                    buffer.lines.push(String::new());
+                }
+                buffer.row = first.min(buffer.lines.len() - 1);
+                self.registers.delete(Register { lines, kind: RegisterKind::Linewise });
+                self.command_message = Message::Info(format!("{} fewer lines", count));
+            }
+            "t" | "co" | "copy" => {
+                buffer.splice_lines(target..target, lines);
+                buffer.row = target + count - 1;
+                self.command_message = Message::Info(format!("{} more lines", count));
+            }
+            _ => {
+                if (first + 1..=last).contains(&target) {
+                    return Err(EditorError::MoveIntoItself);
+                }
+                buffer.splice_lines(first..last + 1, Vec::new());
+                // Rows after the moved block shift up by its length.
+                let target = if target > last { target - count } else { target };
+                buffer.splice_lines(target..target, lines);
+                buffer.row = target + count - 1;
+                self.command_message = Message::Info(format!("{} lines moved", count));
+            }
+        }
+        buffer.col = first_non_blank(&buffer.lines[buffer.row]);
+        buffer.modified = true;
+        Ok(())
+    }
+
+    /// `:e!`: reads the active buffer's file again, dropping unsaved changes and
+    /// keeping the cursor where it was as far as the new text allows. With
+    /// `as_text` (`:set binary`) a binary file is read as editable text.
+    fn reload_buffer(&mut self, as_text: bool) -> Result<(), EditorError> {
+        let fold_by_indent = self.settings.fold_by_indent;
+        let Some(buffer) = self.active_buffer() else { return Ok(()) };
+        let Some(path) = buffer.filename.clone() else {
+            self.command_message = Message::Info("No file name for :e!".to_string());
+            return Ok(());
+        };
+        let text = read_lines(&path, as_text).map_err(|e| EditorError::load(&path, e))?;
+        buffer.baseline = Some(text.lines.clone());
+        buffer.lines = text.lines;
+        buffer.bom = text.bom;
+        buffer.binary = text.binary;
+        buffer.detect_indent();
+        buffer.modified = false;
+        let len = buffer.lines.len();
+        buffer.row = buffer.row.min(len - 1);
+        buffer.col = buffer.col.min(buffer.lines[buffer.row].graphemes(true).count());
+        buffer.top_row = buffer.top_row.min(buffer.row);
+        buffer.signs.retain(|&row, _| row < len);
+        if fold_by_indent {
+            buffer.fold_by_indent();
+            buffer.open_folds_at(buffer.row);
+        } else {
+            buffer.folds.retain(|fold| fold.end < len);
+        }
+        self.extra_cursors.clear();
+        self.command_message = Message::Info(format!("Reloaded {}", path.display()));
+        Ok(())
     }
 
-    fn save_file(&mut self, filename: Option<PathBuf>) {
+    fn save_file(&mut self, filename: Option<PathBuf>) -> Result<(), EditorError> {
+        let trim_trailing = self.settings.trim_trailing;
+        let fix_eol = self.settings.fix_eol;
         if let Some(buffer) = self.active_buffer() {
             let target_filename = filename.or_else(|| buffer.filename.clone());
+            if let (true, Some(path)) = (buffer.binary, &target_filename) {
+                // Saving would write the hex dump over the file.
+                return Err(EditorError::Binary(path.clone()));
+            }
+            if let (Some(_), Some(path)) = (&buffer.loading, &target_filename) {
+                // Saving would cut the file short.
+                return Err(EditorError::StillLoading(path.clone()));
+            }
             if let Some(path) = target_filename {
-                match std::fs::write(&path, buffer.lines.join("\n")) {
-                    Ok(_) => {
-                        buffer.filename = Some(path.clone());
-                        buffer.modified = false;
-                        self.command_message = format!("Saved to {}", path.display());
+                // Trim in memory first so the buffer matches what ends up on disk.
+                let mut trimmed = 0;
+                if trim_trailing && buffer.modified {
+                    for line in buffer.lines.iter_mut() {
+                        let len = line.trim_end_matches([' ', '\t']).len();
+                        if len < line.len() {
+                            line.truncate(len);
+                            trimmed += 1;
+                        }
                     }
-                    Err(e) => self.command_message = format!("Error saving {}: {}", path.display(), e),
+                    let grapheme_count = buffer.lines[buffer.row].graphemes(true).count();
+                    buffer.col = buffer.col.min(grapheme_count);
+                }
+                let mut content = if buffer.bom { "\u{feff}".to_string() } else { String::new() };
+                content.push_str(&buffer.lines.join("\n"));
+                if fix_eol {
+                    content.truncate(content.trim_end_matches('\n').len());
+                    content.push('\n');
+                }
+                let created = !path.exists();
+                if let Err(source) = write_atomically(&path, &content) {
+                    return Err(EditorError::Save { path, source });
+                }
+                if buffer.filename.is_none() {
+                    let filetype = detect_filetype(Some(&path)).unwrap_or_default();
+                    buffer.set_filetype(&filetype);
+                }
+                buffer.filename = Some(path.clone());
+                buffer.modified = false;
+                buffer.deleted_on_disk = false;
+                buffer.baseline = Some(buffer.lines.clone());
+                if created {
+                    self.tree_dirty = true;
                 }
+                self.command_message = Message::Info(if trimmed > 0 {
+                    format!("Saved to {} (trimmed {} lines)", path.display(), trimmed)
+                } else {
+                    format!("Saved to {}", path.display())
+                });
             } else {
-                self.command_message = "No filename. Use :w <filename>".to_string();
+                self.command_message = Message::Info("No filename. Use :w <filename>".to_string());
             }
         }
+        Ok(())
+    }
+}
+
+/// Puts the terminal back the way it was before the editor started.
+fn restore_terminal() -> io::Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        // FIX: Reset cursor to default shape on exit
+        SetCursorStyle::DefaultUserShape,
+        Show
+    )
+}
+
+/// Restores the terminal when dropped, whether `main` returns normally, with an
+/// error, or unwinds from a panic.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = restore_terminal();
     }
 }
 
 fn main() -> io::Result<()> {
+    // The panic message has to be printed on the normal screen in cooked mode,
+    // so restore the terminal before the default hook runs rather than after.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+
     enable_raw_mode()?;
+    let guard = TerminalGuard;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     let mut editor = Editor::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    editor.startup(&args);
     let res = editor.run(&mut terminal);
 
     // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        // FIX: Reset cursor to default shape on exit
-        SetCursorStyle::DefaultUserShape
-    )?;
-    terminal.show_cursor()?;
+    drop(guard);
 
     if let Err(err) = res {
         println!("{:?}", err);
@@ -706,3 +7329,1153 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(lines: &[&str]) -> Editor {
+        Editor::with_buffer(lines.iter().map(|line| line.to_string()).collect())
+    }
+
+    /// A fresh directory for the files of test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("moti-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        dir
+    }
+
+    /// Types `keys`, each a plain character.
+    fn keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            editor.feed_key(KeyCode::Char(c), KeyModifiers::NONE);
+        }
+    }
+
+    /// The matched text of every match of `pattern` in `line`.
+    fn matches<'a>(pattern: &str, line: &'a str) -> Vec<&'a str> {
+        let regex = Regex::new(pattern, true, false).expect("valid pattern");
+        find_matches(line, &regex).into_iter().map(|range| &line[range]).collect()
+    }
+
+    #[test]
+    fn regex_classes() {
+        assert_eq!(matches(r"\d+", "a12b345"), ["12", "345"]);
+        assert_eq!(matches(r"[a-c]+", "xabcaby"), ["abcab"]);
+        assert_eq!(matches(r"[^a-c ]+", "ab xyz c"), ["xyz"]);
+        assert_eq!(matches(r"\w+", "foo_1 bar"), ["foo_1", "bar"]);
+        assert_eq!(matches(r"\S+", " a\tbc "), ["a", "bc"]);
+        assert_eq!(matches(r"[]x]", "a]x"), ["]", "x"]);
+        assert_eq!(matches(r"a.c", "abc a\u{e9}c ac"), ["abc", "a\u{e9}c"]);
+    }
+
+    #[test]
+    fn regex_anchors() {
+        assert_eq!(matches("^a", "aaa"), ["a"]);
+        assert_eq!(matches("a$", "aaa"), ["a"]);
+        assert_eq!(matches(r"\bfoo\b", "foo food afoo foo"), ["foo", "foo"]);
+        assert_eq!(matches(r"\Boo", "foo oo"), ["oo"]);
+        assert_eq!(matches(r"\<fo", "foo afoo"), ["fo"]);
+        assert_eq!(matches(r"o\>", "foo oops"), ["o"]);
+        assert_eq!(matches("^$", ""), [""]);
+    }
+
+    #[test]
+    fn regex_groups_and_alternation() {
+        let regex = Regex::new(r"(\w+)=(\d+)?", true, false).unwrap();
+        let line = "a=1 b=";
+        let captures = regex.captures(line);
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[0], [Some(0..3), Some(0..1), Some(2..3)]);
+        assert_eq!(captures[1], [Some(4..6), Some(4..5), None]);
+        assert_eq!(matches("cat|dog", "hotdog catalog"), ["dog", "cat"]);
+        assert_eq!(matches("(?:ab)+", "abababx"), ["ababab"]);
+        assert_eq!(matches("a(b|bc)d", "abcd"), ["abcd"]);
+        assert_eq!(matches("a{2,3}", "aaaaaaa"), ["aaa", "aaa"]);
+        assert_eq!(matches("a+?", "aaa"), ["a", "a", "a"]);
+        assert_eq!(matches("<.*?>", "<a><b>"), ["<a>", "<b>"]);
+        // The last pass through a repeated group is the one captured.
+        let captures = Regex::new("(a|b)*", true, false).unwrap().captures("abb");
+        assert_eq!(captures[0][1], Some(2..3));
+        assert!(Regex::new("(a", true, false).is_err());
+        assert!(Regex::new("a)", true, false).is_err());
+        assert!(Regex::new("*a", true, false).is_err());
+        assert!(Regex::new("a{3,1}", true, false).is_err());
+    }
+
+    #[test]
+    fn regex_case_and_magic() {
+        let matched = |pattern, line, magic, ignore_case| {
+            let regex = Regex::new(pattern, magic, ignore_case).unwrap();
+            find_matches(line, &regex).into_iter().map(|range| &line[range]).collect::<Vec<_>>()
+        };
+        assert_eq!(matched("stra[ß]e", "STRAßE", true, true), ["STRAßE"]);
+        assert_eq!(matched("[a-c]x", "BX", true, true), ["BX"]);
+        assert!(matched("bx", "BX", true, false).is_empty());
+        assert_eq!(matched("a.(b", "xa.(b aab", false, false), ["a.(b"]);
+    }
+
+    #[test]
+    fn regex_pathological_patterns() {
+        let long = "x".repeat(20_000);
+        assert_eq!(matches("(.)*", &long), [long.as_str()]);
+        let pairs = "ab".repeat(10_000);
+        assert_eq!(matches("(ab)*", &pairs), [pairs.as_str()]);
+        // Exponential for a plain backtracker; here each split is tried once per position.
+        let started = Instant::now();
+        assert!(matches("(a+)+b", &"a".repeat(24)).is_empty());
+        assert!(matches("(a*)*b", &"a".repeat(5_000)).is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(matches("(a*)*", "aab"), ["aa", ""]);
+        assert_eq!(Regex::new("(x{100}){200}", true, false).unwrap_err(), "pattern too large");
+        let nested = format!("{}x{}", "(".repeat(REGEX_MAX_DEPTH), ")".repeat(REGEX_MAX_DEPTH));
+        assert_eq!(matches(&nested, "axb"), ["x"]);
+        let deep = format!("({}", nested);
+        assert_eq!(Regex::new(&deep, true, false).unwrap_err(), "pattern too deeply nested");
+        assert_eq!(Regex::new(&"(".repeat(100_000), true, false).unwrap_err(), "pattern too deeply nested");
+    }
+
+    #[test]
+    fn closing_the_last_buffer_leaves_an_empty_one() {
+        let mut editor = editor_with(&["one"]);
+        editor.feed_command("bd");
+        assert_eq!(editor.buffers.len(), 1);
+        assert_eq!(editor.text(), [""]);
+        keys(&mut editor, "itwo");
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(editor.text(), ["two"]);
+    }
+
+    #[test]
+    fn removing_a_buffer_remaps_what_points_at_buffers() {
+        let mut editor = editor_with(&["a"]);
+        editor.buffers.extend([Buffer::new(None), Buffer::new(None)]);
+        editor.active_buffer_index = 2;
+        editor.jumplist = vec![(0, 1, 0), (2, 3, 0)];
+        editor.tag_stack = vec![(1, 0, 0)];
+        editor.diff = Some(DiffView { left: 1, right: 2, rows: Vec::new(), cursor: 0, top: 0 });
+        editor.remove_buffer(0);
+        assert_eq!(editor.active_buffer_index, 1);
+        assert_eq!(editor.jumplist, [(1, 3, 0)]);
+        assert_eq!(editor.tag_stack, [(0, 0, 0)]);
+        assert!(editor.diff.as_ref().is_some_and(|diff| (diff.left, diff.right) == (0, 1)));
+        editor.remove_buffer(0);
+        assert!(editor.diff.is_none());
+    }
+
+    #[test]
+    fn shell_output_follows_its_buffer() {
+        let mut editor = editor_with(&["first"]);
+        editor.buffers.push(Buffer::new(None));
+        editor.active_buffer_index = 1;
+        editor.feed_command("r !echo hi");
+        editor.remove_buffer(0);
+        let result = editor.shell_receiver.recv_timeout(Duration::from_secs(10)).expect("command finishes");
+        editor.finish_shell_command(result);
+        assert_eq!(editor.buffers[0].lines, ["", "hi"]);
+
+        editor.feed_command("r !echo hi");
+        editor.remove_buffer(0);
+        editor.buffers.push(Buffer::new(None));
+        let result = editor.shell_receiver.recv_timeout(Duration::from_secs(10)).expect("command finishes");
+        editor.finish_shell_command(result);
+        assert_eq!(editor.buffers[0].lines, [""]);
+        assert!(editor.command_message.to_string().contains("dropped"));
+    }
+
+    #[test]
+    fn commands_without_a_buffer() {
+        let mut editor = editor_with(&[]);
+        editor.buffers.clear();
+        editor.feed_command("set so=3");
+        assert_eq!(editor.settings.scrolloff, 3);
+        editor.feed_command("alias W w");
+        assert_eq!(editor.aliases.get("W").map(String::as_str), Some("w"));
+        editor.feed_command("%s/a/b/");
+        editor.feed_command("2d");
+        editor.feed_command("q");
+        assert!(editor.should_exit);
+    }
+
+    #[test]
+    fn change_word_stops_at_the_end_of_the_word() {
+        for (typed, changed) in [("llcwX", "foX bar baz"), ("lcwX", "fX bar baz"), ("c2wX", "X baz"), ("3lcwX", "fooXbar baz")] {
+            let mut editor = editor_with(&["foo bar baz"]);
+            keys(&mut editor, typed);
+            assert_eq!(editor.text(), [changed], "{}", typed);
+        }
+    }
+
+    #[test]
+    fn binary_buffers_are_read_only() {
+        let path = temp_dir("binary").join("data.bin");
+        std::fs::write(&path, b"ab\0cd\nef").unwrap();
+        let mut editor = editor_with(&[]);
+        let text = read_lines(&path, false).unwrap();
+        editor.buffers[0] = Buffer { lines: text.lines, binary: text.binary, ..Buffer::new(Some(path.clone())) };
+        let dump = editor.text().to_vec();
+        editor.registers.yank(Register { lines: vec!["x".to_string()], kind: RegisterKind::Linewise });
+        for typed in ["x", "X", "dd", "p", "P", "gUU", "~", "o", "S", "D", "cwx"] {
+            keys(&mut editor, typed);
+            editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+            assert_eq!(editor.text(), dump, "{}", typed);
+            assert!(matches!(editor.mode, Mode::Normal), "{}", typed);
+        }
+        for command in ["s/0/1/", "sort", "g/0/d", "m0", "1d", "t0", "%!cat"] {
+            editor.feed_command(command);
+            assert_eq!(editor.text(), dump, "{}", command);
+            assert!(matches!(editor.command_message, Message::Error(EditorError::Binary(_))), "{}", command);
+        }
+        assert!(!editor.buffers[0].modified);
+
+        editor.buffers[0].modified = true;
+        editor.feed_command("set binary");
+        assert!(editor.buffers[0].binary);
+        editor.buffers[0].modified = false;
+        editor.feed_command("set binary");
+        assert!(!editor.buffers[0].binary);
+        assert_eq!(editor.text(), ["ab\0cd", "ef"]);
+        editor.feed_command("set binary");
+        assert_eq!(editor.command_message.to_string(), "Not a binary file");
+    }
+
+    /// Feeds the batches of the load of the active buffer to it until it has loaded.
+    fn finish_load(editor: &mut Editor) {
+        while editor.buffers[editor.active_buffer_index].loading.is_some() {
+            let chunk = editor.load_receiver.recv_timeout(Duration::from_secs(10)).expect("load goes on");
+            editor.receive_file_chunk(chunk);
+        }
+    }
+
+    #[test]
+    fn big_files_load_in_batches() {
+        let dir = temp_dir("load");
+        let path = dir.join("big.txt");
+        let lines: Vec<String> = (0..LOAD_CHUNK_LINES * 2 + 10).map(|i| format!("line {:020}", i)).collect();
+        std::fs::write(&path, format!("\u{feff}{}\n", lines.join("\n"))).unwrap();
+        let mut editor = editor_with(&[]);
+        editor.open_file_in_new_buffer(Some(path.clone())).unwrap();
+        assert!(editor.buffers[1].loading.is_some());
+        assert_eq!(editor.text(), [""]);
+        let chunk = editor.load_receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        editor.receive_file_chunk(chunk);
+        assert_eq!(editor.text().len(), LOAD_CHUNK_LINES);
+        // Renaming the file in the tree while it loads doesn't lose the rest.
+        editor.buffers[1].filename = Some(dir.join("renamed.txt"));
+        keys(&mut editor, "G");
+        finish_load(&mut editor);
+        assert_eq!(editor.text(), lines);
+        assert!(editor.buffers[1].bom);
+        assert_eq!(editor.cursor().0, lines.len() - 1);
+    }
+
+    #[test]
+    fn a_failed_load_leaves_an_empty_buffer() {
+        let path = temp_dir("load-failed").join("bad.txt");
+        let mut bytes = "x\n".repeat(ASYNC_LOAD_MIN_BYTES as usize).into_bytes();
+        bytes.extend([0xff, 0xfe, b'\n']);
+        std::fs::write(&path, bytes).unwrap();
+        let mut editor = editor_with(&[]);
+        editor.open_file_in_new_buffer(Some(path)).unwrap();
+        assert!(editor.buffers[1].loading.is_some());
+        finish_load(&mut editor);
+        assert_eq!(editor.text(), [""]);
+        assert!(matches!(editor.command_message, Message::Error(EditorError::Encoding { .. })));
+    }
+
+    /// What `editor` shows drawn at `width` x `height`, a row a line. The cell
+    /// covered by the right half of a wide character is left out.
+    fn screen(editor: &mut Editor, width: u16, height: u16) -> Vec<String> {
+        let drawn = editor.render_to(Rect::new(0, 0, width, height));
+        (0..height)
+            .map(|y| {
+                let (mut row, mut x) = (String::new(), 0);
+                while x < width {
+                    let symbol = drawn.get(x, y).symbol();
+                    row.push_str(symbol);
+                    x += symbol.width().max(1) as u16;
+                }
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn screen_with_signs_and_a_modified_buffer() {
+        let mut editor = editor_with(&["fn main() {", "    let x = 1;", "}"]);
+        editor.set_sign(1, 'E', Style::default());
+        keys(&mut editor, "jwx");
+        assert_eq!(screen(&mut editor, 32, 5), [
+            " 1  fn main() {                 ",
+            " 2 E    et x = 1;               ",
+            " 3  }                           ",
+            "-- NORMAL -- [No Name] [+]   2:5",
+            "                                ",
+        ]);
+    }
+
+    #[test]
+    fn screen_with_a_closed_fold() {
+        let mut editor = editor_with(&["fn main() {", "    let x = 1;", "}", "tail"]);
+        keys(&mut editor, "zfj");
+        assert_eq!(screen(&mut editor, 32, 5), [
+            " 1 +-- 2 lines: fn main() {     ",
+            " 3 }                            ",
+            " 4 tail                         ",
+            "-- NORMAL -- [No Name]       1:1",
+            "                                ",
+        ]);
+    }
+
+    #[test]
+    fn screen_scrolled_sideways() {
+        let mut editor = editor_with(&["0123456789abcdefghijklmnopqrstuvwxyz", "short", "0123456789abcdefghijklmn"]);
+        keys(&mut editor, "$");
+        assert_eq!(screen(&mut editor, 32, 5), [
+            " 1 789abcdefghijklmnopqrstuvwxyz",
+            " 2                              ",
+            " 3 789abcdefghijklmn            ",
+            "-- NORMAL -- [No Name]      1:36",
+            "                                ",
+        ]);
+    }
+
+    #[test]
+    fn keys_and_commands_drive_the_editor() {
+        let mut editor = editor_with(&[]);
+        keys(&mut editor, "ihello");
+        editor.feed_key(KeyCode::Enter, KeyModifiers::NONE);
+        keys(&mut editor, "world");
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+        keys(&mut editor, "0l");
+        assert_eq!(editor.text(), ["hello", "world"]);
+        assert_eq!(editor.cursor(), (1, 1));
+        assert!(matches!(editor.mode, Mode::Normal));
+        editor.feed_command("%s/l/L/g");
+        assert_eq!(editor.text(), ["heLLo", "worLd"]);
+        assert!(matches!(editor.mode, Mode::Normal));
+        keys(&mut editor, "ggdd");
+        assert_eq!(editor.text(), ["worLd"]);
+        assert_eq!(editor.cursor(), (0, 0));
+        editor.feed_command("nosuchcommand");
+        assert!(matches!(editor.command_message, Message::Error(EditorError::UnknownCommand(_))));
+    }
+
+    /// The line numbers in the gutter after drawing `editor` at 30 x `height`.
+    fn visible_rows(editor: &mut Editor, height: u16) -> Vec<usize> {
+        screen(editor, 30, height).iter().filter_map(|row| row.split_whitespace().next()?.parse().ok()).collect()
+    }
+
+    #[test]
+    fn opened_lines_are_on_screen() {
+        let lines: Vec<String> = (1..=20).map(|i| format!("line {}", i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        // Five rows of text between the top and the status and command lines.
+        let mut editor = editor_with(&lines);
+        keys(&mut editor, "O");
+        assert_eq!(visible_rows(&mut editor, 7), [1, 2, 3, 4, 5]);
+        assert_eq!(editor.cursor(), (0, 0));
+
+        let mut editor = editor_with(&lines);
+        keys(&mut editor, "jjo");
+        assert_eq!(visible_rows(&mut editor, 7), [1, 2, 3, 4, 5]);
+        assert_eq!(editor.cursor(), (3, 0));
+
+        let mut editor = editor_with(&lines);
+        keys(&mut editor, "4jo");
+        assert_eq!(visible_rows(&mut editor, 7), [2, 3, 4, 5, 6]);
+        assert_eq!(editor.cursor(), (5, 0));
+
+        let mut editor = editor_with(&lines);
+        keys(&mut editor, "Go");
+        assert_eq!(visible_rows(&mut editor, 7), [17, 18, 19, 20, 21]);
+        assert_eq!(editor.cursor(), (20, 0));
+
+        // `O` on the top line of the screen.
+        let mut editor = editor_with(&lines);
+        keys(&mut editor, "G");
+        assert_eq!(visible_rows(&mut editor, 7), [16, 17, 18, 19, 20]);
+        keys(&mut editor, "kkkkO");
+        assert_eq!(visible_rows(&mut editor, 7), [16, 17, 18, 19, 20]);
+        assert_eq!(editor.cursor(), (15, 0));
+    }
+
+    #[test]
+    fn linewise_yank_and_put() {
+        let mut editor = editor_with(&["a", "b", "c", "d", "e"]);
+        keys(&mut editor, "Vjjy");
+        assert_eq!(editor.registers.get('"').map(|r| (r.lines.clone(), r.kind)), Some((vec!["a".into(), "b".into(), "c".into()], RegisterKind::Linewise)));
+        keys(&mut editor, "Gp");
+        assert_eq!(editor.text(), ["a", "b", "c", "d", "e", "a", "b", "c"]);
+        assert_eq!(editor.cursor(), (5, 0));
+        keys(&mut editor, "ggjP");
+        assert_eq!(editor.text(), ["a", "a", "b", "c", "b", "c", "d", "e", "a", "b", "c"]);
+        assert_eq!(editor.cursor(), (1, 0));
+    }
+
+    #[test]
+    fn visual_line_case_clears_a_pending_prefix() {
+        let mut editor = editor_with(&["ab", "cd", "ef"]);
+        keys(&mut editor, "Vjgu");
+        keys(&mut editor, "U");
+        assert_eq!(editor.text(), ["ab", "cd", "ef"]);
+        assert!(editor.pending_command_prefix.is_none());
+        keys(&mut editor, "VjU");
+        assert_eq!(editor.text(), ["AB", "CD", "ef"]);
+        keys(&mut editor, "gg");
+        assert_eq!(editor.cursor().0, 0);
+    }
+
+    #[test]
+    fn visual_block_keys_clear_a_pending_prefix() {
+        let mut editor = editor_with(&["abc", "def", "ghi", "jkl"]);
+        keys(&mut editor, "jjzfjgg");
+        editor.feed_key(KeyCode::Char('v'), KeyModifiers::CONTROL);
+        keys(&mut editor, "lzd");
+        assert_eq!(editor.text(), ["c", "def", "ghi", "jkl"]);
+        assert!(editor.pending_command_prefix.is_none());
+        // The `o` after it opens a line rather than being read as `zo`.
+        keys(&mut editor, "o");
+        assert!(matches!(editor.mode, Mode::Insert));
+        assert_eq!(editor.text(), ["c", "", "def", "ghi", "jkl"]);
+        assert!(!editor.buffers[0].folds[0].open);
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+
+        // `zf` still folds the block.
+        let mut editor = editor_with(&["abc", "def", "ghi"]);
+        editor.feed_key(KeyCode::Char('v'), KeyModifiers::CONTROL);
+        keys(&mut editor, "jzf");
+        assert!(matches!(editor.mode, Mode::Normal));
+        assert_eq!(folds(&editor), [(0, 1)]);
+    }
+
+    #[test]
+    fn charwise_put_across_lines() {
+        let mut editor = editor_with(&["one two", "three four"]);
+        keys(&mut editor, "wd/fo");
+        editor.feed_key(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.text(), ["one four"]);
+        keys(&mut editor, "0p");
+        assert_eq!(editor.text(), ["otwo", "three ne four"]);
+        assert_eq!(editor.cursor(), (0, 1));
+        keys(&mut editor, "P");
+        assert_eq!(editor.text(), ["otwo", "three two", "three ne four"]);
+    }
+
+    #[test]
+    fn blockwise_put_onto_short_lines() {
+        let mut editor = editor_with(&["abc", "def", "x", ""]);
+        editor.feed_key(KeyCode::Char('v'), KeyModifiers::CONTROL);
+        keys(&mut editor, "jly");
+        assert_eq!(editor.registers.get('"').map(|r| r.kind), Some(RegisterKind::Blockwise));
+        keys(&mut editor, "jjp");
+        // The empty line is padded out to the column the block goes in.
+        assert_eq!(editor.text(), ["abc", "def", "xab", " de"]);
+        keys(&mut editor, "ggP");
+        assert_eq!(editor.text(), ["ababc", "dedef", "xab", " de"]);
+        // Short pieces are padded when text follows them, and missing lines added.
+        keys(&mut editor, "G$p");
+        assert_eq!(editor.text(), ["ababc", "dedef", "xab", " deab", "   de"]);
+    }
+
+    fn indent_of(text: &str) -> IndentStyle {
+        detect_indent(&text.lines().map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn detects_indentation() {
+        assert_eq!(indent_of("fn a() {\n\tx;\n\tif y {\n\t\tz;\n\t}\n}"), IndentStyle::Tabs);
+        assert_eq!(indent_of("a:\n  b:\n    c: 1\n  d: 2\n"), IndentStyle::Spaces(2));
+        assert_eq!(indent_of("fn a() {\n    if b {\n        c();\n        if d {\n            e();\n        }\n    }\n}"), IndentStyle::Spaces(4));
+        assert_eq!(indent_of("no\nindentation\n\nat all"), IndentStyle::Unknown);
+        assert_eq!(indent_of(""), IndentStyle::Unknown);
+    }
+
+    #[test]
+    fn detects_indentation_of_mixed_files() {
+        // Tabs need twice as many lines as spaces to win, and the other way round.
+        let tabs_and_spaces = |tabs: usize, spaces: usize| {
+            let lines = ["a", "\tb"].repeat(tabs).into_iter().chain(["a", "    b"].repeat(spaces));
+            detect_indent(&lines.map(str::to_string).collect::<Vec<_>>())
+        };
+        assert_eq!(tabs_and_spaces(4, 2), IndentStyle::Tabs);
+        assert_eq!(tabs_and_spaces(3, 2), IndentStyle::Unknown);
+        assert_eq!(tabs_and_spaces(2, 3), IndentStyle::Unknown);
+        assert_eq!(tabs_and_spaces(2, 4), IndentStyle::Spaces(4));
+    }
+
+    #[test]
+    fn comment_bodies_do_not_count_as_indentation() {
+        let text = "/**\n * Docs\n * more\n */\nfn a() {\n\tb();\n}";
+        assert_eq!(indent_of(text), IndentStyle::Tabs);
+        let text = "/*\n * one\n * two\n * three\n */\nfn a() {\n    b();\n}";
+        assert_eq!(indent_of(text), IndentStyle::Spaces(4));
+    }
+
+    #[test]
+    fn j_and_k_keep_the_column() {
+        let mut editor = editor_with(&["long line here", "ab", "another long one"]);
+        keys(&mut editor, "8l");
+        keys(&mut editor, "j");
+        assert_eq!(editor.cursor(), (1, 1));
+        keys(&mut editor, "j");
+        assert_eq!(editor.cursor(), (2, 8));
+        keys(&mut editor, "kk");
+        assert_eq!(editor.cursor(), (0, 8));
+        keys(&mut editor, "$j");
+        assert_eq!(editor.cursor(), (1, 1));
+        keys(&mut editor, "j");
+        assert_eq!(editor.cursor(), (2, 15));
+    }
+
+    #[test]
+    fn other_motions_and_edits_reset_the_column() {
+        let mut editor = editor_with(&["long line here", "abc", "another long one"]);
+        keys(&mut editor, "8ljhj");
+        assert_eq!(editor.cursor(), (2, 1));
+        let mut editor = editor_with(&["long line here", "abc", "another long one"]);
+        keys(&mut editor, "8ljxj");
+        assert_eq!(editor.cursor(), (2, 1));
+        // `$` wants the end of every line only until the next motion.
+        let mut editor = editor_with(&["long line here", "abc", "another long one"]);
+        keys(&mut editor, "$j0j");
+        assert_eq!(editor.cursor(), (2, 0));
+    }
+
+    #[test]
+    fn j_and_k_keep_the_display_column_over_wide_characters() {
+        let mut editor = editor_with(&["abcdef", "日本語", "abcdef"]);
+        keys(&mut editor, "3lj");
+        // Cell 3 is the second half of `本`, so the cursor goes onto it.
+        assert_eq!(editor.cursor(), (1, 1));
+        keys(&mut editor, "j");
+        assert_eq!(editor.cursor(), (2, 3));
+        keys(&mut editor, "gg0lljk");
+        assert_eq!(editor.cursor(), (0, 2));
+    }
+
+    #[test]
+    fn j_and_k_with_virtualedit() {
+        let mut editor = editor_with(&["long line here", "ab", "another long one"]);
+        editor.feed_command("set virtualedit=all");
+        keys(&mut editor, "8lj");
+        assert_eq!(editor.cursor(), (1, 8));
+        keys(&mut editor, "j");
+        assert_eq!(editor.cursor(), (2, 8));
+    }
+
+    /// The text and kind of register `name`.
+    fn register(editor: &Editor, name: char) -> Option<(Vec<String>, RegisterKind)> {
+        editor.registers.get(name).map(|register| (register.lines.clone(), register.kind))
+    }
+
+    #[test]
+    fn x_and_capital_x_with_counts() {
+        let charwise = |text: &str| Some((vec![text.to_string()], RegisterKind::Charwise));
+        let mut editor = editor_with(&["abcdefgh"]);
+        keys(&mut editor, "3l10x");
+        assert_eq!(editor.text(), ["abc"]);
+        assert_eq!(editor.cursor(), (0, 2));
+        assert_eq!(register(&editor, '"'), charwise("defgh"));
+        assert_eq!(register(&editor, '-'), charwise("defgh"));
+        assert_eq!(register(&editor, '1'), None);
+
+        let mut editor = editor_with(&["abcdefgh"]);
+        keys(&mut editor, "5l10X");
+        assert_eq!(editor.text(), ["fgh"]);
+        assert_eq!(editor.cursor(), (0, 0));
+        assert_eq!(register(&editor, '"'), charwise("abcde"));
+
+        let mut editor = editor_with(&["abcdefgh"]);
+        keys(&mut editor, "3l2x");
+        assert_eq!(editor.text(), ["abcfgh"]);
+        assert_eq!(editor.cursor(), (0, 3));
+        keys(&mut editor, "2X");
+        assert_eq!(editor.text(), ["afgh"]);
+        assert_eq!(register(&editor, '-'), charwise("bc"));
+
+        let mut editor = editor_with(&["", "next"]);
+        keys(&mut editor, "xX");
+        assert_eq!(editor.text(), ["", "next"]);
+        assert!(!editor.buffers[0].modified);
+        assert_eq!(register(&editor, '"'), None);
+    }
+
+    #[test]
+    fn scrolling_cuts_wide_characters_into_blanks() {
+        let cfg = WidthConfig::default();
+        let scrolled = |spans: &[&'static str], offset| {
+            let spans = spans.iter().map(|&text| Span::raw(text)).collect();
+            scroll_spans(spans, offset, &cfg).iter().map(|span| span.content.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(scrolled(&["ab", "日本"], 0), ["ab", "日本"]);
+        assert_eq!(scrolled(&["ab", "日本"], 2), ["日本"]);
+        assert_eq!(scrolled(&["ab", "日本"], 3), [" ", "本"]);
+        assert_eq!(scrolled(&["日本", "c"], 1), [" ", "本", "c"]);
+        assert_eq!(scrolled(&["日本", "c"], 4), ["c"]);
+        assert!(scrolled(&["日本", "c"], 5).is_empty());
+        let styled = scroll_spans(vec![Span::styled("日x", Style::default().fg(Color::Red))], 1, &cfg);
+        assert!(styled.iter().all(|span| span.style.fg == Some(Color::Red)));
+    }
+
+    #[test]
+    fn deleting_a_wide_character_at_the_right_edge() {
+        let mut editor = editor_with(&["abcdefghijklmnopqrstuvwxyz0123456789日本語", "short"]);
+        keys(&mut editor, "$");
+        assert_eq!(screen(&mut editor, 32, 4), [
+            " 1 nopqrstuvwxyz0123456789日本語",
+            " 2                              ",
+            "-- NORMAL -- [No Name]      1:39",
+            "                                ",
+        ]);
+        keys(&mut editor, "x");
+        assert_eq!(screen(&mut editor, 32, 4), [
+            " 1 nopqrstuvwxyz0123456789日本  ",
+            " 2                              ",
+            "-- NORMAL -- [No Name] [+]  1:38",
+            "                                ",
+        ]);
+    }
+
+    /// Types `search` after `/` or `?` and Enter.
+    fn search(editor: &mut Editor, search: &str) {
+        keys(editor, search);
+        editor.feed_key(KeyCode::Enter, KeyModifiers::NONE);
+    }
+
+    #[test]
+    fn operators_with_searches() {
+        let mut editor = editor_with(&["one two three two"]);
+        search(&mut editor, "d/two");
+        assert_eq!(editor.text(), ["two three two"]);
+        keys(&mut editor, "ldn");
+        assert_eq!(editor.text(), ["ttwo"]);
+        assert_eq!(register(&editor, '"'), Some((vec!["wo three ".to_string()], RegisterKind::Charwise)));
+
+        let mut editor = editor_with(&["one two three"]);
+        keys(&mut editor, "$");
+        search(&mut editor, "d?two");
+        assert_eq!(editor.text(), ["one e"]);
+        assert_eq!(editor.cursor(), (0, 4));
+    }
+
+    #[test]
+    fn operators_with_searches_across_lines() {
+        // Ending at the start of a line from the start of one takes whole lines.
+        let mut editor = editor_with(&["one two", "foo"]);
+        search(&mut editor, "d/foo");
+        assert_eq!(editor.text(), ["foo"]);
+        assert_eq!(register(&editor, '"'), Some((vec!["one two".to_string()], RegisterKind::Linewise)));
+        // From within a line, it stops at the end of the line before.
+        let mut editor = editor_with(&["one two", "foo"]);
+        keys(&mut editor, "w");
+        search(&mut editor, "d/foo");
+        assert_eq!(editor.text(), ["one ", "foo"]);
+        // A search that wraps around takes the text between the match and the cursor.
+        let mut editor = editor_with(&["foo bar", "baz"]);
+        keys(&mut editor, "jl");
+        search(&mut editor, "d/bar");
+        assert_eq!(editor.text(), ["foo az"]);
+        assert_eq!(editor.cursor(), (0, 4));
+    }
+
+    #[test]
+    fn operators_with_a_search_that_fails() {
+        let mut editor = editor_with(&["one two"]);
+        search(&mut editor, "d/nothing");
+        assert_eq!(editor.text(), ["one two"]);
+        assert_eq!(editor.command_message.to_string(), "Pattern not found: nothing");
+        assert!(!editor.buffers[0].modified);
+        keys(&mut editor, "x");
+        assert_eq!(editor.text(), ["ne two"]);
+    }
+
+    #[test]
+    fn substitute_with_confirmation() {
+        let mut editor = editor_with(&["a a a", "a"]);
+        editor.feed_command("%s/a/b/gc");
+        assert_eq!(editor.prompt.as_ref().map(|p| p.message.as_str()), Some("replace with b (y/n/a/q/l)?"));
+        keys(&mut editor, "yxn");
+        assert_eq!(editor.text(), ["b a a", "a"]);
+        assert_eq!(editor.cursor(), (0, 4));
+        keys(&mut editor, "a");
+        assert_eq!(editor.text(), ["b a b", "b"]);
+        assert_eq!(editor.command_message.to_string(), "3 substitutions on 2 lines");
+        assert!(editor.prompt.is_none());
+
+        // Esc stops like q, keeping what was already replaced.
+        let mut editor = editor_with(&["a a", "a"]);
+        editor.feed_command("%s/a/b/gc");
+        keys(&mut editor, "y");
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(editor.text(), ["b a", "a"]);
+        assert_eq!(editor.command_message.to_string(), "1 substitutions on 1 lines");
+        keys(&mut editor, "x");
+        assert_eq!(editor.text(), ["b ", "a"]);
+    }
+
+    #[test]
+    fn renaming_a_directory_keeps_it_and_its_subdirectories_open() {
+        let dir = temp_dir("rename-expanded");
+        std::fs::create_dir_all(dir.join("old/sub")).unwrap();
+        let mut editor = editor_with(&[]);
+        editor.expanded_dirs.extend([dir.join("old"), dir.join("old/sub"), dir.join("older")]);
+        editor.rename_path(&dir.join("old"), "new");
+        assert!(dir.join("new/sub").is_dir());
+        let mut expanded: Vec<_> = editor.expanded_dirs.iter().filter_map(|d| d.strip_prefix(&dir).ok()).collect();
+        expanded.sort();
+        assert_eq!(expanded, [Path::new("new"), Path::new("new/sub"), Path::new("older")]);
+    }
+
+    #[test]
+    fn file_operations_fail_with_their_errors() {
+        let dir = temp_dir("file-errors");
+        let file = dir.join("file.txt");
+        std::fs::write(&file, "text").unwrap();
+        let mut editor = editor_with(&["text"]);
+
+        assert!(matches!(editor.save_file(Some(dir.join("missing/file.txt"))), Err(EditorError::Save { .. })));
+        assert!(editor.buffers[0].filename.is_none());
+        assert!(matches!(editor.change_directory(&dir.join("missing")), Err(EditorError::ChangeDir { .. })));
+        assert!(matches!(editor.change_directory(&file), Err(EditorError::NotADirectory(_))));
+
+        editor.open_file_in_new_buffer(Some(file.clone())).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(editor.reload_buffer(false), Err(EditorError::Load { .. })));
+        assert_eq!(editor.text(), ["text"]);
+
+        std::fs::write(&file, [0xff, 0xfe, b'\n']).unwrap();
+        assert!(matches!(editor.open_file_in_new_buffer(Some(file)), Err(EditorError::Encoding { .. })));
+        assert_eq!(editor.buffers.len(), 3);
+
+        let config = dir.join("config");
+        std::fs::write(&config, "set scrolloff=2\nnosuchcommand\n").unwrap();
+        match editor.source_config(&config) {
+            Err(EditorError::Config { line: 2, source, .. }) => assert!(matches!(*source, EditorError::UnknownCommand(_))),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_are_drawn_until_another_message_replaces_them() {
+        let mut editor = editor_with(&["text"]);
+        editor.feed_command("nosuchcommand");
+        assert_eq!(editor.command_message.to_string(), "Unknown command: nosuchcommand");
+        let error_style = editor.ui_style.error_message_style;
+        assert_eq!(editor.render_to(Rect::new(0, 0, 30, 4)).get(0, 3).style().fg, error_style.fg);
+        editor.feed_command("s/text/word/");
+        assert!(matches!(editor.command_message, Message::Info(_)));
+        assert_ne!(editor.render_to(Rect::new(0, 0, 30, 4)).get(0, 3).style().fg, error_style.fg);
+    }
+
+    #[test]
+    fn numbered_registers_rotate_on_deletes() {
+        let lines: Vec<String> = (1..=11).map(|i| format!("line {}", i)).collect();
+        let mut editor = editor_with(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        keys(&mut editor, "Vy");
+        for _ in 0..10 {
+            keys(&mut editor, "dd");
+        }
+        // The last delete is in "1 and the tenth from last fell off the end.
+        for (name, line) in ('1'..='9').zip((2..=10).rev()) {
+            assert_eq!(register(&editor, name), Some((vec![format!("line {}", line)], RegisterKind::Linewise)), "\"{}", name);
+        }
+        assert_eq!(register(&editor, '0'), Some((vec!["line 1".to_string()], RegisterKind::Linewise)));
+        assert_eq!(register(&editor, '"'), register(&editor, '1'));
+
+        // Deletes within a line go to "- and leave the numbered registers alone.
+        let mut editor = editor_with(&["one two", "three"]);
+        keys(&mut editor, "dw");
+        assert_eq!(register(&editor, '-'), Some((vec!["one ".to_string()], RegisterKind::Charwise)));
+        assert_eq!(register(&editor, '1'), None);
+        assert_eq!(register(&editor, '"'), register(&editor, '-'));
+        // A charwise delete across lines counts as a big one.
+        search(&mut editor, "d/ree");
+        assert_eq!(editor.text(), ["ree"]);
+        assert_eq!(register(&editor, '1'), Some((vec!["two".to_string(), "th".to_string()], RegisterKind::Charwise)));
+        assert_eq!(register(&editor, '-'), Some((vec!["one ".to_string()], RegisterKind::Charwise)));
+    }
+
+    #[test]
+    fn rgb_colors_map_to_the_nearest_of_256() {
+        // Corners of the 6x6x6 cube.
+        assert_eq!(rgb_to_ansi256(0, 0, 0), 16);
+        assert_eq!(rgb_to_ansi256(255, 255, 255), 231);
+        assert_eq!(rgb_to_ansi256(255, 0, 0), 196);
+        assert_eq!(rgb_to_ansi256(0, 255, 0), 46);
+        assert_eq!(rgb_to_ansi256(0, 0, 255), 21);
+        assert_eq!(rgb_to_ansi256(255, 255, 0), 226);
+        // The grey ramp runs from 8 to 238 in steps of 10.
+        assert_eq!(rgb_to_ansi256(8, 8, 8), 232);
+        assert_eq!(rgb_to_ansi256(128, 128, 128), 244);
+        assert_eq!(rgb_to_ansi256(238, 238, 238), 255);
+        assert_eq!(rgb_to_ansi256(130, 128, 126), 244);
+        // Each channel rounds to the nearest cube level: 0, 95, 135, 175, 215, 255.
+        assert_eq!(rgb_to_ansi256(114, 0, 0), 52);
+        assert_eq!(rgb_to_ansi256(115, 0, 0), 88);
+        assert_eq!(rgb_to_ansi256(234, 0, 0), 160);
+        assert_eq!(rgb_to_ansi256(235, 0, 0), 196);
+        // A dark red is nearer a grey than black or the darkest red in the cube.
+        assert_eq!(rgb_to_ansi256(47, 0, 0), 233);
+    }
+
+    #[test]
+    fn dd_with_counts() {
+        let lines = |range: std::ops::RangeInclusive<usize>| range.map(|i| format!("line {}", i)).collect::<Vec<_>>();
+        let mut editor = editor_with(&["line 1", "line 2", "line 3", "line 4", "  line 5", "line 6"]);
+        keys(&mut editor, "j3dd");
+        assert_eq!(editor.text(), ["line 1", "  line 5", "line 6"]);
+        assert_eq!(editor.cursor(), (1, 2));
+        assert_eq!(register(&editor, '1'), Some((lines(2..=4), RegisterKind::Linewise)));
+
+        // A count past the end deletes what is there and lands on the line above.
+        let mut editor = editor_with(&["line 1", "line 2", "line 3"]);
+        keys(&mut editor, "j5dd");
+        assert_eq!(editor.text(), ["line 1"]);
+        assert_eq!(editor.cursor(), (0, 0));
+        assert_eq!(register(&editor, '1'), Some((lines(2..=3), RegisterKind::Linewise)));
+
+        let mut editor = editor_with(&["line 1", "line 2", "line 3"]);
+        keys(&mut editor, "Gdd");
+        assert_eq!(editor.text(), ["line 1", "line 2"]);
+        assert_eq!(editor.cursor(), (1, 0));
+        assert_eq!(register(&editor, '"'), Some((lines(3..=3), RegisterKind::Linewise)));
+
+        // Deleting every line leaves one empty line.
+        keys(&mut editor, "gg9dd");
+        assert_eq!(editor.text(), [""]);
+        assert_eq!(editor.cursor(), (0, 0));
+        assert_eq!(register(&editor, '1'), Some((lines(1..=2), RegisterKind::Linewise)));
+        assert_eq!(register(&editor, '2'), Some((lines(3..=3), RegisterKind::Linewise)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_failed_save_keeps_the_file_and_the_changes() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("failed-save");
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut editor = editor_with(&[]);
+        editor.open_file_in_new_buffer(Some(path.clone())).unwrap();
+        keys(&mut editor, "ccnew");
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Root writes anyway, so there is nothing to check.
+        if std::fs::File::create(dir.join("probe")).is_err() {
+            assert!(matches!(editor.save_file(None), Err(EditorError::Save { .. })));
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "old\n");
+            assert!(editor.buffers[1].modified);
+        }
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Something in the way of the temporary file stops the save whoever runs it.
+        let temp = dir.join(format!(".file.txt.{}.tmp", std::process::id()));
+        std::fs::create_dir(&temp).unwrap();
+        editor.feed_command("w");
+        assert!(matches!(editor.command_message, Message::Error(EditorError::Save { .. })));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old\n");
+        assert!(editor.buffers[1].modified);
+        std::fs::remove_dir(&temp).unwrap();
+        editor.feed_command("w");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!editor.buffers[1].modified);
+    }
+
+    #[test]
+    fn moving_lines() {
+        let mut editor = editor_with(&["1", "2", "3", "4", "5"]);
+        editor.feed_command("2,4m3");
+        assert!(matches!(editor.command_message, Message::Error(EditorError::MoveIntoItself)));
+        assert_eq!(editor.text(), ["1", "2", "3", "4", "5"]);
+        // Moving below its own last line is allowed and changes nothing.
+        editor.feed_command("2,4m4");
+        assert_eq!(editor.text(), ["1", "2", "3", "4", "5"]);
+        editor.feed_command("2,4m0");
+        assert_eq!(editor.text(), ["2", "3", "4", "1", "5"]);
+        assert_eq!(editor.cursor(), (2, 0));
+        keys(&mut editor, "gg");
+        editor.feed_command("m$");
+        assert_eq!(editor.text(), ["3", "4", "1", "5", "2"]);
+        assert_eq!(editor.cursor(), (4, 0));
+        editor.feed_command("1,2m$");
+        assert_eq!(editor.text(), ["1", "5", "2", "3", "4"]);
+        assert!(editor.buffers[0].modified);
+    }
+
+    /// The text and style of each span `highlight_spans` cuts `line` into.
+    fn spans<'a>(line: &'a str, highlights: &[(Range<usize>, Style)]) -> Vec<(&'a str, Style)> {
+        let mut start = 0;
+        let spans = highlight_spans(line, highlights).into_iter().map(|span| {
+            start += span.content.len();
+            (&line[start - span.content.len()..start], span.style)
+        });
+        spans.collect()
+    }
+
+    #[test]
+    fn nested_and_overlapping_highlights() {
+        let plain = Style::default();
+        let bg = Style::default().bg(Color::Blue);
+        let red = Style::default().fg(Color::Red);
+        let green = Style::default().fg(Color::Green);
+        assert_eq!(spans("abc", &[]), [("abc", plain)]);
+        assert_eq!(spans("", &[(0..0, red)]), [("", plain)]);
+
+        // A range inside another takes both styles.
+        assert_eq!(spans("abcdef", &[(1..5, bg), (2..4, red)]), [
+            ("a", plain), ("b", bg), ("cd", bg.fg(Color::Red)), ("e", bg), ("f", plain),
+        ]);
+        // Where two set the same attribute, the later one wins.
+        assert_eq!(spans("abcdef", &[(0..4, red), (2..6, green)]), [("ab", red), ("cdef", green)]);
+        assert_eq!(spans("abcdef", &[(2..6, green), (0..4, red)]), [("abcd", red), ("ef", green)]);
+        assert_eq!(spans("abcdef", &[(0..4, red), (2..6, bg)]), [
+            ("ab", red), ("cd", red.bg(Color::Blue)), ("ef", bg),
+        ]);
+        // Neighbours styled alike merge, and ranges past the end are cut off.
+        assert_eq!(spans("abcdef", &[(0..2, red), (2..4, red), (5..10, green)]), [
+            ("abcd", red), ("e", plain), ("f", green),
+        ]);
+    }
+
+    /// The matched text of every match of `search` in `line` under `settings`.
+    fn search_in<'a>(settings: &Settings, pattern: &str, whole_word: bool, line: &'a str) -> Vec<&'a str> {
+        let search = Search { pattern: pattern.to_string(), whole_word, forward: true };
+        let regex = settings.search_regex(&search).expect("valid pattern");
+        search_matches(line, &search, &regex).into_iter().map(|range| &line[range]).collect()
+    }
+
+    #[test]
+    fn ignorecase_and_smartcase() {
+        let line = "Foo foo FOO fOo";
+        let mut settings = Settings::default();
+        assert_eq!(search_in(&settings, "foo", false, line), ["foo"]);
+        assert_eq!(search_in(&settings, "Foo", false, line), ["Foo"]);
+
+        settings.apply("ignorecase").unwrap();
+        assert_eq!(search_in(&settings, "foo", false, line), ["Foo", "foo", "FOO", "fOo"]);
+        assert_eq!(search_in(&settings, "fOO", false, line), ["Foo", "foo", "FOO", "fOo"]);
+
+        // With smartcase an uppercase letter anywhere makes the pattern exact.
+        settings.apply("smartcase").unwrap();
+        assert_eq!(search_in(&settings, "foo", false, line), ["Foo", "foo", "FOO", "fOo"]);
+        assert_eq!(search_in(&settings, "fOo", false, line), ["fOo"]);
+        assert_eq!(search_in(&settings, "FOO", false, line), ["FOO"]);
+        // ... but not in an escape, which names a class rather than a letter.
+        assert_eq!(search_in(&settings, r"\Wfoo", false, line), [" foo", " FOO", " fOo"]);
+        assert_eq!(search_in(&settings, r"f\S\S", false, line), ["Foo", "foo", "FOO", "fOo"]);
+        // `*` and `#` search for the word under the cursor, which was not typed.
+        assert_eq!(search_in(&settings, "Foo", true, line), ["Foo", "foo", "FOO", "fOo"]);
+
+        // smartcase does nothing without ignorecase.
+        settings.apply("noignorecase").unwrap();
+        assert_eq!(search_in(&settings, "foo", false, line), ["foo"]);
+        assert_eq!(search_in(&settings, "Foo", true, line), ["Foo"]);
+    }
+
+    #[test]
+    fn case_operators() {
+        let mut editor = editor_with(&["abcd Efg"]);
+        keys(&mut editor, "3~");
+        assert_eq!(editor.text(), ["ABCd Efg"]);
+        assert_eq!(editor.cursor(), (0, 3));
+        keys(&mut editor, "wg~w");
+        assert_eq!(editor.text(), ["ABCd eFG"]);
+        keys(&mut editor, "0gUw");
+        assert_eq!(editor.text(), ["ABCD eFG"]);
+        keys(&mut editor, "wguw");
+        assert_eq!(editor.text(), ["ABCD efg"]);
+    }
+
+    #[test]
+    fn case_mappings_that_change_length() {
+        // German sharp s uppercases to two letters; the cursor steps past both.
+        let mut editor = editor_with(&["straße"]);
+        keys(&mut editor, "gUw");
+        assert_eq!(editor.text(), ["STRASSE"]);
+        let mut editor = editor_with(&["ßx"]);
+        keys(&mut editor, "~");
+        assert_eq!(editor.text(), ["SSx"]);
+        assert_eq!(editor.cursor(), (0, 2));
+        // Lowercasing does not bring it back.
+        keys(&mut editor, "0guw");
+        assert_eq!(editor.text(), ["ssx"]);
+
+        // Mapping is not Turkish-aware: dotted capital I lowercases to i plus a
+        // combining dot, one grapheme, and dotless i uppercases to a plain I.
+        let mut editor = editor_with(&["İz ız"]);
+        keys(&mut editor, "guw");
+        assert_eq!(editor.text(), ["i\u{307}z ız"]);
+        assert_eq!(editor.buffers[0].lines[0].graphemes(true).count(), 5);
+        keys(&mut editor, "wgUw");
+        assert_eq!(editor.text(), ["i\u{307}z IZ"]);
+        keys(&mut editor, "0~");
+        assert_eq!(editor.text(), ["I\u{307}z IZ"]);
+        assert_eq!(editor.cursor(), (0, 1));
+    }
+
+    #[test]
+    fn byte_order_marks_round_trip() {
+        let dir = temp_dir("bom");
+        let path = dir.join("bom.txt");
+        let bytes = "\u{feff}héllo\nworld".as_bytes();
+        std::fs::write(&path, bytes).unwrap();
+        let mut editor = editor_with(&[]);
+        editor.open_file_in_new_buffer(Some(path.clone())).unwrap();
+        assert_eq!(editor.text(), ["héllo", "world"]);
+        assert!(editor.buffers[1].bom);
+        editor.feed_command("w");
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        editor.feed_command("set fixeol");
+        std::fs::write(&path, "\u{feff}a\nb\n").unwrap();
+        editor.feed_command("e!");
+        assert_eq!(editor.text(), ["a", "b"]);
+        editor.feed_command("w");
+        assert_eq!(std::fs::read(&path).unwrap(), "\u{feff}a\nb\n".as_bytes());
+
+        // The mark is only written while the buffer has it.
+        editor.feed_command("set nobomb");
+        editor.feed_command("w");
+        assert_eq!(std::fs::read(&path).unwrap(), b"a\nb\n");
+        editor.feed_command("e!");
+        assert!(!editor.buffers[1].bom);
+        editor.feed_command("set bomb");
+        editor.feed_command("w");
+        assert_eq!(std::fs::read(&path).unwrap(), "\u{feff}a\nb\n".as_bytes());
+    }
+
+    #[test]
+    fn an_editor_without_buffers_draws_and_takes_keys() {
+        let mut editor = editor_with(&["text"]);
+        editor.buffers.clear();
+        editor.active_buffer_index = 3;
+        assert_eq!(screen(&mut editor, 20, 4), [
+            "                    ",
+            "No buffer. Open a fi",
+            "-- NORMAL --        ",
+            "                    ",
+        ]);
+        for key in "jlwxddoabc".chars() {
+            editor.feed_key(KeyCode::Char(key), KeyModifiers::NONE);
+            screen(&mut editor, 20, 4);
+        }
+        for key in [KeyCode::Esc, KeyCode::Up, KeyCode::PageDown, KeyCode::Backspace, KeyCode::Enter] {
+            editor.feed_key(key, KeyModifiers::NONE);
+            screen(&mut editor, 20, 4);
+        }
+        keys(&mut editor, "Vjy");
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+        editor.feed_command("bd");
+        screen(&mut editor, 1, 1);
+        assert!(editor.buffers.len() <= 1);
+    }
+
+    #[test]
+    fn dropping_the_scratch_buffer_keeps_jumps_in_their_files() {
+        let dir = temp_dir("scratch-jumps");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        std::fs::write(&a, "a1\na2\na3\na4").unwrap();
+        std::fs::write(&b, "b1\nb2").unwrap();
+        let mut editor = editor_with(&[]);
+        editor.open_file_in_new_buffer(Some(a.clone())).unwrap();
+        keys(&mut editor, "3G");
+        editor.open_file_in_new_buffer(Some(b.clone())).unwrap();
+        editor.tag_stack.push((1, 1, 0));
+        editor.drop_scratch_buffer();
+        assert_eq!(editor.buffers.len(), 2);
+        assert_eq!(editor.buffers[editor.active_buffer_index].filename.as_deref(), Some(b.as_path()));
+        assert_eq!(editor.tag_stack, [(0, 1, 0)]);
+
+        editor.feed_key(KeyCode::Char('o'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffers[editor.active_buffer_index].filename.as_deref(), Some(a.as_path()));
+        assert_eq!(editor.cursor(), (0, 0));
+    }
+
+    /// Ten lines `l0`..`l9` with a closed fold over rows 5-7.
+    fn editor_with_fold() -> Editor {
+        let lines: Vec<String> = (0..10).map(|i| format!("l{}", i)).collect();
+        let mut editor = editor_with(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        editor.buffers[0].folds.push(Fold { start: 5, end: 7, open: false });
+        editor
+    }
+
+    fn folds(editor: &Editor) -> Vec<(usize, usize)> {
+        editor.buffers[0].folds.iter().map(|fold| (fold.start, fold.end)).collect()
+    }
+
+    #[test]
+    fn folds_follow_lines_added_and_removed_elsewhere() {
+        let mut editor = editor_with_fold();
+        editor.feed_command("g/l[19]/d");
+        assert_eq!(folds(&editor), [(4, 6)]);
+        assert_eq!(editor.text()[4], "l5");
+
+        let mut editor = editor_with_fold();
+        editor.feed_command("1,2m$");
+        assert_eq!(folds(&editor), [(3, 5)]);
+        assert_eq!(editor.text()[3], "l5");
+
+        let mut editor = editor_with_fold();
+        keys(&mut editor, "9G");
+        editor.feed_command("2,3d");
+        assert_eq!(folds(&editor), [(3, 5)]);
+
+        let mut editor = editor_with_fold();
+        editor.feed_command("$t0");
+        assert_eq!(folds(&editor), [(6, 8)]);
+        assert_eq!(editor.text()[6], "l5");
+
+        // Lines put or deleted after the fold leave it alone.
+        let mut editor = editor_with_fold();
+        keys(&mut editor, "gg");
+        editor.feed_command("9,10d");
+        keys(&mut editor, "ggVyGp");
+        assert_eq!(folds(&editor), [(5, 7)]);
+    }
+
+    #[test]
+    fn folds_grow_and_shrink_with_lines_inside_them() {
+        let mut editor = editor_with_fold();
+        keys(&mut editor, "6Gzo7Gli");
+        editor.feed_key(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(folds(&editor), [(5, 8)]);
+        editor.feed_key(KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(folds(&editor), [(5, 7)]);
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+        editor.feed_command("6,7d");
+        assert_eq!(folds(&editor), [(5, 5)]);
+        assert_eq!(editor.text()[5], "l7");
+    }
+
+    #[test]
+    fn replace_mode_past_the_end_of_the_line() {
+        let mut editor = editor_with(&["ab", "abcdefgh"]);
+        editor.feed_command("set virtualedit=all");
+        keys(&mut editor, "$lllRxy");
+        assert_eq!(editor.text(), ["ab  xy", "abcdefgh"]);
+        assert_eq!(editor.cursor(), (0, 6));
+        editor.feed_key(KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.text(), ["ab  x", "abcdefgh"]);
+        editor.feed_key(KeyCode::Esc, KeyModifiers::NONE);
+
+        // Within the line it replaces as usual.
+        keys(&mut editor, "j0Rzz");
+        assert_eq!(editor.text(), ["ab  x", "zzcdefgh"]);
+    }
+
+    #[test]
+    fn commenting_blank_lines_changes_nothing() {
+        let mut editor = editor_with(&["", "  ", "let x = 1;"]);
+        editor.buffers[0].set_filetype("rust");
+        keys(&mut editor, "gcc");
+        keys(&mut editor, "gcj");
+        assert_eq!(editor.text(), ["", "  ", "let x = 1;"]);
+        assert!(!editor.buffers[0].modified);
+        keys(&mut editor, "gcG");
+        assert_eq!(editor.text(), ["", "  ", "// let x = 1;"]);
+        assert!(editor.buffers[0].modified);
+    }
+}